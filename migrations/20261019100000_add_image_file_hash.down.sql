@@ -0,0 +1,3 @@
+DROP INDEX image_file_hash;
+ALTER TABLE image DROP COLUMN file_missing;
+ALTER TABLE image DROP COLUMN file_hash;
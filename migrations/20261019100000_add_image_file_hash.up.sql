@@ -0,0 +1,5 @@
+-- SHA-256 of the media file, NULL for images imported before hashing was introduced
+ALTER TABLE image ADD COLUMN file_hash TEXT NULL;
+-- Set when the media file is known to be missing or quarantined (see fsck)
+ALTER TABLE image ADD COLUMN file_missing BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE INDEX image_file_hash ON image(file_hash);
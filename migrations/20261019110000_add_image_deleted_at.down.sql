@@ -0,0 +1,2 @@
+DROP INDEX image_deleted_at;
+ALTER TABLE image DROP COLUMN deleted_at;
@@ -0,0 +1,3 @@
+-- Images in trash have deletion time set, they are purged after retention period
+ALTER TABLE image ADD COLUMN deleted_at INTEGER NULL;
+CREATE INDEX image_deleted_at ON image(deleted_at);
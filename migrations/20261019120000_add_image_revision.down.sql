@@ -0,0 +1,2 @@
+DROP TABLE image_revision;
+ALTER TABLE image DROP COLUMN extra_params;
@@ -0,0 +1,12 @@
+-- Parameters of infotext which have no own column, JSON object of strings
+ALTER TABLE image ADD COLUMN extra_params TEXT NOT NULL DEFAULT '{}';
+
+-- History of image metadata edits, values are JSON objects with changed fields only
+CREATE TABLE image_revision (
+    id          INTEGER PRIMARY KEY autoincrement,
+    image_id    INTEGER NOT NULL REFERENCES image(id) ON DELETE CASCADE,
+    old_values  TEXT    NOT NULL,
+    new_values  TEXT    NOT NULL,
+    created_at  INTEGER NOT NULL DEFAULT(unixepoch())
+);
+CREATE INDEX image_revision_image_id ON image_revision(image_id);
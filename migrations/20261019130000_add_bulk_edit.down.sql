@@ -0,0 +1,3 @@
+DROP INDEX image_revision_bulk_edit_id;
+ALTER TABLE image_revision DROP COLUMN bulk_edit_id;
+DROP TABLE bulk_edit;
@@ -0,0 +1,11 @@
+-- Bulk edits of images found by search, revisions of edited images reference them
+CREATE TABLE bulk_edit (
+    id          INTEGER PRIMARY KEY autoincrement,
+    search      TEXT    NOT NULL,
+    description TEXT    NOT NULL,
+    image_count INTEGER NOT NULL DEFAULT 0,
+    created_at  INTEGER NOT NULL DEFAULT(unixepoch()),
+    undone_at   INTEGER NULL
+);
+ALTER TABLE image_revision ADD COLUMN bulk_edit_id INTEGER NULL REFERENCES bulk_edit(id) ON DELETE SET NULL;
+CREATE INDEX image_revision_bulk_edit_id ON image_revision(bulk_edit_id);
@@ -0,0 +1,2 @@
+DROP TABLE image_tag;
+DROP TABLE tag;
@@ -0,0 +1,12 @@
+CREATE TABLE tag (
+    id          INTEGER PRIMARY KEY autoincrement,
+    name        TEXT    NOT NULL UNIQUE COLLATE NOCASE,
+    created_at  INTEGER NOT NULL DEFAULT(unixepoch())
+);
+
+CREATE TABLE image_tag (
+    image_id    INTEGER NOT NULL REFERENCES image(id) ON DELETE CASCADE,
+    tag_id      INTEGER NOT NULL REFERENCES tag(id) ON DELETE CASCADE,
+    PRIMARY KEY (image_id, tag_id)
+);
+CREATE INDEX image_tag_tag_id ON image_tag(tag_id);
@@ -0,0 +1,18 @@
+CREATE TABLE album (
+    id              INTEGER PRIMARY KEY autoincrement,
+    name            TEXT    NOT NULL,
+    description     TEXT    NOT NULL DEFAULT '',
+    -- First image of album is the cover if not set
+    cover_image_id  INTEGER REFERENCES image(id) ON DELETE SET NULL,
+    created_at      INTEGER NOT NULL DEFAULT(unixepoch())
+);
+
+CREATE TABLE album_image (
+    album_id    INTEGER NOT NULL REFERENCES album(id) ON DELETE CASCADE,
+    image_id    INTEGER NOT NULL REFERENCES image(id) ON DELETE CASCADE,
+    -- Order of images in album, starting from 1
+    position    INTEGER NOT NULL,
+    added_at    INTEGER NOT NULL DEFAULT(unixepoch()),
+    PRIMARY KEY (album_id, image_id)
+);
+CREATE INDEX album_image_image_id ON album_image(image_id);
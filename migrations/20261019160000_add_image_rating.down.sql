@@ -0,0 +1,2 @@
+ALTER TABLE image DROP COLUMN favorite;
+ALTER TABLE image DROP COLUMN rating;
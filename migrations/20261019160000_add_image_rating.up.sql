@@ -0,0 +1,2 @@
+ALTER TABLE image ADD COLUMN rating INTEGER NOT NULL DEFAULT 0 CHECK (rating BETWEEN 0 AND 5);
+ALTER TABLE image ADD COLUMN favorite BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+DROP TABLE comparison;
+ALTER TABLE image DROP COLUMN comparisons;
+ALTER TABLE image DROP COLUMN score;
@@ -0,0 +1,10 @@
+-- Elo score from pairwise comparisons
+ALTER TABLE image ADD COLUMN score REAL NOT NULL DEFAULT 1500;
+ALTER TABLE image ADD COLUMN comparisons INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE comparison (
+    id          INTEGER PRIMARY KEY autoincrement,
+    winner_id   INTEGER NOT NULL REFERENCES image(id) ON DELETE CASCADE,
+    loser_id    INTEGER NOT NULL REFERENCES image(id) ON DELETE CASCADE,
+    created_at  INTEGER NOT NULL DEFAULT(unixepoch())
+);
@@ -0,0 +1,2 @@
+-- Free-text markdown note
+ALTER TABLE image ADD COLUMN note TEXT NOT NULL DEFAULT '';
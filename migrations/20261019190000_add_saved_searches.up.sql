@@ -0,0 +1,8 @@
+CREATE TABLE saved_search (
+    id          INTEGER PRIMARY KEY autoincrement,
+    name        TEXT    NOT NULL UNIQUE,
+    -- Search box query and sort order of `/images`
+    query       TEXT    NOT NULL,
+    sort        TEXT    NOT NULL DEFAULT 'newest',
+    created_at  INTEGER NOT NULL DEFAULT(unixepoch())
+);
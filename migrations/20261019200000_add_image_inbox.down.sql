@@ -0,0 +1,2 @@
+DROP INDEX image_inbox;
+ALTER TABLE image DROP COLUMN inbox;
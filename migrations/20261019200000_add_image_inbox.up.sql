@@ -0,0 +1,3 @@
+-- Newly imported image waiting for triage
+ALTER TABLE image ADD COLUMN inbox BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE INDEX image_inbox ON image(inbox) WHERE inbox;
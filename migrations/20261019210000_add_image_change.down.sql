@@ -0,0 +1,16 @@
+DROP TABLE sync_skipped;
+DROP TABLE sync_peer;
+DROP TRIGGER image_change_album_rename;
+DROP TRIGGER image_change_album_delete;
+DROP TRIGGER image_change_album_insert;
+DROP TRIGGER image_change_tag_rename;
+DROP TRIGGER image_change_tag_delete;
+DROP TRIGGER image_change_tag_insert;
+DROP TRIGGER image_change_trash;
+DROP TRIGGER image_change_inbox;
+DROP TRIGGER image_change_score;
+DROP TRIGGER image_change_note;
+DROP TRIGGER image_change_favorite;
+DROP TRIGGER image_change_rating;
+DROP TRIGGER image_change_delete;
+DROP TABLE image_change;
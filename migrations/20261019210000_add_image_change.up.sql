@@ -0,0 +1,103 @@
+-- Latest change of every field of every image, the feed other instances sync from.
+-- Rows are replaced on change, so ids grow with changes and serve as cursor of the feed
+CREATE TABLE image_change (
+    id          INTEGER PRIMARY KEY autoincrement,
+    -- No foreign key, rows are removed by trigger when image is deleted
+    image_id    INTEGER NOT NULL,
+    -- image, rating, favorite, note, score, inbox, trash, tags or albums
+    field       TEXT    NOT NULL,
+    -- Milliseconds since epoch. Changes pulled from other instances keep their time
+    changed_at  INTEGER NOT NULL DEFAULT(CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)),
+    UNIQUE (image_id, field)
+);
+
+-- Inserted images are recorded by the application:
+-- sqlx can't check INSERT INTO image at compile time when a trigger writes on it
+CREATE TRIGGER image_change_delete AFTER DELETE ON image BEGIN
+    DELETE FROM image_change WHERE image_id = OLD.id;
+END;
+CREATE TRIGGER image_change_rating AFTER UPDATE OF rating ON image
+WHEN OLD.rating IS NOT NEW.rating BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (NEW.id, 'rating');
+END;
+CREATE TRIGGER image_change_favorite AFTER UPDATE OF favorite ON image
+WHEN OLD.favorite IS NOT NEW.favorite BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (NEW.id, 'favorite');
+END;
+CREATE TRIGGER image_change_note AFTER UPDATE OF note ON image
+WHEN OLD.note IS NOT NEW.note BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (NEW.id, 'note');
+END;
+CREATE TRIGGER image_change_score AFTER UPDATE OF score, comparisons ON image
+WHEN OLD.score IS NOT NEW.score OR OLD.comparisons IS NOT NEW.comparisons BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (NEW.id, 'score');
+END;
+CREATE TRIGGER image_change_inbox AFTER UPDATE OF inbox ON image
+WHEN OLD.inbox IS NOT NEW.inbox BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (NEW.id, 'inbox');
+END;
+CREATE TRIGGER image_change_trash AFTER UPDATE OF deleted_at ON image
+WHEN OLD.deleted_at IS NOT NEW.deleted_at BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (NEW.id, 'trash');
+END;
+
+CREATE TRIGGER image_change_tag_insert AFTER INSERT ON image_tag BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (NEW.image_id, 'tags');
+END;
+-- Tags of deleted images are deleted too, they need no changes
+CREATE TRIGGER image_change_tag_delete AFTER DELETE ON image_tag
+WHEN EXISTS (SELECT 1 FROM image WHERE id = OLD.image_id) BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (OLD.image_id, 'tags');
+END;
+CREATE TRIGGER image_change_tag_rename AFTER UPDATE OF name ON tag BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field)
+    SELECT image_id, 'tags' FROM image_tag WHERE tag_id = NEW.id;
+END;
+CREATE TRIGGER image_change_album_insert AFTER INSERT ON album_image BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (NEW.image_id, 'albums');
+END;
+CREATE TRIGGER image_change_album_delete AFTER DELETE ON album_image
+WHEN EXISTS (SELECT 1 FROM image WHERE id = OLD.image_id) BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field) VALUES (OLD.image_id, 'albums');
+END;
+CREATE TRIGGER image_change_album_rename AFTER UPDATE OF name ON album BEGIN
+    INSERT OR REPLACE INTO image_change (image_id, field)
+    SELECT image_id, 'albums' FROM album_image WHERE album_id = NEW.id;
+END;
+
+-- Existing images and their curation are changed when they were created
+INSERT INTO image_change (image_id, field, changed_at)
+SELECT id, 'image', created_at * 1000 FROM image ORDER BY id;
+INSERT INTO image_change (image_id, field, changed_at)
+SELECT id, field, created_at * 1000 FROM (
+    SELECT id, created_at, 'rating' AS field FROM image WHERE rating != 0
+    UNION ALL SELECT id, created_at, 'favorite' FROM image WHERE favorite
+    UNION ALL SELECT id, created_at, 'note' FROM image WHERE note != ''
+    UNION ALL SELECT id, created_at, 'score' FROM image WHERE comparisons > 0
+    UNION ALL SELECT id, created_at, 'inbox' FROM image WHERE NOT inbox
+    UNION ALL SELECT id, created_at, 'trash' FROM image WHERE deleted_at IS NOT NULL
+    UNION ALL SELECT id, created_at, 'tags' FROM image
+        WHERE id IN (SELECT image_id FROM image_tag)
+    UNION ALL SELECT id, created_at, 'albums' FROM image
+        WHERE id IN (SELECT image_id FROM album_image)
+) ORDER BY id;
+
+-- Peers this instance pulled changes from
+CREATE TABLE sync_peer (
+    url         TEXT    PRIMARY KEY,
+    -- Id of the last applied change in the feed of the peer
+    cursor      INTEGER NOT NULL DEFAULT 0,
+    synced_at   INTEGER NOT NULL DEFAULT(unixepoch())
+);
+
+-- Changes of images which were not copied from peers because their files couldn't be
+-- fetched. The cursor moves past them, so they are retried on every sync with the peer
+CREATE TABLE sync_skipped (
+    url         TEXT    NOT NULL,
+    file_hash   TEXT    NOT NULL,
+    -- JSON of the change as it's in the change feed of the peer
+    change      TEXT    NOT NULL,
+    reason      TEXT    NOT NULL,
+    skipped_at  INTEGER NOT NULL DEFAULT(unixepoch()),
+    PRIMARY KEY (url, file_hash)
+);
@@ -0,0 +1,401 @@
+use sqlx::{Executor, Sqlite, Transaction};
+
+use crate::models::{Image, Limits, IMAGE_COLUMNS};
+
+#[derive(Debug, PartialEq, serde::Serialize, sqlx::FromRow)]
+pub struct Album {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    pub cover_image_id: Option<i64>,
+    /// File of the cover image or of the first image if cover is not set
+    pub cover_file_path: Option<String>,
+    /// Number of images (not in trash) in the album
+    pub image_count: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum AlbumError {
+    #[error("Album name is empty")]
+    EmptyName,
+}
+
+fn normalize_name(name: &str) -> Result<&str, AlbumError> {
+    match name.trim() {
+        "" => Err(AlbumError::EmptyName),
+        name => Ok(name),
+    }
+}
+
+/// All albums, recently created first
+pub async fn fetch_albums(
+    executor: impl Executor<'_, Database = Sqlite>,
+) -> sqlx::Result<Vec<Album>> {
+    sqlx::query_as!(
+        Album,
+        r#"SELECT album.id as "id!", album.name, album.description, album.cover_image_id,
+        coalesce(
+            (SELECT file_path FROM image WHERE image.id = album.cover_image_id),
+            (SELECT image.file_path FROM album_image JOIN image ON image.id = album_image.image_id
+            WHERE album_image.album_id = album.id AND image.deleted_at IS NULL
+            ORDER BY album_image.position LIMIT 1)
+        ) as "cover_file_path?: String",
+        (SELECT count(*) FROM album_image JOIN image ON image.id = album_image.image_id
+        WHERE album_image.album_id = album.id AND image.deleted_at IS NULL) as "image_count!: i64",
+        album.created_at as "created_at: _"
+        FROM album ORDER BY album.created_at DESC, album.id DESC"#
+    )
+    .fetch_all(executor)
+    .await
+}
+
+pub async fn fetch_album(
+    executor: impl Executor<'_, Database = Sqlite>,
+    album_id: i64,
+) -> sqlx::Result<Option<Album>> {
+    sqlx::query_as!(
+        Album,
+        r#"SELECT album.id as "id!", album.name, album.description, album.cover_image_id,
+        coalesce(
+            (SELECT file_path FROM image WHERE image.id = album.cover_image_id),
+            (SELECT image.file_path FROM album_image JOIN image ON image.id = album_image.image_id
+            WHERE album_image.album_id = album.id AND image.deleted_at IS NULL
+            ORDER BY album_image.position LIMIT 1)
+        ) as "cover_file_path?: String",
+        (SELECT count(*) FROM album_image JOIN image ON image.id = album_image.image_id
+        WHERE album_image.album_id = album.id AND image.deleted_at IS NULL) as "image_count!: i64",
+        album.created_at as "created_at: _"
+        FROM album WHERE album.id = ?"#,
+        album_id
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Albums containing the image
+pub async fn fetch_image_album_ids(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+) -> sqlx::Result<Vec<i64>> {
+    sqlx::query_scalar!(
+        "SELECT album_id FROM album_image WHERE image_id = ?",
+        image_id
+    )
+    .fetch_all(executor)
+    .await
+}
+
+pub async fn create_album(
+    executor: impl Executor<'_, Database = Sqlite>,
+    name: &str,
+    description: &str,
+) -> anyhow::Result<i64> {
+    let name = normalize_name(name)?;
+    let description = description.trim();
+    let id = sqlx::query_scalar!(
+        "INSERT INTO album (name, description) VALUES (?, ?) RETURNING id",
+        name,
+        description
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(id)
+}
+
+pub async fn update_album(
+    executor: impl Executor<'_, Database = Sqlite>,
+    album_id: i64,
+    name: &str,
+    description: &str,
+) -> anyhow::Result<()> {
+    let name = normalize_name(name)?;
+    let description = description.trim();
+    sqlx::query!(
+        "UPDATE album SET name = ?, description = ? WHERE id = ?",
+        name,
+        description,
+        album_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Delete album, its images are kept
+pub async fn delete_album(
+    executor: impl Executor<'_, Database = Sqlite>,
+    album_id: i64,
+) -> sqlx::Result<()> {
+    sqlx::query!("DELETE FROM album WHERE id = ?", album_id)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Set cover image of album, `None` makes the first image the cover
+pub async fn set_album_cover(
+    executor: impl Executor<'_, Database = Sqlite>,
+    album_id: i64,
+    image_id: Option<i64>,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE album SET cover_image_id = ? WHERE id = ?",
+        image_id,
+        album_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Images of album (not in trash) in album order
+pub async fn fetch_album_images(
+    executor: impl Executor<'_, Database = Sqlite>,
+    album_id: i64,
+    limits: &Limits,
+) -> sqlx::Result<Vec<Image>> {
+    let images = sqlx::QueryBuilder::new(format!(
+        "SELECT {} FROM album_image JOIN image ON image.id = album_image.image_id
+        WHERE image.deleted_at IS NULL AND album_image.album_id = ",
+        IMAGE_COLUMNS
+    ))
+    .push_bind(album_id)
+    .push(" ORDER BY album_image.position LIMIT ")
+    .push_bind(limits.limit)
+    .push(" OFFSET ")
+    .push_bind(limits.offset)
+    .build()
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .map(|row| sqlx::FromRow::from_row(&row).unwrap())
+    .collect();
+
+    Ok(images)
+}
+
+/// Add images to the end of album keeping their order, images already in album are skipped.
+/// Returns number of added images
+pub async fn add_album_images(
+    transaction: &mut Transaction<'_, Sqlite>,
+    album_id: i64,
+    image_ids: &[i64],
+) -> sqlx::Result<u64> {
+    let mut added = 0;
+    for image_id in image_ids {
+        let result = sqlx::query!(
+            "INSERT INTO album_image (album_id, image_id, position)
+            SELECT ?, id, (SELECT coalesce(max(position), 0) + 1 FROM album_image WHERE album_id = ?)
+            FROM image WHERE id = ?
+            ON CONFLICT DO NOTHING",
+            album_id,
+            album_id,
+            image_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        added += result.rows_affected();
+    }
+    Ok(added)
+}
+
+pub async fn remove_album_images(
+    transaction: &mut Transaction<'_, Sqlite>,
+    album_id: i64,
+    image_ids: &[i64],
+) -> sqlx::Result<u64> {
+    let mut removed = 0;
+    for image_id in image_ids {
+        let result = sqlx::query!(
+            "DELETE FROM album_image WHERE album_id = ? AND image_id = ?",
+            album_id,
+            image_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        removed += result.rows_affected();
+    }
+    Ok(removed)
+}
+
+/// Ids of all images of album in order, including images in trash
+async fn fetch_album_order(
+    transaction: &mut Transaction<'_, Sqlite>,
+    album_id: i64,
+) -> sqlx::Result<Vec<i64>> {
+    sqlx::query_scalar!(
+        "SELECT image_id FROM album_image WHERE album_id = ? ORDER BY position",
+        album_id
+    )
+    .fetch_all(&mut *transaction)
+    .await
+}
+
+/// Number images of album from 1 in the given order
+async fn save_album_order(
+    transaction: &mut Transaction<'_, Sqlite>,
+    album_id: i64,
+    image_ids: &[i64],
+) -> sqlx::Result<()> {
+    for (position, image_id) in (1..).zip(image_ids) {
+        sqlx::query!(
+            "UPDATE album_image SET position = ? WHERE album_id = ? AND image_id = ?",
+            position,
+            album_id,
+            image_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Move image to `position` (from 1) in album, out of range positions move it to the start or
+/// the end. Returns the position the image took, `None` if it's not in the album
+pub async fn move_album_image(
+    transaction: &mut Transaction<'_, Sqlite>,
+    album_id: i64,
+    image_id: i64,
+    position: i64,
+) -> sqlx::Result<Option<usize>> {
+    let mut order = fetch_album_order(transaction, album_id).await?;
+    let Some(index) = order.iter().position(|id| *id == image_id) else {
+        return Ok(None);
+    };
+    order.remove(index);
+    let index = (position.saturating_sub(1)).clamp(0, order.len() as i64) as usize;
+    order.insert(index, image_id);
+    save_album_order(transaction, album_id, &order).await?;
+    Ok(Some(index + 1))
+}
+
+/// Rearrange some images of album in the given order, e.g. after dragging images of one page.
+/// The images take the same places they occupied together before, other images don't move
+pub async fn reorder_album_images(
+    transaction: &mut Transaction<'_, Sqlite>,
+    album_id: i64,
+    image_ids: &[i64],
+) -> sqlx::Result<()> {
+    let mut order = fetch_album_order(transaction, album_id).await?;
+    let reordered: Vec<i64> = image_ids
+        .iter()
+        .copied()
+        .filter(|id| order.contains(id))
+        .collect();
+    let mut reordered = reordered.into_iter();
+    for id in order.iter_mut() {
+        if image_ids.contains(id) {
+            match reordered.next() {
+                Some(new_id) => *id = new_id,
+                None => break,
+            }
+        }
+    }
+    save_album_order(transaction, album_id, &order).await
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use super::{
+        add_album_images, create_album, delete_album, fetch_album, fetch_album_images,
+        fetch_albums, move_album_image, remove_album_images, reorder_album_images, set_album_cover,
+    };
+    use crate::models::{
+        fetch_image_by_id, insert_test_image, new_test_image, new_test_pool, trash_image, Image,
+        Limits,
+    };
+
+    async fn album_ids(pool: &Pool<Sqlite>, album_id: i64) -> Vec<i64> {
+        fetch_album_images(pool, album_id, &Limits::all())
+            .await
+            .unwrap()
+            .iter()
+            .map(|image| image.id)
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_album_order() {
+        let pool = new_test_pool().await;
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            ids.push(
+                insert_test_image(
+                    &pool,
+                    Image {
+                        file_path: Some(format!("images/{}.png", i)),
+                        ..new_test_image()
+                    },
+                )
+                .await,
+            );
+        }
+        let [a, b, c, d, e] = ids[..] else {
+            unreachable!()
+        };
+        let album_id = create_album(&pool, " Best ", "").await.unwrap();
+        assert!(create_album(&pool, " ", "").await.is_err());
+
+        let mut transaction = pool.begin().await.unwrap();
+        assert_eq!(
+            add_album_images(&mut transaction, album_id, &[c, a, b])
+                .await
+                .unwrap(),
+            3
+        );
+        // Images already in album are skipped
+        assert_eq!(
+            add_album_images(&mut transaction, album_id, &[a, d, e])
+                .await
+                .unwrap(),
+            2
+        );
+        transaction.commit().await.unwrap();
+        assert_eq!(album_ids(&pool, album_id).await, vec![c, a, b, d, e]);
+
+        let mut transaction = pool.begin().await.unwrap();
+        assert_eq!(
+            move_album_image(&mut transaction, album_id, c, 3)
+                .await
+                .unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            move_album_image(&mut transaction, album_id, e, -1)
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        transaction.commit().await.unwrap();
+        assert_eq!(album_ids(&pool, album_id).await, vec![e, a, b, c, d]);
+
+        // Dragged images of a page take their places, others stay
+        let mut transaction = pool.begin().await.unwrap();
+        reorder_album_images(&mut transaction, album_id, &[c, b, a])
+            .await
+            .unwrap();
+        remove_album_images(&mut transaction, album_id, &[d])
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+        assert_eq!(album_ids(&pool, album_id).await, vec![e, c, b, a]);
+
+        let album = fetch_album(&pool, album_id).await.unwrap().unwrap();
+        assert_eq!(album.name, "Best");
+        assert_eq!(album.image_count, 4);
+        assert_eq!(album.cover_file_path.as_deref(), Some("images/4.png"));
+        set_album_cover(&pool, album_id, Some(b)).await.unwrap();
+        trash_image(&pool, e).await.unwrap();
+        let albums = fetch_albums(&pool).await.unwrap();
+        assert_eq!(albums[0].image_count, 3);
+        assert_eq!(albums[0].cover_file_path.as_deref(), Some("images/1.png"));
+
+        // Images are kept when album is deleted
+        delete_album(&pool, album_id).await.unwrap();
+        assert!(fetch_album(&pool, album_id).await.unwrap().is_none());
+        assert!(fetch_image_by_id(&pool, a).await.unwrap().is_some());
+    }
+}
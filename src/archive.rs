@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+use anyhow::bail;
+use flate2::read::MultiGzDecoder;
+use tempfile::NamedTempFile;
+use tokio::sync::mpsc::Sender;
+use zip::ZipArchive;
+
+/// Archives with more entries are rejected
+const MAX_ENTRIES: usize = 10_000;
+/// Larger entries are rejected. The limit is checked against bytes actually read,
+/// sizes in archive headers may lie
+const MAX_ENTRY_SIZE: u64 = 256 * 1024 * 1024;
+/// Reading stops when all entries of an archive take more space unpacked
+const MAX_TOTAL_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+/// ZIP entries which unpack to this many times their compressed size are rejected unread,
+/// images hardly compress at all
+const MAX_COMPRESSION_RATIO: u64 = 100;
+/// Smaller entries are not checked for compression ratio, e.g. text sidecars
+const MIN_RATIO_CHECKED_SIZE: u64 = 1024 * 1024;
+/// Larger `.txt` files are not sidecars
+const MAX_SIDECAR_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    /// Gzipped tar
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// Format of archive at `path` by its contents, `None` if it's not an archive
+    pub fn detect(path: &Path) -> io::Result<Option<ArchiveFormat>> {
+        let mut header = Vec::with_capacity(512);
+        File::open(path)?.take(512).read_to_end(&mut header)?;
+        Ok(Self::from_header(&header))
+    }
+
+    fn from_header(header: &[u8]) -> Option<ArchiveFormat> {
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveFormat::TarGz)
+        } else if header.get(257..262) == Some(b"ustar") {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// File read from an archive into a temporary file
+pub struct ArchiveEntry {
+    /// Path inside the archive
+    pub path: String,
+    pub file: NamedTempFile,
+    /// Contents of the `.txt` file with the same path except extension, A1111 saves
+    /// generation parameters there when they are not embedded into images
+    pub sidecar: Option<String>,
+}
+
+/// Entry which is not read, e.g. with unsafe path or too large
+pub struct RejectedEntry {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Sizes of unpacked entries, see [`MAX_ENTRY_SIZE`] and [`MAX_TOTAL_SIZE`]
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    entry_size: u64,
+    total_size: u64,
+}
+
+const LIMITS: Limits = Limits {
+    entry_size: MAX_ENTRY_SIZE,
+    total_size: MAX_TOTAL_SIZE,
+};
+
+/// Read files of archive one by one and send them to `sender`, so only a few entries are
+/// unpacked at any time. `.txt` files are attached to entries as sidecars instead of being
+/// sent. Blocking, stops early if the receiver is dropped
+pub fn read_archive(
+    path: &Path,
+    format: ArchiveFormat,
+    sender: Sender<Result<ArchiveEntry, RejectedEntry>>,
+) -> anyhow::Result<()> {
+    read_archive_with_limits(path, format, LIMITS, sender)
+}
+
+fn read_archive_with_limits(
+    path: &Path,
+    format: ArchiveFormat,
+    limits: Limits,
+    sender: Sender<Result<ArchiveEntry, RejectedEntry>>,
+) -> anyhow::Result<()> {
+    let mut sidecars = HashMap::new();
+    visit_files(path, format, limits, |name, reader| {
+        if let (true, Ok(reader)) = (is_sidecar(name), reader) {
+            let mut contents = Vec::new();
+            reader
+                .take(MAX_SIDECAR_SIZE + 1)
+                .read_to_end(&mut contents)?;
+            if contents.len() as u64 <= MAX_SIDECAR_SIZE {
+                if let Ok(contents) = String::from_utf8(contents) {
+                    sidecars.insert(without_extension(name).to_owned(), contents);
+                }
+            }
+        }
+        Ok(true)
+    })?;
+
+    let mut total_size = 0;
+    visit_files(path, format, limits, |name, reader| {
+        if is_sidecar(name) || is_junk(name) {
+            return Ok(true);
+        }
+        let item = match (is_safe_path(name), reader) {
+            (false, _) => Err(RejectedEntry {
+                path: name.to_owned(),
+                reason: "Unsafe path outside of archive".to_owned(),
+            }),
+            (true, Err(reason)) => Err(RejectedEntry {
+                path: name.to_owned(),
+                reason,
+            }),
+            (true, Ok(reader)) => {
+                let mut file = NamedTempFile::new()?;
+                let size = io::copy(&mut reader.take(limits.entry_size + 1), &mut file)?;
+                total_size += size;
+                if total_size > limits.total_size {
+                    bail!(
+                        "Unpacked archive is larger than {}",
+                        format_size(limits.total_size)
+                    );
+                }
+                match size > limits.entry_size {
+                    true => Err(RejectedEntry {
+                        path: name.to_owned(),
+                        reason: format!("File is larger than {}", format_size(limits.entry_size)),
+                    }),
+                    false => Ok(ArchiveEntry {
+                        path: name.to_owned(),
+                        file,
+                        sidecar: sidecars.get(without_extension(name)).cloned(),
+                    }),
+                }
+            }
+        };
+        Ok(sender.blocking_send(item).is_ok())
+    })
+}
+
+/// Call `visit` with name and contents of every regular file of archive
+/// until it returns `false`. Symlinks and other special entries are skipped.
+/// Entries which must not be read get the reason instead of contents
+fn visit_files(
+    path: &Path,
+    format: ArchiveFormat,
+    limits: Limits,
+    mut visit: impl FnMut(&str, Result<&mut dyn Read, String>) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    let file = File::open(path)?;
+    match format {
+        ArchiveFormat::Zip => {
+            let mut archive = ZipArchive::new(file)?;
+            if archive.len() > MAX_ENTRIES {
+                bail!("Archive has more than {} entries", MAX_ENTRIES);
+            }
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                if !entry.is_file() {
+                    continue;
+                }
+                let name = entry.name().to_owned();
+                let reader: Result<&mut dyn Read, _> = match entry.size() {
+                    size if size >= MIN_RATIO_CHECKED_SIZE
+                        && size / entry.compressed_size().max(1) > MAX_COMPRESSION_RATIO =>
+                    {
+                        Err(format!(
+                            "File is compressed more than {} times",
+                            MAX_COMPRESSION_RATIO
+                        ))
+                    }
+                    _ => Ok(&mut entry),
+                };
+                if !visit(&name, reader)? {
+                    break;
+                }
+            }
+        }
+        ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+            let reader: Box<dyn Read> = match format {
+                ArchiveFormat::TarGz => Box::new(MultiGzDecoder::new(BufReader::new(file))),
+                _ => Box::new(file),
+            };
+            let mut archive = tar::Archive::new(reader);
+            // Skipping entries unpacks them too, so limits are checked before reading
+            let mut total_size = 0;
+            for (index, entry) in archive.entries()?.enumerate() {
+                if index >= MAX_ENTRIES {
+                    bail!("Archive has more than {} entries", MAX_ENTRIES);
+                }
+                let mut entry = entry?;
+                total_size += entry.size();
+                if total_size > limits.total_size {
+                    bail!(
+                        "Unpacked archive is larger than {}",
+                        format_size(limits.total_size)
+                    );
+                }
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+                if !visit(&name, Ok(&mut entry))? {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn format_size(size: u64) -> String {
+    match size {
+        size if size >= 1 << 30 => format!("{} GiB", size >> 30),
+        size if size >= 1 << 20 => format!("{} MiB", size >> 20),
+        size => format!("{} bytes", size),
+    }
+}
+
+/// Whether archive path stays inside the directory the archive would be unpacked to
+pub fn is_safe_path(name: &str) -> bool {
+    let mut parts = name.split(['/', '\\']);
+    let first = parts.next().unwrap_or_default();
+    !first.is_empty() && !first.ends_with(':') && first != ".." && parts.all(|part| part != "..")
+}
+
+fn is_sidecar(name: &str) -> bool {
+    name.to_lowercase().ends_with(".txt")
+}
+
+/// Metadata added by macOS archivers
+fn is_junk(name: &str) -> bool {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    name.starts_with("__MACOSX/") || file_name.starts_with("._") || file_name == ".DS_Store"
+}
+
+fn without_extension(name: &str) -> &str {
+    let file_name_start = name.rfind('/').map_or(0, |slash| slash + 1);
+    match name[file_name_start..].rfind('.') {
+        Some(dot) if dot > 0 => &name[..file_name_start + dot],
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use tempfile::NamedTempFile;
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::{
+        is_safe_path, read_archive_with_limits, without_extension, ArchiveEntry, ArchiveFormat,
+        Limits, RejectedEntry, LIMITS,
+    };
+
+    type Items = Vec<Result<ArchiveEntry, RejectedEntry>>;
+
+    /// Entries read from `archive` and the result of reading
+    async fn read(archive: &NamedTempFile, limits: Limits) -> (Items, anyhow::Result<()>) {
+        let format = ArchiveFormat::detect(archive.path()).unwrap().unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let path = archive.path().to_owned();
+        let reader = tokio::task::spawn_blocking(move || {
+            read_archive_with_limits(&path, format, limits, sender)
+        });
+        let mut items = Vec::new();
+        while let Some(item) = receiver.recv().await {
+            items.push(item);
+        }
+        (items, reader.await.unwrap())
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> NamedTempFile {
+        let mut archive = NamedTempFile::new().unwrap();
+        let mut writer = ZipWriter::new(archive.as_file_mut());
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, contents) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap();
+        archive
+    }
+
+    fn tar(files: &[(&str, &[u8])], gzip: bool) -> NamedTempFile {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *contents).unwrap();
+        }
+        let mut bytes = builder.into_inner().unwrap();
+        if gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&bytes).unwrap();
+            bytes = encoder.finish().unwrap();
+        }
+        let mut archive = NamedTempFile::new().unwrap();
+        archive.write_all(&bytes).unwrap();
+        archive
+    }
+
+    #[test]
+    fn test_paths() {
+        assert!(is_safe_path("renders/cat.png"));
+        assert!(is_safe_path("12:30.png"));
+        assert!(!is_safe_path("../cat.png"));
+        assert!(!is_safe_path("renders/../../cat.png"));
+        assert!(!is_safe_path("/etc/passwd"));
+        assert!(!is_safe_path("C:\\cat.png"));
+        assert_eq!(without_extension("renders/cat.png"), "renders/cat");
+        assert_eq!(without_extension("renders.v2/cat"), "renders.v2/cat");
+        assert_eq!(without_extension(".hidden"), ".hidden");
+    }
+
+    #[actix_web::test]
+    async fn test_read_zip() {
+        let archive = zip(&[
+            ("renders/cat.png", b"image"),
+            ("renders/cat.txt", b"parameters"),
+            ("../escape.png", b"image"),
+            ("__MACOSX/renders/._cat.png", b"junk"),
+        ]);
+        assert_eq!(
+            ArchiveFormat::detect(archive.path()).unwrap(),
+            Some(ArchiveFormat::Zip)
+        );
+        let (items, result) = read(&archive, LIMITS).await;
+        result.unwrap();
+
+        assert_eq!(items.len(), 2);
+        let entry = items[0].as_ref().ok().unwrap();
+        assert_eq!(entry.path, "renders/cat.png");
+        assert_eq!(entry.sidecar.as_deref(), Some("parameters"));
+        assert_eq!(std::fs::read(entry.file.path()).unwrap(), b"image");
+        let rejected = items[1].as_ref().err().unwrap();
+        assert_eq!(rejected.path, "../escape.png");
+    }
+
+    #[actix_web::test]
+    async fn test_read_tar() {
+        let limits = Limits {
+            entry_size: 1000,
+            total_size: 10_000,
+        };
+        let large = [b'x'; 2000];
+        let files: [(&str, &[u8]); 3] = [
+            ("renders/cat.png", b"image"),
+            ("renders/cat.txt", b"parameters"),
+            ("large.png", &large),
+        ];
+        for (gzip, format) in [(false, ArchiveFormat::Tar), (true, ArchiveFormat::TarGz)] {
+            let archive = tar(&files, gzip);
+            assert_eq!(ArchiveFormat::detect(archive.path()).unwrap(), Some(format));
+            let (items, result) = read(&archive, limits).await;
+            result.unwrap();
+
+            assert_eq!(items.len(), 2);
+            let entry = items[0].as_ref().ok().unwrap();
+            assert_eq!(entry.path, "renders/cat.png");
+            assert_eq!(entry.sidecar.as_deref(), Some("parameters"));
+            assert_eq!(std::fs::read(entry.file.path()).unwrap(), b"image");
+            let rejected = items[1].as_ref().err().unwrap();
+            assert_eq!(rejected.path, "large.png");
+            assert_eq!(rejected.reason, "File is larger than 1000 bytes");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_total_size_limit() {
+        let limits = Limits {
+            entry_size: 1000,
+            total_size: 1500,
+        };
+        let files: [(&str, &[u8]); 2] = [("a.png", &[b'a'; 1000]), ("b.png", &[b'b'; 1000])];
+        // Tar is checked by sizes in headers, ZIP by bytes read
+        for archive in [zip(&files), tar(&files, false), tar(&files, true)] {
+            let (_, result) = read(&archive, limits).await;
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "Unpacked archive is larger than 1500 bytes"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compression_ratio_limit() {
+        let zeros = vec![0; 4 * 1024 * 1024];
+        let archive = zip(&[("bomb.png", &zeros), ("cat.png", b"image")]);
+        let (items, result) = read(&archive, LIMITS).await;
+        result.unwrap();
+
+        assert_eq!(items.len(), 2);
+        let rejected = items[0].as_ref().err().unwrap();
+        assert_eq!(rejected.path, "bomb.png");
+        assert_eq!(rejected.reason, "File is compressed more than 100 times");
+        assert!(items[1].is_ok());
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use chrono::{NaiveDateTime, Utc};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Pool, Sqlite};
+use tempfile::NamedTempFile;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tokio_util::io::SyncIoBridge;
+
+use crate::{
+    archive::is_safe_path,
+    storage::MediaStore,
+    utils::hash::{sha256_file, sha256_file_blocking},
+};
+
+const MANIFEST_NAME: &str = "manifest.json";
+const DATABASE_NAME: &str = "db.sqlite3";
+const MEDIA_DIR: &str = "media/";
+/// Version of backup layout, bumped on incompatible changes
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Hex-encoded SHA-256 of the contents
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Last entry of every backup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created_at: NaiveDateTime,
+    /// Latest migration applied to the database
+    pub migration: i64,
+    /// `created_at` of the backup this incremental backup is based on
+    pub base: Option<NaiveDateTime>,
+    pub database: ManifestFile,
+    /// All media files of the library by key. Files unchanged since the base backup
+    /// are listed but not stored in the archive
+    pub files: BTreeMap<String, ManifestFile>,
+}
+
+/// Media files which belong to the library, hidden top-level directories hold
+/// unfinished uploads and restores
+fn is_library_file(key: &str) -> bool {
+    !key.starts_with('.')
+}
+
+async fn list_library_files(store: &dyn MediaStore) -> io::Result<Vec<String>> {
+    let mut keys = store.list("").await?;
+    keys.retain(|key| is_library_file(key));
+    Ok(keys)
+}
+
+/// Version of the latest migration known to this build
+pub fn latest_migration() -> i64 {
+    sqlx::migrate!()
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Version of the latest migration applied to the database
+pub async fn fetch_applied_migration(
+    executor: impl Executor<'_, Database = Sqlite>,
+) -> sqlx::Result<i64> {
+    sqlx::query_scalar("SELECT coalesce(max(version), 0) FROM _sqlx_migrations WHERE success")
+        .fetch_one(executor)
+        .await
+}
+
+/// Write `.tar.gz` backup to `writer`: snapshot of the database taken with `VACUUM INTO`,
+/// media files and the manifest with their hashes. With `base` only files which are new or
+/// changed since that backup are written. The archive is written while files are read
+pub async fn write_backup(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    base: Option<&Manifest>,
+    writer: impl AsyncWrite + Unpin + Send + 'static,
+) -> anyhow::Result<Manifest> {
+    let (sender, mut receiver) = mpsc::channel::<(String, NamedTempFile)>(1);
+    let bridge = SyncIoBridge::new(writer);
+    let tar_writer = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut tar = tar::Builder::new(GzEncoder::new(bridge, Compression::default()));
+        while let Some((name, file)) = receiver.blocking_recv() {
+            tar.append_path_with_name(file.path(), name)?;
+        }
+        tar.into_inner()?.finish()?.shutdown()?;
+        Ok(())
+    });
+
+    let result = send_backup_files(pool, store, base, &sender).await;
+    drop(sender);
+    // Error of the writer explains why files could not be sent
+    tar_writer.await??;
+    result
+}
+
+async fn send_backup_files(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    base: Option<&Manifest>,
+    sender: &mpsc::Sender<(String, NamedTempFile)>,
+) -> anyhow::Result<Manifest> {
+    let send = |name: String, file: NamedTempFile| async move {
+        sender
+            .send((name, file))
+            .await
+            .map_err(|_| anyhow::anyhow!("Backup writer stopped"))
+    };
+
+    // Media is read after the snapshot, so only files purged meanwhile may be missing
+    let created_at = Utc::now().naive_utc();
+    let snapshot = NamedTempFile::new()?;
+    sqlx::query("VACUUM INTO ?")
+        .bind(snapshot.path().to_string_lossy().as_ref())
+        .execute(pool)
+        .await?;
+    let migration = fetch_applied_migration(pool).await?;
+    let database = ManifestFile {
+        sha256: sha256_file(snapshot.path()).await?,
+        size: snapshot.as_file().metadata()?.len(),
+    };
+    send(DATABASE_NAME.to_owned(), snapshot).await?;
+
+    let mut files = BTreeMap::new();
+    for key in list_library_files(store).await? {
+        let mut stream = match store.get(&key).await {
+            Ok(stream) => stream,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                log::warn!("File {} was removed during backup", key);
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let file = NamedTempFile::new()?;
+        let mut output = tokio::fs::File::from_std(file.reopen()?);
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(chunk) = stream.try_next().await? {
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            output.write_all(&chunk).await?;
+        }
+        output.flush().await?;
+        let entry = ManifestFile {
+            sha256: hex::encode(hasher.finalize()),
+            size,
+        };
+        if base.and_then(|base| base.files.get(&key)) != Some(&entry) {
+            send(format!("{}{}", MEDIA_DIR, key), file).await?;
+        }
+        files.insert(key, entry);
+    }
+
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        created_at,
+        migration,
+        base: base.map(|base| base.created_at),
+        database,
+        files,
+    };
+    let file = NamedTempFile::new()?;
+    serde_json::to_writer_pretty(file.as_file(), &manifest)?;
+    send(MANIFEST_NAME.to_owned(), file).await?;
+    Ok(manifest)
+}
+
+/// Call `visit` with name and contents of every regular file of `.tar.gz` archive
+fn visit_entries(
+    path: &Path,
+    mut visit: impl FnMut(&str, &mut dyn Read) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut archive = tar::Archive::new(MultiGzDecoder::new(BufReader::new(file)));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        visit(&name, &mut entry)?;
+    }
+    Ok(())
+}
+
+/// Manifest of backup at `path`. Blocking, the whole archive is read
+pub fn read_manifest(path: &Path) -> anyhow::Result<Manifest> {
+    let mut manifest = None;
+    visit_entries(path, |name, reader| {
+        if name == MANIFEST_NAME {
+            manifest = Some(serde_json::from_reader(reader)?);
+        }
+        Ok(())
+    })?;
+    let manifest: Manifest =
+        manifest.with_context(|| format!("{} has no manifest", path.display()))?;
+    if manifest.version != FORMAT_VERSION {
+        bail!("Unsupported backup format version {}", manifest.version);
+    }
+    Ok(manifest)
+}
+
+/// Unpack backups into `directory`: the database to `db.sqlite3` and media files to `media/`.
+/// The last of `archives` is restored, the others are backups it's based on. Every file is
+/// checked against the manifest of the last backup. Blocking
+pub fn unpack_backups(archives: &[PathBuf], directory: &Path) -> anyhow::Result<Manifest> {
+    let (last, bases) = archives.split_last().context("No backups to restore")?;
+    let manifest = read_manifest(last)?;
+
+    let mut has_database = false;
+    let mut restored = HashSet::new();
+    for archive in std::iter::once(last).chain(bases.iter().rev()) {
+        let is_last = archive == last;
+        visit_entries(archive, |name, reader| {
+            let (destination, expected) = if name == DATABASE_NAME && is_last {
+                (directory.join(DATABASE_NAME), &manifest.database)
+            } else if let Some(key) = name.strip_prefix(MEDIA_DIR) {
+                match manifest.files.get(key) {
+                    Some(expected) if !restored.contains(key) => {
+                        if !is_safe_path(key) {
+                            bail!("Unsafe path {} in {}", name, archive.display());
+                        }
+                        (directory.join(name), expected)
+                    }
+                    _ => return Ok(()),
+                }
+            } else {
+                return Ok(());
+            };
+
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            io::copy(reader, &mut File::create(&destination)?)?;
+            if sha256_file_blocking(&destination)? != expected.sha256 {
+                // Earlier backups may have older versions of files
+                if is_last {
+                    bail!("{} in {} doesn't match manifest", name, archive.display());
+                }
+                std::fs::remove_file(&destination)?;
+                return Ok(());
+            }
+            match name.strip_prefix(MEDIA_DIR) {
+                Some(key) => {
+                    restored.insert(key.to_owned());
+                }
+                None => has_database = true,
+            }
+            Ok(())
+        })?;
+    }
+
+    if !has_database {
+        bail!("{} has no database", last.display());
+    }
+    let missing: Vec<&String> = manifest
+        .files
+        .keys()
+        .filter(|key| !restored.contains(*key))
+        .collect();
+    if let Some(key) = missing.first() {
+        bail!(
+            "{} files of the backup are not in the archives, e.g. {}. \
+            Pass incremental backups after the backups they are based on",
+            missing.len(),
+            key
+        );
+    }
+    Ok(manifest)
+}
+
+/// Path of SQLite database file from `sqlite://path?options` URL
+fn database_path(url: &str) -> anyhow::Result<PathBuf> {
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+        .unwrap_or(url);
+    let path = path.split('?').next().unwrap_or_default();
+    if path.is_empty() || path == ":memory:" {
+        bail!("Database URL {} doesn't point to a file", url);
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Replace the library with backups, see [`unpack_backups`]. Libraries with images or media
+/// files are replaced only if `force` is set. `pool` is closed before the database file is
+/// replaced, then migrations are applied to the restored database
+pub async fn restore(
+    pool: Pool<Sqlite>,
+    database_url: &str,
+    media_root: &Path,
+    store: &dyn MediaStore,
+    archives: Vec<PathBuf>,
+    force: bool,
+) -> anyhow::Result<Manifest> {
+    let database_path = database_path(database_url)?;
+    let image_count: i64 = sqlx::query_scalar("SELECT count(*) FROM image")
+        .fetch_one(&pool)
+        .await?;
+    let existing_files = list_library_files(store).await?;
+    if !force && (image_count > 0 || !existing_files.is_empty()) {
+        bail!("Library is not empty, pass --force to replace it");
+    }
+
+    let directory = tempfile::Builder::new()
+        .prefix(".restore-")
+        .tempdir_in(media_root)?;
+    let path = directory.path().to_owned();
+    let manifest = tokio::task::spawn_blocking(move || unpack_backups(&archives, &path)).await??;
+    if manifest.migration > latest_migration() {
+        bail!(
+            "Backup is made by a newer version of sdgenbox (migration {})",
+            manifest.migration
+        );
+    }
+
+    // Files of the backup are published before the database which refers to them,
+    // and files of the old library are deleted only when the new database is in place
+    for key in manifest.files.keys() {
+        let source = directory.path().join(MEDIA_DIR).join(key);
+        store.publish(key, &source).await?;
+    }
+
+    // The database is replaced by rename, so it's either old or new if restore is interrupted
+    let database_directory = match database_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let new_database = tempfile::Builder::new()
+        .prefix(".restore-")
+        .tempfile_in(database_directory)?
+        .into_temp_path();
+    tokio::fs::copy(directory.path().join(DATABASE_NAME), &new_database).await?;
+    pool.close().await;
+    // Journal of the old database must not be applied to the new one
+    for suffix in ["-wal", "-shm"] {
+        let mut path = database_path.clone().into_os_string();
+        path.push(suffix);
+        match tokio::fs::remove_file(path).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+    }
+    new_database.persist(&database_path)?;
+
+    for key in existing_files {
+        if !manifest.files.contains_key(&key) {
+            store.delete(&key).await?;
+        }
+    }
+
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .connect(database_url)
+        .await?;
+    sqlx::migrate!().run(&pool).await?;
+    pool.close().await;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, path::Path};
+
+    use sqlx::{migrate, Pool, Sqlite};
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::{database_path, read_manifest, restore, unpack_backups, write_backup};
+    use crate::{
+        models::{insert_test_image, new_test_image, Image},
+        storage::{LocalStore, MediaStore},
+    };
+
+    async fn new_library(root: &Path) -> (String, Pool<Sqlite>, LocalStore) {
+        std::fs::create_dir_all(root).unwrap();
+        let url = format!("sqlite://{}?mode=rwc", root.join("db.sqlite3").display());
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .connect(&url)
+            .await
+            .unwrap();
+        migrate!().run(&pool).await.unwrap();
+        (url, pool, LocalStore::new(&root.join("media")))
+    }
+
+    async fn put(store: &LocalStore, key: &str, contents: &str) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        store.put(key, file.path()).await.unwrap();
+    }
+
+    async fn backup(pool: &Pool<Sqlite>, store: &LocalStore, path: &Path, base: Option<&Path>) {
+        let base = base.map(|base| read_manifest(base).unwrap());
+        let file = tokio::fs::File::create(path).await.unwrap();
+        write_backup(pool, store, base.as_ref(), file)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_backup_and_restore() {
+        let root = TempDir::new().unwrap();
+        let (_, pool, store) = new_library(&root.path().join("source")).await;
+        insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/a.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        put(&store, "images/a.png", "a").await;
+        put(&store, ".staging/unfinished.png", "staged").await;
+        let full = root.path().join("full.tar.gz");
+        backup(&pool, &store, &full, None).await;
+
+        put(&store, "images/b.png", "b").await;
+        let incremental = root.path().join("incremental.tar.gz");
+        backup(&pool, &store, &incremental, Some(&full)).await;
+        let manifest = read_manifest(&incremental).unwrap();
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            vec!["images/a.png", "images/b.png"]
+        );
+        // Unchanged files are only in the full backup
+        let directory = TempDir::new().unwrap();
+        assert!(unpack_backups(std::slice::from_ref(&incremental), directory.path()).is_err());
+
+        let target = root.path().join("target");
+        let (url, target_pool, target_store) = new_library(&target).await;
+        put(&target_store, "images/old.png", "old").await;
+        let archives = vec![full, incremental];
+        assert!(restore(
+            target_pool.clone(),
+            &url,
+            &target,
+            &target_store,
+            archives.clone(),
+            false
+        )
+        .await
+        .is_err());
+        restore(target_pool, &url, &target, &target_store, archives, true)
+            .await
+            .unwrap();
+
+        let files = target_store.list("").await.unwrap();
+        assert_eq!(files, vec!["images/a.png", "images/b.png"]);
+        // The database is swapped by rename, no temporary copies are left
+        for directory in [target.clone(), target.join("media")] {
+            for entry in std::fs::read_dir(directory).unwrap() {
+                let name = entry.unwrap().file_name();
+                assert!(
+                    !name.to_string_lossy().starts_with(".restore-"),
+                    "{:?}",
+                    name
+                );
+            }
+        }
+        let (_, target_pool, _) = new_library(&target).await;
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM image")
+            .fetch_one(&target_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_database_path() {
+        assert_eq!(
+            database_path("sqlite://db.sqlite3").unwrap(),
+            Path::new("db.sqlite3")
+        );
+        assert_eq!(
+            database_path("sqlite:///tmp/db.sqlite3?mode=rwc").unwrap(),
+            Path::new("/tmp/db.sqlite3")
+        );
+        assert!(database_path("sqlite::memory:").is_err());
+    }
+}
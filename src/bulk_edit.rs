@@ -0,0 +1,469 @@
+use regex::Regex;
+use serde_json::Value;
+use sqlx::{types::Json, Executor, Sqlite, Transaction};
+
+use crate::{
+    models::{fetch_images, Limits},
+    revisions::{diff, field_changes, update_image_params, FieldChange, ImageParams},
+    utils::search::{SearchQuery, SortOrder},
+};
+
+/// Fields of [`ImageParams`] which can be changed by bulk edit
+pub const FIELDS: [&str; 11] = [
+    "prompt",
+    "negative_prompt",
+    "steps",
+    "sampler",
+    "cfg_scale",
+    "seed",
+    "width",
+    "height",
+    "model_hash",
+    "model",
+    "clip_skip",
+];
+
+/// Fields with text values, they can be changed by find and replace
+pub const TEXT_FIELDS: [&str; 5] = [
+    "prompt",
+    "negative_prompt",
+    "sampler",
+    "model_hash",
+    "model",
+];
+
+/// How far preview and result list changed images
+pub const SAMPLE_SIZE: usize = 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BulkEditError {
+    #[error("Unknown field `{0}`")]
+    UnknownField(String),
+    #[error("Invalid value of `{0}`: {1}")]
+    InvalidValue(String, String),
+    #[error("Invalid regular expression: {0}")]
+    InvalidPattern(#[from] regex::Error),
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum UndoError {
+    #[error("No bulk edit {0}")]
+    NotFound(i64),
+    #[error("Bulk edit {0} is already undone")]
+    AlreadyUndone(i64),
+}
+
+#[derive(Debug)]
+pub enum BulkOperation {
+    /// Set field to the value
+    Set { field: String, value: Value },
+    /// Replace all matches of pattern in text field, replacement may reference groups as `$1`
+    Replace {
+        field: String,
+        pattern: Regex,
+        replacement: String,
+    },
+}
+
+impl BulkOperation {
+    pub fn set(field: &str, value: &str) -> Result<Self, BulkEditError> {
+        if !FIELDS.contains(&field) {
+            return Err(BulkEditError::UnknownField(field.to_string()));
+        }
+        let invalid_value = || BulkEditError::InvalidValue(field.to_string(), value.to_string());
+        let value = match field {
+            field if TEXT_FIELDS.contains(&field) => Value::String(value.to_string()),
+            "clip_skip" if value.trim().is_empty() => Value::Null,
+            "cfg_scale" => {
+                let number: f64 = value.trim().parse().map_err(|_| invalid_value())?;
+                serde_json::Number::from_f64(number)
+                    .map(Value::Number)
+                    .ok_or_else(invalid_value)?
+            }
+            _ => {
+                let number: i64 = value.trim().parse().map_err(|_| invalid_value())?;
+                Value::Number(number.into())
+            }
+        };
+
+        Ok(BulkOperation::Set {
+            field: field.to_string(),
+            value,
+        })
+    }
+
+    pub fn replace(field: &str, pattern: &str, replacement: &str) -> Result<Self, BulkEditError> {
+        if !TEXT_FIELDS.contains(&field) {
+            return Err(BulkEditError::UnknownField(field.to_string()));
+        }
+        Ok(BulkOperation::Replace {
+            field: field.to_string(),
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// Human readable description, saved with bulk edit
+    pub fn describe(&self) -> String {
+        match self {
+            BulkOperation::Set { field, value } => format!("Set {} to {}", field, value),
+            BulkOperation::Replace {
+                field,
+                pattern,
+                replacement,
+            } => format!(
+                "Replace /{}/ with \"{}\" in {}",
+                pattern, replacement, field
+            ),
+        }
+    }
+
+    fn apply(&self, params: &ImageParams) -> anyhow::Result<ImageParams> {
+        let mut values = params.to_map();
+        match self {
+            BulkOperation::Set { field, value } => {
+                values.insert(field.clone(), value.clone());
+            }
+            BulkOperation::Replace {
+                field,
+                pattern,
+                replacement,
+            } => {
+                if let Some(Value::String(text)) = values.get(field) {
+                    let replaced = pattern.replace_all(text, replacement.as_str()).into_owned();
+                    values.insert(field.clone(), Value::String(replaced));
+                }
+            }
+        }
+        Ok(serde_json::from_value(Value::Object(values))?)
+    }
+}
+
+pub struct ImageChanges {
+    pub image_id: i64,
+    pub changes: Vec<FieldChange>,
+}
+
+pub struct BulkEditReport {
+    /// Images found by the search
+    pub matched: usize,
+    /// Images which are (or would be) changed by the operation
+    pub changed: usize,
+    /// First [`SAMPLE_SIZE`] changed images
+    pub sample: Vec<ImageChanges>,
+    /// Id of applied bulk edit, not set for dry run
+    pub bulk_edit_id: Option<i64>,
+}
+
+/// Apply operation to all images found by `search`, recording revisions of changed images.
+/// With `dry_run` nothing is written, only the report of what would change is returned.
+pub async fn bulk_edit(
+    transaction: &mut Transaction<'_, Sqlite>,
+    search: &str,
+    operation: &BulkOperation,
+    dry_run: bool,
+) -> anyhow::Result<BulkEditReport> {
+    let query: SearchQuery = search.parse()?;
+    let images = fetch_images(
+        &mut *transaction,
+        &query,
+        SortOrder::default(),
+        &Limits::all(),
+    )
+    .await?;
+
+    let bulk_edit_id = match dry_run {
+        true => None,
+        false => {
+            let description = operation.describe();
+            let id = sqlx::query_scalar!(
+                "INSERT INTO bulk_edit (search, description) VALUES (?, ?) RETURNING id",
+                search,
+                description,
+            )
+            .fetch_one(&mut *transaction)
+            .await?;
+            Some(id)
+        }
+    };
+
+    let mut report = BulkEditReport {
+        matched: images.len(),
+        changed: 0,
+        sample: Vec::new(),
+        bulk_edit_id,
+    };
+    for image in &images {
+        let params = ImageParams::from(image);
+        let new_params = operation.apply(&params)?;
+        let (old_values, new_values) = diff(&params, &new_params);
+        if new_values.is_empty() {
+            continue;
+        }
+        if !dry_run {
+            update_image_params(transaction, image.id, &new_params, bulk_edit_id).await?;
+        }
+        report.changed += 1;
+        if report.sample.len() < SAMPLE_SIZE {
+            report.sample.push(ImageChanges {
+                image_id: image.id,
+                changes: field_changes(&old_values, &new_values),
+            });
+        }
+    }
+
+    if let Some(bulk_edit_id) = bulk_edit_id {
+        let changed = report.changed as i64;
+        sqlx::query!(
+            "UPDATE bulk_edit SET image_count = ? WHERE id = ?",
+            changed,
+            bulk_edit_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    Ok(report)
+}
+
+#[derive(Debug, PartialEq, sqlx::FromRow)]
+pub struct BulkEdit {
+    pub id: i64,
+    pub search: String,
+    pub description: String,
+    pub image_count: i64,
+    pub created_at: chrono::NaiveDateTime,
+    pub undone_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Latest bulk edits, newest first
+pub async fn fetch_bulk_edits(
+    executor: impl Executor<'_, Database = Sqlite>,
+    limit: i64,
+) -> sqlx::Result<Vec<BulkEdit>> {
+    sqlx::query_as!(
+        BulkEdit,
+        r#"SELECT id as "id!", search, description, image_count,
+        created_at as "created_at: _", undone_at as "undone_at: _"
+        FROM bulk_edit ORDER BY id DESC LIMIT ?"#,
+        limit,
+    )
+    .fetch_all(executor)
+    .await
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct UndoReport {
+    pub reverted: usize,
+    /// Images edited again after the bulk edit, they are left as is
+    pub skipped: Vec<i64>,
+}
+
+/// Return fields changed by bulk edit to their previous values. Images whose fields
+/// were changed again since then are skipped to not lose later edits.
+/// Fails with [`UndoError`] if there is no such bulk edit or it's already undone
+pub async fn undo_bulk_edit(
+    transaction: &mut Transaction<'_, Sqlite>,
+    bulk_edit_id: i64,
+) -> anyhow::Result<UndoReport> {
+    let undone = sqlx::query!(
+        "UPDATE bulk_edit SET undone_at = unixepoch() WHERE id = ? AND undone_at IS NULL",
+        bulk_edit_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if undone.rows_affected() == 0 {
+        let exists = sqlx::query_scalar!("SELECT 1 FROM bulk_edit WHERE id = ?", bulk_edit_id)
+            .fetch_optional(&mut *transaction)
+            .await?
+            .is_some();
+        return Err(match exists {
+            true => UndoError::AlreadyUndone(bulk_edit_id).into(),
+            false => UndoError::NotFound(bulk_edit_id).into(),
+        });
+    }
+
+    let revisions = sqlx::query!(
+        r#"SELECT image_revision.image_id, old_values as "old_values: Json<serde_json::Map<String, Value>>",
+        new_values as "new_values: Json<serde_json::Map<String, Value>>",
+        prompt, negative_prompt, steps, sampler, cfg_scale, seed, width, height, model_hash,
+        model, clip_skip, extra_params as "extra_params: Json<crate::models::ExtraParams>"
+        FROM image_revision JOIN image ON image.id = image_revision.image_id
+        WHERE bulk_edit_id = ? ORDER BY image_revision.id"#,
+        bulk_edit_id,
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let mut report = UndoReport::default();
+    for revision in revisions {
+        let current = ImageParams {
+            prompt: revision.prompt,
+            negative_prompt: revision.negative_prompt,
+            steps: revision.steps,
+            sampler: revision.sampler,
+            cfg_scale: revision.cfg_scale,
+            seed: revision.seed,
+            width: revision.width,
+            height: revision.height,
+            model_hash: revision.model_hash,
+            model: revision.model,
+            clip_skip: revision.clip_skip,
+            extra_params: revision.extra_params.0,
+        };
+        let mut values = current.to_map();
+        let unchanged = revision
+            .new_values
+            .iter()
+            .all(|(field, value)| values.get(field) == Some(value));
+        if !unchanged {
+            report.skipped.push(revision.image_id);
+            continue;
+        }
+        values.extend(revision.old_values.0);
+        let params: ImageParams = serde_json::from_value(Value::Object(values))?;
+        update_image_params(transaction, revision.image_id, &params, None).await?;
+        report.reverted += 1;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use super::{bulk_edit, fetch_bulk_edits, undo_bulk_edit, BulkOperation, UndoError};
+    use crate::{
+        models::{fetch_image_by_id, insert_test_image, new_test_image, new_test_pool, Image},
+        revisions::{update_image_params, ImageParams},
+    };
+
+    async fn model(pool: &Pool<Sqlite>, image_id: i64) -> String {
+        fetch_image_by_id(pool, image_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .model
+    }
+
+    #[test]
+    fn test_operations() {
+        assert!(BulkOperation::set("file_path", "x").is_err());
+        assert!(BulkOperation::set("steps", "many").is_err());
+        assert!(BulkOperation::set("clip_skip", "").is_ok());
+        assert!(BulkOperation::replace("steps", "1", "2").is_err());
+        assert!(BulkOperation::replace("prompt", "(", "").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_set_preview_apply_and_undo() {
+        let pool = new_test_pool().await;
+        let first = insert_test_image(
+            &pool,
+            Image {
+                prompt: "cat".to_string(),
+                model_hash: "aaa".to_string(),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let second = insert_test_image(
+            &pool,
+            Image {
+                prompt: "dog".to_string(),
+                model_hash: "aaa".to_string(),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let other = insert_test_image(
+            &pool,
+            Image {
+                prompt: "cat".to_string(),
+                model_hash: "bbb".to_string(),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let operation = BulkOperation::set("model", "renamed").unwrap();
+
+        let mut transaction = pool.begin().await.unwrap();
+        let report = bulk_edit(&mut transaction, "model_hash:aaa", &operation, true)
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+        assert_eq!((report.matched, report.changed), (2, 2));
+        assert_eq!(report.sample[0].changes[0].new_value, "renamed");
+        assert_eq!(report.bulk_edit_id, None);
+        assert_eq!(model(&pool, first).await, "model");
+
+        let mut transaction = pool.begin().await.unwrap();
+        let report = bulk_edit(&mut transaction, "model_hash:aaa", &operation, false)
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+        assert_eq!(report.changed, 2);
+        assert_eq!(model(&pool, first).await, "renamed");
+        assert_eq!(model(&pool, second).await, "renamed");
+        assert_eq!(model(&pool, other).await, "model");
+        let bulk_edits = fetch_bulk_edits(&pool, 10).await.unwrap();
+        assert_eq!(bulk_edits[0].image_count, 2);
+
+        // Edited again after the bulk edit, kept as is
+        let mut transaction = pool.begin().await.unwrap();
+        let image = fetch_image_by_id(&mut transaction, second)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut params = ImageParams::from(&image);
+        params.model = "manual".to_string();
+        update_image_params(&mut transaction, second, &params, None)
+            .await
+            .unwrap();
+        let bulk_edit_id = report.bulk_edit_id.unwrap();
+        let report = undo_bulk_edit(&mut transaction, bulk_edit_id)
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+        assert_eq!(report.reverted, 1);
+        assert_eq!(report.skipped, vec![second]);
+        assert_eq!(model(&pool, first).await, "model");
+        assert_eq!(model(&pool, second).await, "manual");
+        let bulk_edits = fetch_bulk_edits(&pool, 10).await.unwrap();
+        assert!(bulk_edits[0].undone_at.is_some());
+
+        let mut transaction = pool.begin().await.unwrap();
+        for (bulk_edit_id, error) in [
+            (bulk_edit_id, UndoError::AlreadyUndone(bulk_edit_id)),
+            (bulk_edit_id + 1, UndoError::NotFound(bulk_edit_id + 1)),
+        ] {
+            let result = undo_bulk_edit(&mut transaction, bulk_edit_id).await;
+            assert_eq!(result.unwrap_err().downcast::<UndoError>().unwrap(), error);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_replace() {
+        let pool = new_test_pool().await;
+        let image_id = insert_test_image(
+            &pool,
+            Image {
+                prompt: "a cat, (cat ears:1.2)".to_string(),
+                model_hash: "aaa".to_string(),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let operation = BulkOperation::replace("prompt", r"cat( ears)?", "dog$1").unwrap();
+        let mut transaction = pool.begin().await.unwrap();
+        let report = bulk_edit(&mut transaction, "", &operation, false)
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        assert_eq!(report.changed, 1);
+        let image = fetch_image_by_id(&pool, image_id).await.unwrap().unwrap();
+        assert_eq!(image.prompt, "a dog, (dog ears:1.2)");
+    }
+}
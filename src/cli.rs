@@ -0,0 +1,374 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    backup,
+    config::{Config, MediaLayout},
+    dump::{self, DumpFormat},
+    fsck::RepairOptions,
+    layout, merge,
+    storage::{MediaStore, Staging},
+    sync,
+};
+
+/// Simple web server for storing and navigating through images generated via Stable Diffusion
+#[derive(Debug, clap::Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Run web server (default)
+    Serve,
+    /// Check consistency of database and media storage
+    Fsck(FsckArgs),
+    /// Move image files to the layout configured by MEDIA_LAYOUT.
+    /// Safe to run while server is running and to restart if interrupted
+    MigrateLayout(MigrateLayoutArgs),
+    /// Write metadata of all images with tags, ratings, albums and notes, without files
+    DumpMetadata(DumpMetadataArgs),
+    /// Load metadata written by dump-metadata, images are matched by file hash or path.
+    /// Loading the same dump twice changes nothing
+    LoadMetadata(LoadMetadataArgs),
+    /// Write snapshot of database and media files into a .tar.gz with a manifest of hashes
+    Backup(BackupArgs),
+    /// Replace the library with a backup and apply migrations to it
+    Restore(RestoreArgs),
+    /// Copy images missing here from another sdgenbox library with their tags, ratings and albums
+    Merge(MergeArgs),
+    /// Pull new images and changes of tags, ratings and albums from another sdgenbox server.
+    /// Fields changed on both sides keep the value changed last
+    Sync(SyncArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct FsckArgs {
+    /// Create images for orphan files using metadata of the files
+    #[arg(long)]
+    import_orphans: bool,
+    /// Mark images whose files are missing
+    #[arg(long)]
+    mark_missing: bool,
+    /// Move files with mismatching hash to quarantine
+    #[arg(long)]
+    quarantine_corrupt: bool,
+    /// Record hashes of images imported before hashing was introduced
+    #[arg(long)]
+    record_hashes: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct MigrateLayoutArgs {
+    /// Number of images moved between database updates
+    #[arg(long, default_value_t = 500)]
+    batch_size: i64,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct DumpMetadataArgs {
+    #[arg(long, value_enum, default_value_t = DumpFormat::Jsonl)]
+    format: DumpFormat,
+    /// File to write, standard output by default
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct LoadMetadataArgs {
+    /// Dump file
+    input: PathBuf,
+    /// Format of dump, detected by file extension by default
+    #[arg(long, value_enum)]
+    format: Option<DumpFormat>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BackupArgs {
+    /// Archive to write, e.g. backup.tar.gz
+    output: PathBuf,
+    /// Previous backup, only files new or changed since it are written
+    #[arg(long)]
+    since: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RestoreArgs {
+    /// Backup to restore, preceded by the backups it's based on if it's incremental
+    #[arg(required = true)]
+    archives: Vec<PathBuf>,
+    /// Replace library which already has images or media files
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct MergeArgs {
+    /// Database of the other library, e.g. db.sqlite3
+    database: PathBuf,
+    /// MEDIA_ROOT of the other library
+    media_root: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct SyncArgs {
+    /// URL of the other server, e.g. http://192.168.1.10:8000
+    peer: String,
+}
+
+pub async fn fsck(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    args: FsckArgs,
+) -> anyhow::Result<()> {
+    let options = RepairOptions {
+        import_orphans: args.import_orphans,
+        mark_missing: args.mark_missing,
+        quarantine_corrupt: args.quarantine_corrupt,
+        record_hashes: args.record_hashes,
+    };
+    let report = crate::fsck::fsck(pool, store, options).await?;
+
+    println!(
+        "Checked {} images and {} files",
+        report.checked_images, report.checked_files
+    );
+    if report.hashed_images > 0 {
+        println!("Recorded hashes of {} images", report.hashed_images);
+    } else if report.unhashed_images > 0 {
+        println!(
+            "{} images have no recorded hash, pass --record-hashes to record them",
+            report.unhashed_images
+        );
+    }
+    for image in &report.missing_files {
+        println!("missing: image {} -> {}", image.id, image.file_path);
+    }
+    for (image, error) in &report.unreadable_files {
+        println!(
+            "unreadable: image {} -> {}: {}",
+            image.id, image.file_path, error
+        );
+    }
+    for file_path in &report.orphan_files {
+        println!("orphan: {}", file_path);
+    }
+    for mismatch in &report.hash_mismatches {
+        println!(
+            "hash mismatch: image {} -> {} (expected {}, actual {})",
+            mismatch.image_id, mismatch.file_path, mismatch.expected, mismatch.actual
+        );
+    }
+    for (file_path, image_id) in &report.imported_orphans {
+        println!("imported: {} as image {}", file_path, image_id);
+    }
+    for (file_path, reason) in &report.failed_imports {
+        println!("import failed: {}: {}", file_path, reason);
+    }
+    if report.marked_missing > 0 {
+        println!("Marked {} images as missing", report.marked_missing);
+    }
+    if report.quarantined > 0 {
+        println!("Quarantined {} files", report.quarantined);
+    }
+    if report.is_clean() {
+        println!("No problems found");
+    }
+    Ok(())
+}
+
+pub async fn migrate_layout(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    layout: MediaLayout,
+    args: MigrateLayoutArgs,
+) -> anyhow::Result<()> {
+    let summary = layout::migrate_layout(pool, store, layout, args.batch_size).await?;
+
+    println!(
+        "Moved {} files, updated {} images with already moved files",
+        summary.moved, summary.updated
+    );
+    for image_id in &summary.missing {
+        println!("missing: image {}", image_id);
+    }
+    Ok(())
+}
+
+pub async fn dump_metadata(pool: &Pool<Sqlite>, args: DumpMetadataArgs) -> anyhow::Result<()> {
+    let records = dump::fetch_records(pool).await?;
+    let count = records.len();
+    match &args.output {
+        Some(output) => {
+            let file = File::create(output)
+                .with_context(|| format!("Failed to create {}", output.display()))?;
+            let mut writer = BufWriter::new(file);
+            dump::write_records(args.format, records, &mut writer)?;
+            writer.flush()?;
+            println!("Dumped {} images to {}", count, output.display());
+        }
+        None => {
+            let mut writer = BufWriter::new(io::stdout().lock());
+            dump::write_records(args.format, records, &mut writer)?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn load_metadata(pool: &Pool<Sqlite>, args: LoadMetadataArgs) -> anyhow::Result<()> {
+    let format = match args.format {
+        Some(format) => format,
+        None => DumpFormat::from_path(&args.input)
+            .context("Unknown dump format, set it with --format")?,
+    };
+    let file = File::open(&args.input)
+        .with_context(|| format!("Failed to open {}", args.input.display()))?;
+    let records = dump::read_records(format, BufReader::new(file))?;
+    let report = dump::load_records(pool, records).await?;
+
+    println!(
+        "Inserted {} images, updated {}, unchanged {}",
+        report.inserted, report.updated, report.unchanged
+    );
+    for conflict in &report.conflicts {
+        println!(
+            "conflict: image {} {}: {} -> {}",
+            conflict.image_id, conflict.field, conflict.local, conflict.loaded
+        );
+    }
+    Ok(())
+}
+
+pub async fn backup(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    args: BackupArgs,
+) -> anyhow::Result<()> {
+    let base = match args.since {
+        Some(since) => {
+            Some(tokio::task::spawn_blocking(move || backup::read_manifest(&since)).await??)
+        }
+        None => None,
+    };
+    let file = tokio::fs::File::create(&args.output)
+        .await
+        .with_context(|| format!("Failed to create {}", args.output.display()))?;
+    let manifest = match backup::write_backup(pool, store, base.as_ref(), file).await {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            tokio::fs::remove_file(&args.output).await?;
+            return Err(error);
+        }
+    };
+
+    let size: u64 = manifest.files.values().map(|file| file.size).sum();
+    println!(
+        "Backed up database and {} files ({} MiB) to {}",
+        manifest.files.len(),
+        size >> 20,
+        args.output.display()
+    );
+    if let Some(base) = manifest.base {
+        println!(
+            "Files unchanged since backup of {} are not included",
+            base.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    Ok(())
+}
+
+pub async fn restore(
+    pool: Pool<Sqlite>,
+    config: &Config,
+    store: &dyn MediaStore,
+    args: RestoreArgs,
+) -> anyhow::Result<()> {
+    let manifest = backup::restore(
+        pool,
+        &config.database_url,
+        &config.media_root,
+        store,
+        args.archives,
+        args.force,
+    )
+    .await?;
+
+    println!(
+        "Restored database and {} files backed up at {}",
+        manifest.files.len(),
+        manifest.created_at.format("%Y-%m-%d %H:%M:%S")
+    );
+    Ok(())
+}
+
+pub async fn merge(
+    pool: &Pool<Sqlite>,
+    config: &Config,
+    store: &dyn MediaStore,
+    args: MergeArgs,
+) -> anyhow::Result<()> {
+    let staging = Staging::new(&config.media_root);
+    let report = merge::merge_library(
+        pool,
+        &staging,
+        store,
+        config.media_layout,
+        &args.database,
+        &args.media_root,
+    )
+    .await?;
+
+    println!(
+        "Added {} images, updated {}, unchanged {}, skipped {}",
+        report.added.len(),
+        report.updated,
+        report.unchanged,
+        report.skipped.len()
+    );
+    if report.album_images > 0 {
+        println!("Added {} images to albums", report.album_images);
+    }
+    for (source_id, image_id) in &report.added {
+        println!("added: image {} as image {}", source_id, image_id);
+    }
+    for (source_id, reason) in &report.skipped {
+        println!("skipped: image {}: {}", source_id, reason);
+    }
+    for conflict in &report.conflicts {
+        println!(
+            "conflict: image {} {}: {} -> {}",
+            conflict.image_id, conflict.field, conflict.local, conflict.loaded
+        );
+    }
+    Ok(())
+}
+
+pub async fn sync(
+    pool: &Pool<Sqlite>,
+    config: &Config,
+    store: &dyn MediaStore,
+    args: SyncArgs,
+) -> anyhow::Result<()> {
+    let staging = Staging::new(&config.media_root);
+    let report = sync::sync(pool, &staging, store, config.media_layout, &args.peer).await?;
+
+    println!(
+        "Added {} images, updated {}, skipped {}",
+        report.added,
+        report.updated,
+        report.skipped.len()
+    );
+    for (file_hash, reason) in &report.skipped {
+        println!("skipped: file {}: {}", file_hash, reason);
+    }
+    Ok(())
+}
@@ -8,4 +8,55 @@ pub struct Config {
 
     pub database_url: String,
     pub media_root: Box<Path>,
+    /// How image files are laid out in `images/` directory
+    #[serde(default)]
+    pub media_layout: MediaLayout,
+    /// Images stay in trash for this many days before they are purged, 0 disables purging
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
+    /// Number of uploaded files whose metadata is extracted and hashed at the same time
+    #[serde(default = "default_upload_workers")]
+    pub upload_workers: usize,
+
+    /// Where media files are stored, see [`crate::storage::MediaStore`]
+    #[serde(default)]
+    pub storage: StorageBackend,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    #[serde(default = "default_s3_region")]
+    pub s3_region: String,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    /// Lifetime of presigned URLs in seconds. If not set, files are proxied through sdgenbox
+    pub s3_presign_expiry: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Local,
+    S3,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaLayout {
+    /// `images/<name>`
+    #[default]
+    Flat,
+    /// `images/ab/cd/<name>`, keeps directories small for large libraries
+    Sharded,
+}
+
+fn default_trash_retention_days() -> u32 {
+    30
+}
+
+fn default_upload_workers() -> usize {
+    std::thread::available_parallelism().map_or(4, |workers| workers.get())
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
 }
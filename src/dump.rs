@@ -0,0 +1,725 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, Executor, Pool, Sqlite, Transaction};
+
+use crate::{
+    albums::add_album_images,
+    models::{
+        insert_image, set_image_favorite, set_image_note, set_image_rating, ExtraParams, Image,
+        IMAGE_COLUMNS,
+    },
+    tags::{add_tags, normalize_name, TagError},
+};
+
+/// Format of metadata dumps
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum DumpFormat {
+    /// JSON object per line
+    Jsonl,
+    /// Extra parameters, tags and albums are JSON in their cells
+    Csv,
+}
+
+impl DumpFormat {
+    /// Format by file extension
+    pub fn from_path(path: &Path) -> Option<DumpFormat> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "jsonl" | "ndjson" => Some(DumpFormat::Jsonl),
+            "csv" => Some(DumpFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Image row with its tags and albums, without media
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct ImageRecord {
+    /// Id in the dumped library, images are matched by file instead
+    pub id: i64,
+    pub file_path: Option<String>,
+    pub file_hash: Option<String>,
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub steps: i64,
+    pub sampler: String,
+    pub cfg_scale: f64,
+    pub seed: i64,
+    pub width: i64,
+    pub height: i64,
+    pub model_hash: String,
+    pub model: String,
+    pub clip_skip: Option<i64>,
+    pub extra_params: ExtraParams,
+    pub rating: i64,
+    pub favorite: bool,
+    pub score: f64,
+    pub comparisons: i64,
+    pub note: String,
+    pub inbox: bool,
+    pub created_at: NaiveDateTime,
+    pub deleted_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Names of albums containing the image
+    #[serde(default)]
+    pub albums: Vec<String>,
+}
+
+/// [`ImageRecord`] as CSV row, CSV cells can't hold lists and maps
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRecord {
+    id: i64,
+    file_path: Option<String>,
+    file_hash: Option<String>,
+    prompt: String,
+    negative_prompt: String,
+    steps: i64,
+    sampler: String,
+    cfg_scale: f64,
+    seed: i64,
+    width: i64,
+    height: i64,
+    model_hash: String,
+    model: String,
+    clip_skip: Option<i64>,
+    extra_params: String,
+    rating: i64,
+    favorite: bool,
+    score: f64,
+    comparisons: i64,
+    note: String,
+    inbox: bool,
+    created_at: NaiveDateTime,
+    deleted_at: Option<NaiveDateTime>,
+    tags: String,
+    albums: String,
+}
+
+impl CsvRecord {
+    fn new(record: ImageRecord) -> serde_json::Result<Self> {
+        Ok(CsvRecord {
+            id: record.id,
+            file_path: record.file_path,
+            file_hash: record.file_hash,
+            prompt: record.prompt,
+            negative_prompt: record.negative_prompt,
+            steps: record.steps,
+            sampler: record.sampler,
+            cfg_scale: record.cfg_scale,
+            seed: record.seed,
+            width: record.width,
+            height: record.height,
+            model_hash: record.model_hash,
+            model: record.model,
+            clip_skip: record.clip_skip,
+            extra_params: serde_json::to_string(&record.extra_params)?,
+            rating: record.rating,
+            favorite: record.favorite,
+            score: record.score,
+            comparisons: record.comparisons,
+            note: record.note,
+            inbox: record.inbox,
+            created_at: record.created_at,
+            deleted_at: record.deleted_at,
+            tags: serde_json::to_string(&record.tags)?,
+            albums: serde_json::to_string(&record.albums)?,
+        })
+    }
+
+    fn into_record(self) -> serde_json::Result<ImageRecord> {
+        /// Empty cell is an empty list or map
+        fn parse<T: Default + serde::de::DeserializeOwned>(cell: &str) -> serde_json::Result<T> {
+            match cell.trim() {
+                "" => Ok(T::default()),
+                cell => serde_json::from_str(cell),
+            }
+        }
+        Ok(ImageRecord {
+            id: self.id,
+            file_path: self.file_path,
+            file_hash: self.file_hash,
+            prompt: self.prompt,
+            negative_prompt: self.negative_prompt,
+            steps: self.steps,
+            sampler: self.sampler,
+            cfg_scale: self.cfg_scale,
+            seed: self.seed,
+            width: self.width,
+            height: self.height,
+            model_hash: self.model_hash,
+            model: self.model,
+            clip_skip: self.clip_skip,
+            extra_params: parse(&self.extra_params)?,
+            rating: self.rating,
+            favorite: self.favorite,
+            score: self.score,
+            comparisons: self.comparisons,
+            note: self.note,
+            inbox: self.inbox,
+            created_at: self.created_at,
+            deleted_at: self.deleted_at,
+            tags: parse(&self.tags)?,
+            albums: parse(&self.albums)?,
+        })
+    }
+}
+
+/// Records of all images including trashed ones, ordered by id so dumps diff well
+pub async fn fetch_records(pool: &Pool<Sqlite>) -> sqlx::Result<Vec<ImageRecord>> {
+    fetch_records_filtered(pool, None).await
+}
+
+/// Records of images with `ids` including trashed ones, ordered by id
+pub async fn fetch_records_by_ids(
+    pool: &Pool<Sqlite>,
+    ids: &[i64],
+) -> sqlx::Result<Vec<ImageRecord>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    fetch_records_filtered(pool, Some(ids)).await
+}
+
+/// Records of images with `ids`, or of all images if `ids` is `None`
+async fn fetch_records_filtered(
+    pool: &Pool<Sqlite>,
+    ids: Option<&[i64]>,
+) -> sqlx::Result<Vec<ImageRecord>> {
+    // Ids are integers, so they are safe to inline
+    let filter = |column: &str| match ids {
+        Some(ids) => {
+            let ids: Vec<String> = ids.iter().map(i64::to_string).collect();
+            format!("{} IN ({})", column, ids.join(", "))
+        }
+        None => "TRUE".to_owned(),
+    };
+    let images: Vec<Image> = sqlx::query_as(&format!(
+        "SELECT {} FROM image WHERE {} ORDER BY id",
+        IMAGE_COLUMNS,
+        filter("id")
+    ))
+    .fetch_all(pool)
+    .await?;
+    let mut tags = fetch_names(
+        pool,
+        &format!(
+            "SELECT image_tag.image_id, tag.name FROM image_tag
+            JOIN tag ON tag.id = image_tag.tag_id WHERE {} ORDER BY tag.name",
+            filter("image_tag.image_id")
+        ),
+    )
+    .await?;
+    let mut albums = fetch_names(
+        pool,
+        &format!(
+            "SELECT album_image.image_id, album.name FROM album_image
+            JOIN album ON album.id = album_image.album_id WHERE {}
+            ORDER BY album.name, album.id",
+            filter("album_image.image_id")
+        ),
+    )
+    .await?;
+
+    Ok(images
+        .into_iter()
+        .map(|image| ImageRecord {
+            tags: tags.remove(&image.id).unwrap_or_default(),
+            albums: albums.remove(&image.id).unwrap_or_default(),
+            id: image.id,
+            file_path: image.file_path,
+            file_hash: image.file_hash,
+            prompt: image.prompt,
+            negative_prompt: image.negative_prompt,
+            steps: image.steps,
+            sampler: image.sampler,
+            cfg_scale: image.cfg_scale,
+            seed: image.seed,
+            width: image.width,
+            height: image.height,
+            model_hash: image.model_hash,
+            model: image.model,
+            clip_skip: image.clip_skip,
+            extra_params: image.extra_params.0,
+            rating: image.rating,
+            favorite: image.favorite,
+            score: image.score,
+            comparisons: image.comparisons,
+            note: image.note,
+            inbox: image.inbox,
+            created_at: image.created_at,
+            deleted_at: image.deleted_at,
+        })
+        .collect())
+}
+
+/// Names by image id from `query` selecting image id and name
+async fn fetch_names(
+    executor: impl Executor<'_, Database = Sqlite>,
+    query: &str,
+) -> sqlx::Result<HashMap<i64, Vec<String>>> {
+    let rows: Vec<(i64, String)> = sqlx::query_as(query).fetch_all(executor).await?;
+    let mut names: HashMap<i64, Vec<String>> = HashMap::new();
+    for (image_id, name) in rows {
+        names.entry(image_id).or_default().push(name);
+    }
+    Ok(names)
+}
+
+pub fn write_records(
+    format: DumpFormat,
+    records: Vec<ImageRecord>,
+    mut writer: impl Write,
+) -> anyhow::Result<()> {
+    match format {
+        DumpFormat::Jsonl => {
+            for record in records {
+                serde_json::to_writer(&mut writer, &record)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        DumpFormat::Csv => {
+            let mut csv = csv::Writer::from_writer(writer);
+            for record in records {
+                csv.serialize(CsvRecord::new(record)?)?;
+            }
+            csv.flush()?;
+        }
+    }
+    Ok(())
+}
+
+pub fn read_records(format: DumpFormat, reader: impl BufRead) -> anyhow::Result<Vec<ImageRecord>> {
+    match format {
+        DumpFormat::Jsonl => reader
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|(index, line)| {
+                serde_json::from_str(&line?).with_context(|| format!("Invalid line {}", index + 1))
+            })
+            .collect(),
+        DumpFormat::Csv => csv::Reader::from_reader(reader)
+            .deserialize::<CsvRecord>()
+            .enumerate()
+            .map(|(index, row)| {
+                row.map_err(anyhow::Error::from)
+                    .and_then(|row| Ok(row.into_record()?))
+                    .with_context(|| format!("Invalid row {}", index + 1))
+            })
+            .collect(),
+    }
+}
+
+/// Curation field of a matched image which had another value before loading
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    pub image_id: i64,
+    pub field: &'static str,
+    pub local: String,
+    pub loaded: String,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct LoadReport {
+    /// Records without matching image, inserted as new images with missing files
+    pub inserted: usize,
+    /// Matched images which got new values
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Local values replaced by loaded ones
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Load dumped `records` in one transaction. Records are matched to images by file hash,
+/// then by file path; unmatched records are inserted and marked as missing their files,
+/// which are not in this library. Loaded rating, favorite flag, note
+/// and ranking score replace local values unless they are defaults, so loading never
+/// erases curation, and replaced non-default values are reported as conflicts. Tags and
+/// albums are added to the local ones, albums are found by name or created. Loading
+/// the same dump again changes nothing
+pub async fn load_records(
+    pool: &Pool<Sqlite>,
+    records: Vec<ImageRecord>,
+) -> anyhow::Result<LoadReport> {
+    let mut report = LoadReport::default();
+    let mut transaction = pool.begin().await?;
+    for record in records {
+        let record_id = record.id;
+        load_record(&mut transaction, record, &mut report)
+            .await
+            .with_context(|| format!("Failed to load image {}", record_id))?;
+    }
+    transaction.commit().await?;
+    Ok(report)
+}
+
+async fn load_record(
+    transaction: &mut Transaction<'_, Sqlite>,
+    record: ImageRecord,
+    report: &mut LoadReport,
+) -> anyhow::Result<()> {
+    let image = match find_image(&mut *transaction, &record).await? {
+        Some(image) => image,
+        None => {
+            let mut image = Image {
+                file_missing: true,
+                ..record.to_image()
+            };
+            insert_image(&mut *transaction, &mut image).await?;
+            set_ranking_and_dates(&mut *transaction, image.id, &record).await?;
+            add_tags(transaction, &[image.id], &normalized_tags(&record)?).await?;
+            for album in &record.albums {
+                let album_id = get_or_create_album(&mut *transaction, album, "").await?;
+                add_album_images(transaction, album_id, &[image.id]).await?;
+            }
+            report.inserted += 1;
+            return Ok(());
+        }
+    };
+
+    let mut changed = merge_curation(transaction, &image, &record, &mut report.conflicts).await?;
+    for album in &record.albums {
+        let album_id = get_or_create_album(&mut *transaction, album, "").await?;
+        if add_album_images(transaction, album_id, &[image.id]).await? > 0 {
+            changed = true;
+        }
+    }
+
+    match changed {
+        true => report.updated += 1,
+        false => report.unchanged += 1,
+    }
+    Ok(())
+}
+
+/// Apply curation of `record` to matching local `image`, see [`load_records`].
+/// Albums are not merged. Returns whether the image is changed
+pub async fn merge_curation(
+    transaction: &mut Transaction<'_, Sqlite>,
+    image: &Image,
+    record: &ImageRecord,
+    conflicts: &mut Vec<Conflict>,
+) -> anyhow::Result<bool> {
+    let tags = normalized_tags(record)?;
+    let mut changed = false;
+    let mut conflict = |field, local: String, loaded: String, is_default: bool| {
+        if !is_default {
+            conflicts.push(Conflict {
+                image_id: image.id,
+                field,
+                local,
+                loaded,
+            });
+        }
+    };
+    if record.rating != 0 && record.rating != image.rating {
+        conflict(
+            "rating",
+            image.rating.to_string(),
+            record.rating.to_string(),
+            image.rating == 0,
+        );
+        set_image_rating(&mut *transaction, image.id, record.rating).await?;
+        changed = true;
+    }
+    if record.favorite && !image.favorite {
+        set_image_favorite(&mut *transaction, image.id, true).await?;
+        changed = true;
+    }
+    if !record.note.is_empty() && record.note != image.note {
+        conflict(
+            "note",
+            image.note.clone(),
+            record.note.clone(),
+            image.note.is_empty(),
+        );
+        set_image_note(&mut *transaction, image.id, &record.note).await?;
+        changed = true;
+    }
+    if record.comparisons > 0
+        && (record.score, record.comparisons) != (image.score, image.comparisons)
+    {
+        conflict(
+            "score",
+            format!("{} ({} comparisons)", image.score, image.comparisons),
+            format!("{} ({} comparisons)", record.score, record.comparisons),
+            image.comparisons == 0,
+        );
+        sqlx::query!(
+            "UPDATE image SET score = ?, comparisons = ? WHERE id = ?",
+            record.score,
+            record.comparisons,
+            image.id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        changed = true;
+    }
+    // Images triaged in the other library leave inbox, but never return to it
+    if !record.inbox && image.inbox {
+        sqlx::query!("UPDATE image SET inbox = FALSE WHERE id = ?", image.id)
+            .execute(&mut *transaction)
+            .await?;
+        changed = true;
+    }
+    if add_tags(transaction, &[image.id], &tags).await? > 0 {
+        changed = true;
+    }
+    Ok(changed)
+}
+
+pub fn normalized_tags(record: &ImageRecord) -> Result<Vec<String>, TagError> {
+    record
+        .tags
+        .iter()
+        .map(|name| normalize_name(name))
+        .collect()
+}
+
+/// Image with the same file, images not in trash are preferred
+async fn find_image(
+    transaction: &mut Transaction<'_, Sqlite>,
+    record: &ImageRecord,
+) -> sqlx::Result<Option<Image>> {
+    for (column, value) in [
+        ("file_hash", &record.file_hash),
+        ("file_path", &record.file_path),
+    ] {
+        let Some(value) = value else {
+            continue;
+        };
+        let image = sqlx::query_as(&format!(
+            "SELECT {} FROM image WHERE {} = ? ORDER BY deleted_at IS NOT NULL, id LIMIT 1",
+            IMAGE_COLUMNS, column
+        ))
+        .bind(value)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if image.is_some() {
+            return Ok(image);
+        }
+    }
+    Ok(None)
+}
+
+impl ImageRecord {
+    /// Image to insert, score and dates are kept by [`set_ranking_and_dates`] after insert
+    pub fn to_image(&self) -> Image {
+        Image {
+            id: 0,
+            prompt: self.prompt.clone(),
+            negative_prompt: self.negative_prompt.clone(),
+            steps: self.steps,
+            sampler: self.sampler.clone(),
+            cfg_scale: self.cfg_scale,
+            seed: self.seed,
+            width: self.width,
+            height: self.height,
+            model_hash: self.model_hash.clone(),
+            model: self.model.clone(),
+            clip_skip: self.clip_skip,
+            extra_params: Json(self.extra_params.clone()),
+            file_path: self.file_path.clone(),
+            file_hash: self.file_hash.clone(),
+            file_missing: false,
+            rating: self.rating,
+            favorite: self.favorite,
+            score: self.score,
+            comparisons: self.comparisons,
+            note: self.note.clone(),
+            inbox: self.inbox,
+            created_at: self.created_at,
+            deleted_at: self.deleted_at,
+        }
+    }
+}
+
+/// Copy ranking score and dates of `record` to inserted image, insert sets defaults for them
+pub async fn set_ranking_and_dates(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    record: &ImageRecord,
+) -> sqlx::Result<()> {
+    // Dates are stored as unix timestamps, as `unixepoch()` writes them
+    let created_at = record.created_at.timestamp();
+    let deleted_at = record.deleted_at.map(|deleted_at| deleted_at.timestamp());
+    sqlx::query!(
+        "UPDATE image SET score = ?, comparisons = ?, created_at = ?, deleted_at = ? WHERE id = ?",
+        record.score,
+        record.comparisons,
+        created_at,
+        deleted_at,
+        image_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// The oldest album named `name`, created with `description` if there is none
+pub async fn get_or_create_album(
+    transaction: &mut Transaction<'_, Sqlite>,
+    name: &str,
+    description: &str,
+) -> sqlx::Result<i64> {
+    let album_id = sqlx::query_scalar!(
+        r#"SELECT id as "id!" FROM album WHERE name = ? ORDER BY id LIMIT 1"#,
+        name
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    match album_id {
+        Some(album_id) => Ok(album_id),
+        None => {
+            sqlx::query_scalar!(
+                r#"INSERT INTO album (name, description) VALUES (?, ?) RETURNING id as "id!""#,
+                name,
+                description
+            )
+            .fetch_one(&mut *transaction)
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{
+        fetch_records, load_records, read_records, write_records, Conflict, DumpFormat, LoadReport,
+    };
+    use crate::{
+        albums::{add_album_images, create_album},
+        models::{
+            fetch_image_by_id, insert_test_image, new_test_image, new_test_pool, set_image_rating,
+            Image,
+        },
+        tags::add_tags,
+    };
+
+    #[actix_web::test]
+    async fn test_dump_and_load() {
+        let source = new_test_pool().await;
+        let a = insert_test_image(
+            &source,
+            Image {
+                file_path: Some("images/a.png".to_string()),
+                file_hash: Some("a".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        insert_test_image(
+            &source,
+            Image {
+                file_path: Some("images/b.png".to_string()),
+                file_hash: Some("b".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        set_image_rating(&source, a, 4).await.unwrap();
+        let album_id = create_album(&source, "Keepers, best", "").await.unwrap();
+        let mut transaction = source.begin().await.unwrap();
+        add_tags(&mut transaction, &[a], &["cat".to_string()])
+            .await
+            .unwrap();
+        add_album_images(&mut transaction, album_id, &[a])
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        let records = fetch_records(&source).await.unwrap();
+        assert_eq!(records[0].tags, vec!["cat"]);
+        assert_eq!(records[0].albums, vec!["Keepers, best"]);
+        for format in [DumpFormat::Jsonl, DumpFormat::Csv] {
+            let mut dump = Vec::new();
+            write_records(format, records.clone(), &mut dump).unwrap();
+            assert_eq!(read_records(format, dump.as_slice()).unwrap(), records);
+        }
+
+        // Target has `b` under another path, rated differently, and doesn't have `a`
+        let target = new_test_pool().await;
+        insert_test_image(
+            &target,
+            Image {
+                file_path: Some("images/c.png".to_string()),
+                file_hash: Some("c".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let b = insert_test_image(
+            &target,
+            Image {
+                file_path: Some("images/b.png".to_string()),
+                file_hash: Some("b".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        sqlx::query("UPDATE image SET file_path = 'images/moved.png' WHERE id = ?")
+            .bind(b)
+            .execute(&target)
+            .await
+            .unwrap();
+        let mut records = records;
+        records[1].rating = 2;
+        set_image_rating(&target, b, 5).await.unwrap();
+
+        let report = load_records(&target, records.clone()).await.unwrap();
+        assert_eq!(
+            report,
+            LoadReport {
+                inserted: 1,
+                updated: 1,
+                unchanged: 0,
+                conflicts: vec![Conflict {
+                    image_id: b,
+                    field: "rating",
+                    local: "5".to_string(),
+                    loaded: "2".to_string(),
+                }],
+            }
+        );
+        let loaded = fetch_records(&target).await.unwrap();
+        let loaded_a = loaded.iter().find(|r| r.file_hash.as_deref() == Some("a"));
+        let loaded_a = loaded_a.unwrap();
+        assert_eq!(
+            (loaded_a.rating, &loaded_a.tags, &loaded_a.albums),
+            (4, &records[0].tags, &records[0].albums)
+        );
+        // Only the dump is loaded, not the file
+        let image = fetch_image_by_id(&target, loaded_a.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(image.file_missing);
+        let image = fetch_image_by_id(&target, b).await.unwrap().unwrap();
+        assert!(!image.file_missing);
+
+        // Decoding accepts text dates too, they must be stored as integers to sort and purge
+        let text_dates: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM image
+            WHERE typeof(created_at) != 'integer' OR typeof(deleted_at) = 'text'",
+        )
+        .fetch_one(&target)
+        .await
+        .unwrap();
+        assert_eq!(text_dates, 0);
+
+        // Loading again changes nothing
+        let report = load_records(&target, records).await.unwrap();
+        assert_eq!((report.inserted, report.updated), (0, 0));
+        assert_eq!(report.unchanged, 2);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(fetch_records(&target).await.unwrap(), loaded);
+    }
+}
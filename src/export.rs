@@ -0,0 +1,417 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    str::FromStr,
+};
+
+use async_zip::{base::write::ZipFileWriter, Compression, ZipDateTime, ZipEntryBuilder};
+use chrono::{TimeZone, Utc};
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use sqlx::{Pool, QueryBuilder, Row, Sqlite};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::compat::FuturesAsyncWriteCompatExt;
+
+use crate::{
+    models::{add_filter_to_query, fetch_images_by_ids, Image, IMAGE_COLUMNS},
+    storage::MediaStore,
+    utils::search::{SearchQuery, SortOrder},
+};
+
+pub const DEFAULT_NAME_TEMPLATE: &str = "{id}_{seed}_{model}.png";
+/// Fields which may be used in file name templates
+const NAME_FIELDS: [&str; 11] = [
+    "id",
+    "seed",
+    "model",
+    "model_hash",
+    "sampler",
+    "steps",
+    "cfg_scale",
+    "width",
+    "height",
+    "rating",
+    "file_hash",
+];
+const METADATA_JSONL: &str = "metadata.jsonl";
+const METADATA_CSV: &str = "metadata.csv";
+/// Images are fetched from database by this many
+const CHUNK_SIZE: usize = 500;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum NameTemplateError {
+    #[error("File name template is empty")]
+    Empty,
+    #[error("Unclosed `{{` in file name template")]
+    Unclosed,
+    #[error("Unknown field `{{{0}}}` in file name template")]
+    UnknownField(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum NamePart {
+    Text(String),
+    Field(String),
+}
+
+/// File names of exported images, `{field}` placeholders are replaced by image fields,
+/// e.g. `{id}_{seed}_{model}.png`
+#[derive(Debug, PartialEq)]
+pub struct NameTemplate(Vec<NamePart>);
+
+impl FromStr for NameTemplate {
+    type Err = NameTemplateError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        if template.trim().is_empty() {
+            return Err(NameTemplateError::Empty);
+        }
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(NamePart::Text(rest[..start].to_owned()));
+            }
+            let end = rest[start..].find('}').ok_or(NameTemplateError::Unclosed)?;
+            let field = &rest[start + 1..start + end];
+            if !NAME_FIELDS.contains(&field) {
+                return Err(NameTemplateError::UnknownField(field.to_owned()));
+            }
+            parts.push(NamePart::Field(field.to_owned()));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(NamePart::Text(rest.to_owned()));
+        }
+        Ok(NameTemplate(parts))
+    }
+}
+
+impl NameTemplate {
+    /// File name of `image`, characters which are not allowed in file names are replaced
+    pub fn render(&self, image: &Image) -> String {
+        let name: String = self
+            .0
+            .iter()
+            .map(|part| match part {
+                NamePart::Text(text) => text.clone(),
+                NamePart::Field(field) => field_value(image, field),
+            })
+            .collect::<String>()
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+                c if c.is_control() => '_',
+                c => c,
+            })
+            .collect();
+        match name.trim_matches('.') {
+            "" => format!("{}.png", image.id),
+            _ => name,
+        }
+    }
+}
+
+fn field_value(image: &Image, field: &str) -> String {
+    match field {
+        "id" => image.id.to_string(),
+        "seed" => image.seed.to_string(),
+        "model" => image.model.clone(),
+        "model_hash" => image.model_hash.clone(),
+        "sampler" => image.sampler.clone(),
+        "steps" => image.steps.to_string(),
+        "cfg_scale" => image.cfg_scale.to_string(),
+        "width" => image.width.to_string(),
+        "height" => image.height.to_string(),
+        "rating" => image.rating.to_string(),
+        "file_hash" => image.file_hash.clone().unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// `name`, or `name` with a number before extension if it's already used
+fn unique_name(name: String, used: &mut HashSet<String>) -> String {
+    let mut unique = name.clone();
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name.as_str(), ""),
+    };
+    let mut number = 1;
+    while used.contains(&unique) {
+        number += 1;
+        unique = format!("{}_{}{}", stem, number, extension);
+    }
+    used.insert(unique.clone());
+    unique
+}
+
+/// What to export
+pub struct Export {
+    pub search: SearchQuery,
+    pub sort: SortOrder,
+    pub name_template: NameTemplate,
+    /// Add `metadata.csv` besides `metadata.jsonl`
+    pub csv: bool,
+}
+
+/// Line of `metadata.jsonl`
+#[derive(Serialize)]
+struct MetadataRecord<'a> {
+    /// Name of the image file in the archive, `None` if the file is missing
+    file_name: Option<&'a str>,
+    #[serde(flatten)]
+    image: &'a Image,
+}
+
+/// Write ZIP archive of images found by `export.search` with their metadata to `writer`.
+/// The archive is written while files are read from `store`, nothing is buffered on disk.
+/// Images are followed by `metadata.jsonl` with all image fields and optional `metadata.csv`
+/// with extra parameters in own columns
+pub async fn write_export(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    export: &Export,
+    writer: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let ids = fetch_sorted_image_ids(pool, &export.search, export.sort).await?;
+
+    let mut used_names = HashSet::from([METADATA_JSONL.to_owned(), METADATA_CSV.to_owned()]);
+    let mut file_names = HashMap::new();
+    let mut extra_keys = BTreeSet::new();
+    for chunk in ids.chunks(CHUNK_SIZE) {
+        for image in fetch_images_by_ids(pool, chunk).await? {
+            extra_keys.extend(image.extra_params.0.keys().cloned());
+            let file_path = match &image.file_path {
+                Some(file_path) if !image.file_missing => file_path,
+                _ => continue,
+            };
+            let mut stream = match store.get(file_path).await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    log::warn!("Image {} is exported without file: {}", image.id, error);
+                    continue;
+                }
+            };
+            let name = unique_name(export.name_template.render(&image), &mut used_names);
+            let entry = ZipEntryBuilder::new(name.clone().into(), Compression::Stored)
+                .last_modification_date(ZipDateTime::from(
+                    Utc.from_utc_datetime(&image.created_at),
+                ));
+            let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
+            while let Some(chunk) = stream.try_next().await? {
+                entry_writer.write_all(&chunk).await?;
+            }
+            entry_writer.into_inner().close().await?;
+            file_names.insert(image.id, name);
+        }
+    }
+
+    let entry = ZipEntryBuilder::new(METADATA_JSONL.into(), Compression::Deflate)
+        .last_modification_date(ZipDateTime::from(Utc::now()));
+    let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
+    for chunk in ids.chunks(CHUNK_SIZE) {
+        for image in fetch_images_by_ids(pool, chunk).await? {
+            let record = MetadataRecord {
+                file_name: file_names.get(&image.id).map(String::as_str),
+                image: &image,
+            };
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            entry_writer.write_all(&line).await?;
+        }
+    }
+    entry_writer.into_inner().close().await?;
+
+    if export.csv {
+        let entry = ZipEntryBuilder::new(METADATA_CSV.into(), Compression::Deflate)
+            .last_modification_date(ZipDateTime::from(Utc::now()));
+        let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
+        let columns: Vec<&str> = IMAGE_COLUMNS
+            .split(',')
+            .map(str::trim)
+            .filter(|column| *column != "extra_params")
+            .collect();
+        let mut header = csv::Writer::from_writer(Vec::new());
+        header.write_field("file_name")?;
+        header.write_record(
+            columns
+                .iter()
+                .copied()
+                .chain(extra_keys.iter().map(String::as_str)),
+        )?;
+        entry_writer.write_all(&header.into_inner()?).await?;
+        for chunk in ids.chunks(CHUNK_SIZE) {
+            let mut rows = csv::Writer::from_writer(Vec::new());
+            for image in fetch_images_by_ids(pool, chunk).await? {
+                let fields = serde_json::to_value(&image)?;
+                rows.write_field(file_names.get(&image.id).map_or("", String::as_str))?;
+                for column in &columns {
+                    rows.write_field(match &fields[column] {
+                        serde_json::Value::String(value) => value.clone(),
+                        serde_json::Value::Null => String::new(),
+                        value => value.to_string(),
+                    })?;
+                }
+                for key in &extra_keys {
+                    rows.write_field(image.extra_params.0.get(key).map_or("", String::as_str))?;
+                }
+                rows.write_record(None::<&[u8]>)?;
+            }
+            entry_writer.write_all(&rows.into_inner()?).await?;
+        }
+        entry_writer.into_inner().close().await?;
+    }
+
+    zip.close().await?;
+    Ok(())
+}
+
+/// Ids of found images in `sort` order
+async fn fetch_sorted_image_ids(
+    pool: &Pool<Sqlite>,
+    search: &SearchQuery,
+    sort: SortOrder,
+) -> sqlx::Result<Vec<i64>> {
+    let mut query = QueryBuilder::new("SELECT id FROM image");
+    add_filter_to_query(&mut query, search);
+    query.push(sort.order_by());
+    query
+        .build()
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.try_get(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashSet},
+        io::{Cursor, Read},
+    };
+
+    use sqlx::types::Json;
+    use tempfile::{NamedTempFile, TempDir};
+    use zip::ZipArchive;
+
+    use super::{
+        unique_name, write_export, Export, NameTemplate, NameTemplateError, DEFAULT_NAME_TEMPLATE,
+    };
+    use crate::{
+        models::{insert_test_image, new_test_image, new_test_pool, Image, IMAGE_COLUMNS},
+        storage::{LocalStore, MediaStore},
+        utils::search::{SearchQuery, SortOrder},
+    };
+
+    #[test]
+    fn test_name_template() {
+        let image = Image {
+            id: 7,
+            seed: 42,
+            sampler: "Euler a".to_string(),
+            model: "sd/v1.5".to_string(),
+            ..new_test_image()
+        };
+        let template: NameTemplate = "{id}_{seed}_{model}.png".parse().unwrap();
+        assert_eq!(template.render(&image), "7_42_sd_v1.5.png");
+        let template: NameTemplate = "../{sampler}".parse().unwrap();
+        assert_eq!(template.render(&image), ".._Euler a");
+        assert_eq!(
+            "{prompt}.png".parse::<NameTemplate>(),
+            Err(NameTemplateError::UnknownField("prompt".to_string()))
+        );
+        assert_eq!(
+            "{id.png".parse::<NameTemplate>(),
+            Err(NameTemplateError::Unclosed)
+        );
+
+        let mut used = HashSet::new();
+        assert_eq!(unique_name("a.png".to_string(), &mut used), "a.png");
+        assert_eq!(unique_name("a.png".to_string(), &mut used), "a_2.png");
+        assert_eq!(unique_name("a.png".to_string(), &mut used), "a_3.png");
+    }
+
+    #[actix_web::test]
+    async fn test_write_export() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+        let mut source = NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut source, b"cat").unwrap();
+        store.put("images/cat.png", source.path()).await.unwrap();
+        for (seed, file_path, extra_params) in [
+            (
+                1,
+                "images/cat.png",
+                BTreeMap::from([("ENSD".to_string(), "31337".to_string())]),
+            ),
+            (2, "images/gone.png", BTreeMap::new()),
+        ] {
+            insert_test_image(
+                &pool,
+                Image {
+                    seed,
+                    file_path: Some(file_path.to_string()),
+                    extra_params: Json(extra_params),
+                    ..new_test_image()
+                },
+            )
+            .await;
+        }
+
+        let export = Export {
+            search: SearchQuery::default(),
+            sort: SortOrder::Oldest,
+            name_template: DEFAULT_NAME_TEMPLATE.parse().unwrap(),
+            csv: true,
+        };
+        let mut buffer = Vec::new();
+        write_export(&pool, &store, &export, &mut buffer)
+            .await
+            .unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(buffer)).unwrap();
+        let names: Vec<_> = archive.file_names().collect();
+        assert_eq!(
+            names,
+            vec!["1_1_model.png", "metadata.jsonl", "metadata.csv"]
+        );
+        let read = |archive: &mut ZipArchive<_>, name| {
+            let mut contents = String::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        assert_eq!(read(&mut archive, "1_1_model.png"), "cat");
+
+        // The image without file is in metadata too
+        let lines: Vec<serde_json::Value> = read(&mut archive, "metadata.jsonl")
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["file_name"], "1_1_model.png");
+        assert_eq!(lines[0]["extra_params"]["ENSD"], "31337");
+        assert_eq!(lines[1]["file_name"], serde_json::Value::Null);
+        assert_eq!(lines[1]["seed"], 2);
+
+        let csv = read(&mut archive, "metadata.csv");
+        let mut rows = csv.lines();
+        let columns = IMAGE_COLUMNS
+            .split(',')
+            .map(str::trim)
+            .filter(|column| *column != "extra_params");
+        let header: Vec<_> = std::iter::once("file_name")
+            .chain(columns)
+            .chain(["ENSD"])
+            .collect();
+        assert_eq!(rows.next(), Some(header.join(",").as_str()));
+        assert!(rows.next().unwrap().ends_with(",31337"));
+        assert!(rows.next().unwrap().starts_with(",2,"));
+        assert_eq!(rows.next(), None);
+    }
+}
@@ -0,0 +1,475 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use futures_util::TryStreamExt;
+use sqlx::{Pool, Sqlite};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    models::{
+        fetch_image_files, image_file_path_exists, insert_image, set_image_file_hash,
+        set_image_file_missing, ImageFile,
+    },
+    storage::MediaStore,
+    utils::{
+        hash::{sha256_file, sha256_stream},
+        image::extract_metadata_from_image,
+    },
+};
+
+/// Prefix of media keys of images
+pub const IMAGES_PREFIX: &str = "images/";
+/// Corrupted files are moved under this prefix keeping their original key
+pub const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// What fsck should fix besides reporting problems
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RepairOptions {
+    /// Create images for orphan files using metadata of the files
+    pub import_orphans: bool,
+    /// Mark images whose files are missing (see [`crate::models::Image::file_missing`])
+    pub mark_missing: bool,
+    /// Move files with mismatching hash to quarantine and mark their images as missing
+    pub quarantine_corrupt: bool,
+    /// Record hashes of images imported before hashing was introduced
+    pub record_hashes: bool,
+}
+
+#[derive(Debug)]
+pub struct HashMismatch {
+    pub image_id: i64,
+    pub file_path: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub checked_images: usize,
+    pub checked_files: usize,
+    /// Images referencing files which don't exist
+    pub missing_files: Vec<ImageFile>,
+    /// Files not referenced by any image
+    pub orphan_files: Vec<String>,
+    /// Files with content different from the one recorded on import
+    pub hash_mismatches: Vec<HashMismatch>,
+    /// Images whose files failed to read, with errors
+    pub unreadable_files: Vec<(ImageFile, String)>,
+    /// Images imported before hashing was introduced, without recorded hash
+    pub unhashed_images: usize,
+
+    pub hashed_images: usize,
+
+    pub imported_orphans: Vec<(String, i64)>,
+    pub failed_imports: Vec<(String, String)>,
+    pub marked_missing: usize,
+    pub quarantined: usize,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_files.is_empty()
+            && self.orphan_files.is_empty()
+            && self.hash_mismatches.is_empty()
+            && self.unreadable_files.is_empty()
+    }
+}
+
+/// Check consistency of `image` table and media storage, repairing problems according to `options`
+pub async fn fsck(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    options: RepairOptions,
+) -> anyhow::Result<FsckReport> {
+    let mut report = FsckReport::default();
+
+    let images = fetch_image_files(pool).await?;
+    let files: HashSet<String> = store.list(IMAGES_PREFIX).await?.into_iter().collect();
+    report.checked_images = images.len();
+    report.checked_files = files.len();
+
+    let referenced: HashSet<&str> = images.iter().map(|i| i.file_path.as_str()).collect();
+    let mut orphan_files: Vec<String> = files
+        .iter()
+        .filter(|file| !referenced.contains(file.as_str()))
+        .cloned()
+        .collect();
+    orphan_files.sort();
+    report.orphan_files = orphan_files;
+
+    for image in images {
+        if !files.contains(&image.file_path) {
+            // The file may have been stored after listing
+            if store.exists(&image.file_path).await? {
+                continue;
+            }
+            if options.mark_missing && !image.file_missing {
+                set_image_file_missing(pool, image.id, true).await?;
+                report.marked_missing += 1;
+            }
+            report.missing_files.push(image);
+            continue;
+        }
+
+        let actual = match store.get(&image.file_path).await {
+            Ok(stream) => sha256_stream(stream).await,
+            Err(error) => Err(error),
+        };
+        let actual = match actual {
+            Ok(actual) => actual,
+            Err(error) => {
+                report.unreadable_files.push((image, error.to_string()));
+                continue;
+            }
+        };
+        match &image.file_hash {
+            None => {
+                report.unhashed_images += 1;
+                if options.record_hashes {
+                    set_image_file_hash(pool, image.id, &actual).await?;
+                    report.hashed_images += 1;
+                }
+            }
+            Some(expected) if *expected != actual => {
+                if options.quarantine_corrupt {
+                    let quarantine_path = format!("{}{}", QUARANTINE_PREFIX, image.file_path);
+                    store.rename(&image.file_path, &quarantine_path).await?;
+                    set_image_file_missing(pool, image.id, true).await?;
+                    report.quarantined += 1;
+                }
+                report.hash_mismatches.push(HashMismatch {
+                    image_id: image.id,
+                    file_path: image.file_path,
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+            Some(_) => {
+                // The file is back (e.g. restored from backup)
+                if options.mark_missing && image.file_missing {
+                    set_image_file_missing(pool, image.id, false).await?;
+                }
+            }
+        }
+    }
+
+    if options.import_orphans {
+        for file_path in &report.orphan_files {
+            // The image may have been imported after fetching images
+            if image_file_path_exists(pool, file_path).await? {
+                continue;
+            }
+            match import_orphan(pool, store, file_path).await {
+                Ok(image_id) => report.imported_orphans.push((file_path.clone(), image_id)),
+                Err(error) => report
+                    .failed_imports
+                    .push((file_path.clone(), format!("{:#}", error))),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Create image referencing already stored `file_path` using metadata of the file
+async fn import_orphan(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    file_path: &str,
+) -> anyhow::Result<i64> {
+    let local_file = NamedTempFile::new()?;
+    let mut writer = tokio::fs::File::create(local_file.path()).await?;
+    let mut stream = store.get(file_path).await?;
+    while let Some(chunk) = stream.try_next().await? {
+        writer.write_all(&chunk).await?;
+    }
+    writer.flush().await?;
+
+    let path = local_file
+        .path()
+        .to_str()
+        .context("Invalid temporary path")?;
+    let mut image = extract_metadata_from_image(path)?;
+    image.file_path = Some(file_path.to_owned());
+    image.file_hash = Some(sha256_file(local_file.path()).await?);
+    insert_image(pool, &mut image).await?;
+
+    Ok(image.id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, io::Write, path::Path};
+
+    use futures_util::future::BoxFuture;
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::{fsck, RepairOptions};
+    use crate::{
+        models::{fetch_image_by_id, insert_test_image, new_test_image, new_test_pool, Image},
+        storage::{ByteStream, LocalStore, MediaStore},
+    };
+
+    /// Store running `after_list` right after listing files, to simulate concurrent changes
+    struct RacingStore {
+        inner: LocalStore,
+        after_list: Box<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>,
+    }
+
+    #[async_trait::async_trait]
+    impl MediaStore for RacingStore {
+        async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+            self.inner.put(key, source).await
+        }
+
+        async fn get(&self, key: &str) -> io::Result<ByteStream> {
+            self.inner.get(key).await
+        }
+
+        async fn delete(&self, key: &str) -> io::Result<()> {
+            self.inner.delete(key).await
+        }
+
+        async fn exists(&self, key: &str) -> io::Result<bool> {
+            self.inner.exists(key).await
+        }
+
+        async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+            let keys = self.inner.list(prefix).await?;
+            (self.after_list)().await;
+            Ok(keys)
+        }
+
+        async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.inner.rename(from, to).await
+        }
+
+        fn presign(&self, key: &str) -> Option<String> {
+            self.inner.presign(key)
+        }
+    }
+
+    async fn put(store: &LocalStore, key: &str, content: &[u8]) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content).unwrap();
+        store.put(key, file.path()).await.unwrap();
+    }
+
+    // SHA-256 of "abc"
+    const ABC_HASH: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[actix_web::test]
+    async fn test_report_and_repair() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+
+        put(&store, "images/ok.png", b"abc").await;
+        insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/ok.png".to_string()),
+                file_hash: Some(ABC_HASH.to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        put(&store, "images/unhashed.png", b"abc").await;
+        let unhashed_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/unhashed.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        put(&store, "images/corrupt.png", b"abd").await;
+        let corrupt_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/corrupt.png".to_string()),
+                file_hash: Some(ABC_HASH.to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let missing_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/missing.png".to_string()),
+                file_hash: Some(ABC_HASH.to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        put(&store, "images/orphan.png", b"abc").await;
+
+        let report = fsck(&pool, &store, RepairOptions::default()).await.unwrap();
+        assert_eq!(report.checked_images, 4);
+        assert_eq!(report.checked_files, 4);
+        assert_eq!(report.missing_files.len(), 1);
+        assert_eq!(report.missing_files[0].id, missing_id);
+        assert_eq!(report.orphan_files, vec!["images/orphan.png"]);
+        assert_eq!(report.hash_mismatches.len(), 1);
+        assert_eq!(report.hash_mismatches[0].image_id, corrupt_id);
+        assert_eq!((report.unhashed_images, report.hashed_images), (1, 0));
+        // Report only, nothing is repaired
+        let unhashed = fetch_image_by_id(&pool, unhashed_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unhashed.file_hash, None);
+        assert!(
+            !fetch_image_by_id(&pool, missing_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .file_missing
+        );
+
+        let options = RepairOptions {
+            import_orphans: false,
+            mark_missing: true,
+            quarantine_corrupt: true,
+            record_hashes: true,
+        };
+        let report = fsck(&pool, &store, options).await.unwrap();
+        assert_eq!(report.hashed_images, 1);
+        assert_eq!(report.marked_missing, 1);
+        assert_eq!(report.quarantined, 1);
+        let unhashed = fetch_image_by_id(&pool, unhashed_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unhashed.file_hash.as_deref(), Some(ABC_HASH));
+        for image_id in [missing_id, corrupt_id] {
+            let image = fetch_image_by_id(&pool, image_id).await.unwrap().unwrap();
+            assert!(image.file_missing);
+        }
+        assert!(!store.exists("images/corrupt.png").await.unwrap());
+        assert!(store.exists("quarantine/images/corrupt.png").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_import_orphan_without_metadata_fails() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+        put(&store, "images/orphan.png", b"not an image").await;
+
+        let options = RepairOptions {
+            import_orphans: true,
+            ..Default::default()
+        };
+        let report = fsck(&pool, &store, options).await.unwrap();
+        assert!(report.imported_orphans.is_empty());
+        assert_eq!(report.failed_imports.len(), 1);
+        assert_eq!(report.failed_imports[0].0, "images/orphan.png");
+    }
+
+    #[actix_web::test]
+    async fn test_unreadable_file_is_reported() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+        put(&store, "images/ok.png", b"abc").await;
+        let ok_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/ok.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        // Symlink to itself is listed, but fails to open
+        std::os::unix::fs::symlink("loop.png", media_root.path().join("images/loop.png")).unwrap();
+        let loop_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/loop.png".to_string()),
+                file_hash: Some(ABC_HASH.to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+
+        let report = fsck(&pool, &store, RepairOptions::default()).await.unwrap();
+        assert_eq!(report.unreadable_files.len(), 1);
+        assert_eq!(report.unreadable_files[0].0.id, loop_id);
+        assert!(!report.is_clean());
+        // Other files are still checked
+        assert_eq!(report.unhashed_images, 1);
+        let ok = fetch_image_by_id(&pool, ok_id).await.unwrap().unwrap();
+        assert_eq!(ok.file_hash, None);
+    }
+
+    #[actix_web::test]
+    async fn test_file_stored_after_listing_is_not_marked_missing() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let path = media_root.path().to_owned();
+        let store = RacingStore {
+            inner: LocalStore::new(media_root.path()),
+            after_list: Box::new(move || {
+                let path = path.clone();
+                Box::pin(
+                    async move { put(&LocalStore::new(&path), "images/new.png", b"abc").await },
+                )
+            }),
+        };
+        let image_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/new.png".to_string()),
+                file_hash: Some(ABC_HASH.to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+
+        let options = RepairOptions {
+            mark_missing: true,
+            ..Default::default()
+        };
+        let report = fsck(&pool, &store, options).await.unwrap();
+        assert!(report.missing_files.is_empty());
+        assert_eq!(report.marked_missing, 0);
+        let image = fetch_image_by_id(&pool, image_id).await.unwrap().unwrap();
+        assert!(!image.file_missing);
+    }
+
+    #[actix_web::test]
+    async fn test_file_imported_after_fetching_is_not_imported_again() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let inserter = pool.clone();
+        let store = RacingStore {
+            inner: LocalStore::new(media_root.path()),
+            after_list: Box::new(move || {
+                let pool = inserter.clone();
+                Box::pin(async move {
+                    let image = Image {
+                        file_path: Some("images/new.png".to_string()),
+                        ..new_test_image()
+                    };
+                    insert_test_image(&pool, image).await;
+                })
+            }),
+        };
+        put(&store.inner, "images/new.png", b"abc").await;
+
+        let options = RepairOptions {
+            import_orphans: true,
+            ..Default::default()
+        };
+        let report = fsck(&pool, &store, options).await.unwrap();
+        assert!(report.imported_orphans.is_empty());
+        assert!(report.failed_imports.is_empty());
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM image")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
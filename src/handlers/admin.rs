@@ -0,0 +1,120 @@
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{Data, Form},
+    HttpRequest, HttpResponse,
+};
+use askama::Template;
+use sqlx::{Pool, Sqlite};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    backup::write_backup,
+    fsck::{fsck, FsckReport, RepairOptions},
+    storage::MediaStore,
+    utils::{
+        csrf::{self, CsrfToken},
+        errors::MapErrToInternal,
+        render::render_html,
+    },
+};
+
+#[derive(Template)]
+#[template(path = "admin/fsck.html")]
+pub struct FsckTemplate {
+    report: Option<FsckReport>,
+    csrf_token: CsrfToken,
+}
+
+pub async fn fsck_get(csrf_token: CsrfToken) -> actix_web::Result<HttpResponse> {
+    render_html(
+        FsckTemplate {
+            report: None,
+            csrf_token,
+        },
+        HttpResponse::Ok(),
+    )
+}
+
+/// Checkboxes are sent only when checked
+#[derive(serde::Deserialize)]
+pub struct FsckForm {
+    import_orphans: Option<String>,
+    mark_missing: Option<String>,
+    quarantine_corrupt: Option<String>,
+    record_hashes: Option<String>,
+    csrf_token: String,
+}
+
+pub async fn fsck_post(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    store: Data<dyn MediaStore>,
+    form: Form<FsckForm>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<HttpResponse> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let options = RepairOptions {
+        import_orphans: form.import_orphans.is_some(),
+        mark_missing: form.mark_missing.is_some(),
+        quarantine_corrupt: form.quarantine_corrupt.is_some(),
+        record_hashes: form.record_hashes.is_some(),
+    };
+    let report = fsck(&pool, store.as_ref(), options)
+        .await
+        .map_err_to_internal()?;
+
+    render_html(
+        FsckTemplate {
+            report: Some(report),
+            csrf_token,
+        },
+        HttpResponse::Ok(),
+    )
+}
+
+/// Bytes of the backup buffered between its writer and the response
+const BACKUP_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Template)]
+#[template(path = "admin/backup.html")]
+pub struct BackupTemplate {
+    csrf_token: CsrfToken,
+}
+
+pub async fn backup_get(csrf_token: CsrfToken) -> actix_web::Result<HttpResponse> {
+    render_html(BackupTemplate { csrf_token }, HttpResponse::Ok())
+}
+
+#[derive(serde::Deserialize)]
+pub struct BackupForm {
+    csrf_token: String,
+}
+
+/// Download full backup, the archive is built while it's sent
+pub async fn backup_post(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    store: Data<dyn MediaStore>,
+    form: Form<BackupForm>,
+) -> actix_web::Result<HttpResponse> {
+    csrf::verify(&req, &form.csrf_token)?;
+
+    let (writer, reader) = tokio::io::duplex(BACKUP_BUFFER_SIZE);
+    actix_web::rt::spawn(async move {
+        if let Err(error) = write_backup(&pool, store.as_ref(), None, writer).await {
+            log::error!("Failed to back up library: {:#}", error);
+        }
+    });
+
+    let file_name = format!(
+        "sdgenbox-backup-{}.tar.gz",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/gzip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        })
+        .streaming(ReaderStream::new(reader)))
+}
@@ -0,0 +1,360 @@
+use actix_web::{
+    http::header::ContentType,
+    web::{self, Data, Form, Redirect},
+    HttpRequest, HttpResponse, Responder,
+};
+use askama::Template;
+use serde::Deserialize;
+use sqlx::{Connection, Pool, Sqlite};
+
+use crate::{
+    albums::{
+        add_album_images, create_album, delete_album, fetch_album, fetch_album_images,
+        fetch_albums, move_album_image, remove_album_images, reorder_album_images, set_album_cover,
+        update_album, Album, AlbumError,
+    },
+    handlers::images::{BulkForm, CsrfForm},
+    models::{Image, Limits},
+    saved_searches::{fetch_saved_searches, SavedSearch},
+    utils::{
+        csrf::{self, CsrfToken},
+        errors::MapErrToInternal,
+        pager,
+        render::{error_redirect, render_html},
+    },
+};
+
+#[derive(Template)]
+#[template(path = "albums/list.html")]
+pub struct AlbumsTemplate<'a> {
+    albums: Vec<Album>,
+    saved_searches: Vec<SavedSearch>,
+    error_message: Option<&'a str>,
+    csrf_token: CsrfToken,
+}
+
+#[derive(Deserialize)]
+pub struct AlbumsQuery {
+    error_message: Option<String>,
+}
+
+pub async fn list_albums(
+    pool: Data<Pool<Sqlite>>,
+    query: web::Query<AlbumsQuery>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<impl Responder> {
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let albums = fetch_albums(&mut connection).await.map_err_to_internal()?;
+    let saved_searches = fetch_saved_searches(&mut connection)
+        .await
+        .map_err_to_internal()?;
+    render_html(
+        AlbumsTemplate {
+            albums,
+            saved_searches,
+            error_message: query.error_message.as_deref(),
+            csrf_token,
+        },
+        HttpResponse::Ok(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct AlbumForm {
+    csrf_token: String,
+    name: String,
+    #[serde(default)]
+    description: String,
+}
+
+pub async fn create(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    form: Form<AlbumForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    match create_album(pool.as_ref(), &form.name, &form.description).await {
+        Ok(album_id) => Ok(Redirect::to(format!("/albums/{}", album_id)).see_other()),
+        Err(error) => error_redirect::<AlbumError>("/albums", error),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "albums/album.html")]
+pub struct AlbumTemplate<'a> {
+    album: Album,
+    images: Vec<Image>,
+    /// Position of the first image on the page
+    first_position: usize,
+    current_page: &'a u32,
+    pager: Vec<Option<u32>>,
+    error_message: Option<&'a str>,
+    csrf_token: CsrfToken,
+}
+
+impl AlbumTemplate<'_> {
+    fn is_cover(&self, image: &Image) -> bool {
+        self.album.cover_image_id == Some(image.id)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AlbumQuery {
+    page: Option<u32>,
+    error_message: Option<String>,
+}
+
+const PAGE_SIZE: u32 = 18;
+
+pub async fn get_album(
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    query: web::Query<AlbumQuery>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<impl Responder> {
+    let (album_id,) = path.into_inner();
+    let page = match query.page {
+        Some(n) if n >= 1 => n,
+        _ => 1,
+    };
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let Some(album) = fetch_album(&mut connection, album_id)
+        .await
+        .map_err_to_internal()?
+    else {
+        return Ok(HttpResponse::NotFound()
+            .content_type(ContentType::html())
+            .body("No album"));
+    };
+    let limits = Limits::from_page(page, PAGE_SIZE);
+    let images = fetch_album_images(&mut connection, album_id, &limits)
+        .await
+        .map_err_to_internal()?;
+
+    let pages = (album.image_count as f32 / PAGE_SIZE as f32).ceil() as usize;
+    let pager = pager::pager(pages as u32, page, 2, 2);
+    render_html(
+        AlbumTemplate {
+            album,
+            images,
+            first_position: limits.offset as usize + 1,
+            current_page: &page,
+            pager,
+            error_message: query.error_message.as_deref(),
+            csrf_token,
+        },
+        HttpResponse::Ok(),
+    )
+}
+
+pub async fn edit(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<AlbumForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (album_id,) = path.into_inner();
+    let album_path = format!("/albums/{}", album_id);
+    match update_album(pool.as_ref(), album_id, &form.name, &form.description).await {
+        Ok(()) => Ok(Redirect::to(album_path).see_other()),
+        Err(error) => error_redirect::<AlbumError>(&album_path, error),
+    }
+}
+
+pub async fn delete(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<CsrfForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (album_id,) = path.into_inner();
+    delete_album(pool.as_ref(), album_id)
+        .await
+        .map_err_to_internal()?;
+    Ok(Redirect::to("/albums").see_other())
+}
+
+#[derive(Deserialize)]
+pub struct CoverForm {
+    csrf_token: String,
+    /// Empty resets the cover to the first image
+    #[serde(default)]
+    image_id: String,
+}
+
+pub async fn set_cover(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<CoverForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (album_id,) = path.into_inner();
+    let image_id = match form.image_id.trim() {
+        "" => None,
+        image_id => Some(
+            image_id
+                .parse()
+                .map_err(actix_web::error::ErrorBadRequest)?,
+        ),
+    };
+    set_album_cover(pool.as_ref(), album_id, image_id)
+        .await
+        .map_err_to_internal()?;
+    Ok(Redirect::to(format!("/albums/{}", album_id)).see_other())
+}
+
+/// Save order of images dragged on album page, sent as repeated `ids` fields
+pub async fn reorder(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<Vec<(String, String)>>,
+) -> actix_web::Result<impl Responder> {
+    let form = BulkForm::new(form);
+    form.verify_csrf(&req)?;
+    let (album_id,) = path.into_inner();
+    let image_ids = form.selected_ids()?;
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    reorder_album_images(&mut transaction, album_id, &image_ids)
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    let page: u32 = form
+        .field("page")
+        .and_then(|page| page.parse().ok())
+        .unwrap_or(1);
+    Ok(Redirect::to(format!("/albums/{}?page={}", album_id, page)).see_other())
+}
+
+#[derive(Deserialize)]
+pub struct MoveImageForm {
+    csrf_token: String,
+    /// New position from 1
+    position: i64,
+}
+
+pub async fn move_image(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64, i64)>,
+    form: Form<MoveImageForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (album_id, image_id) = path.into_inner();
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    let position = move_album_image(&mut transaction, album_id, image_id, form.position)
+        .await
+        .map_err_to_internal()?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("No such image in album"))?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    // Show the page the image moved to
+    let page = (position - 1) / PAGE_SIZE as usize + 1;
+    Ok(Redirect::to(format!("/albums/{}?page={}", album_id, page)).see_other())
+}
+
+#[derive(Deserialize)]
+pub struct PageCsrfForm {
+    csrf_token: String,
+    page: Option<u32>,
+}
+
+pub async fn remove_image(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64, i64)>,
+    form: Form<PageCsrfForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (album_id, image_id) = path.into_inner();
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    remove_album_images(&mut transaction, album_id, &[image_id])
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    let page = form.page.unwrap_or(1);
+    Ok(Redirect::to(format!("/albums/{}?page={}", album_id, page)).see_other())
+}
+
+/// Add selected or all found images from the image list to album `album_id`
+pub async fn add_images(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    form: Form<Vec<(String, String)>>,
+) -> actix_web::Result<impl Responder> {
+    let form = BulkForm::new(form);
+    form.verify_csrf(&req)?;
+    let album_id: i64 = form
+        .field("album_id")
+        .unwrap_or_default()
+        .parse()
+        .map_err(actix_web::error::ErrorBadRequest)?;
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    let image_ids = form.image_ids(&mut transaction).await?;
+    add_album_images(&mut transaction, album_id, &image_ids)
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    form.redirect()
+}
+
+#[derive(Deserialize)]
+pub struct ImageAlbumForm {
+    csrf_token: String,
+    album_id: i64,
+}
+
+/// Add image to the end of album from image page
+pub async fn add_image(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<ImageAlbumForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    add_album_images(&mut transaction, form.album_id, &[image_id])
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    Ok(Redirect::to(format!("/images/{}", image_id)).see_other())
+}
+
+/// Remove image from album from image page
+pub async fn remove_from_album(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<ImageAlbumForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    remove_album_images(&mut transaction, form.album_id, &[image_id])
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    Ok(Redirect::to(format!("/images/{}", image_id)).see_other())
+}
@@ -0,0 +1,855 @@
+use actix_multipart::form::{tempfile::TempFile, MultipartForm, MultipartFormConfig};
+use actix_web::{
+    error::{JsonPayloadError, PathError, QueryPayloadError},
+    http::{Method, StatusCode},
+    web::{self, Data, Json, JsonConfig, PathConfig, QueryConfig, ServiceConfig},
+    Either, HttpRequest, HttpResponse, Resource, ResponseError, Route,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, Pool, Sqlite};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::{
+    config::Config,
+    models::{
+        dedup_images, fetch_image_by_id, fetch_image_ids, fetch_images, fetch_images_count,
+        purge_images, search_finds_all, trash_images, DedupKeep, Image, Limits,
+    },
+    storage::{MediaStore, Staging},
+    sync::{fetch_changes, fetch_file_path_by_hash, ChangeFeed},
+    upload::{Importer, UploadResult},
+    utils::search::{SearchError, SearchQuery, SortOrder},
+};
+
+/// Error of JSON API, rendered as `{"error": {"code": ..., "message": ...}}`
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Internal error")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(error: sqlx::Error) -> Self {
+        ApiError::Internal(error.into())
+    }
+}
+
+impl From<SearchError> for ApiError {
+    fn from(error: SearchError) -> Self {
+        ApiError::BadRequest(error.to_string())
+    }
+}
+
+impl ApiError {
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound(_) => "not_found",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(error) = self {
+            log::error!("API request failed: {:#}", error);
+        }
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: ErrorDetail {
+                code: self.code(),
+                message: self.to_string(),
+            },
+        })
+    }
+}
+
+/// Body of error responses
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetail {
+    /// `bad_request`, `forbidden`, `not_found` or `internal`
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Routes of JSON API relative to `/api/v1`, every route must be in [`ApiDoc`]
+pub fn routes() -> Vec<(&'static str, Method, Route)> {
+    vec![
+        ("/images", Method::GET, web::to(list_images)),
+        ("/images", Method::POST, web::to(upload_images)),
+        ("/images/delete", Method::POST, web::to(delete_images)),
+        ("/images/{id}", Method::GET, web::to(get_image)),
+        ("/images/{id}", Method::DELETE, web::to(delete_image)),
+        ("/dedup", Method::POST, web::to(dedup)),
+        ("/changes", Method::GET, web::to(list_changes)),
+        ("/media/{hash}", Method::GET, web::to(get_media_by_hash)),
+    ]
+}
+
+/// Register [`routes`] with extractors which report errors as JSON
+pub fn configure(config: &mut ServiceConfig) {
+    config
+        .app_data(json_config())
+        .app_data(query_config())
+        .app_data(path_config())
+        .app_data(multipart_config());
+
+    // Routes of the same path must be in one resource, otherwise the first one answers
+    // 405 Method Not Allowed for methods of others
+    let mut resources: Vec<(&str, Resource)> = Vec::new();
+    for (path, method, route) in routes() {
+        let route = route.method(method);
+        match resources.iter().position(|(other, _)| *other == path) {
+            Some(i) => {
+                let (_, resource) = resources.remove(i);
+                resources.insert(i, (path, resource.route(route)));
+            }
+            None => resources.push((path, web::resource(path).route(route))),
+        }
+    }
+    for (_, resource) in resources {
+        config.service(resource);
+    }
+}
+
+/// OpenAPI document of the JSON API, served at `/api/openapi.json`
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "sdgenbox API"),
+    paths(
+        list_images,
+        upload_images,
+        delete_images,
+        get_image,
+        delete_image,
+        dedup,
+        list_changes,
+        get_media_by_hash
+    ),
+    tags(
+        (name = "images", description = "Search, import and delete images"),
+        (name = "sync", description = "Replicate images and their curation to other instances")
+    )
+)]
+pub struct ApiDoc;
+
+/// Malformed JSON body, query or path are reported as [`ApiError::BadRequest`]
+fn json_config() -> JsonConfig {
+    JsonConfig::default()
+        .error_handler(|error: JsonPayloadError, _| ApiError::BadRequest(error.to_string()).into())
+}
+
+fn query_config() -> QueryConfig {
+    QueryConfig::default()
+        .error_handler(|error: QueryPayloadError, _| ApiError::BadRequest(error.to_string()).into())
+}
+
+fn path_config() -> PathConfig {
+    PathConfig::default()
+        .error_handler(|error: PathError, _| ApiError::BadRequest(error.to_string()).into())
+}
+
+fn multipart_config() -> MultipartFormConfig {
+    MultipartFormConfig::default()
+        .error_handler(|error, _| ApiError::BadRequest(error.to_string()).into())
+}
+
+/// Unknown API route
+pub async fn not_found(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    Err(ApiError::NotFound(format!(
+        "No route {} {}",
+        req.method(),
+        req.path()
+    )))
+}
+
+const MAX_PER_PAGE: u32 = 100;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListImagesQuery {
+    /// Search box query, e.g. `model:"any v4*" steps:>=20 -tag:rejected`
+    #[serde(default)]
+    pub search: String,
+    #[serde(default)]
+    pub sort: SortOrder,
+    /// Page number from 1
+    #[serde(default = "default_page")]
+    #[param(minimum = 1)]
+    pub page: u32,
+    /// Images per page, at most 100
+    #[serde(default = "default_per_page")]
+    #[param(minimum = 1, maximum = 100)]
+    pub per_page: u32,
+}
+
+fn default_page() -> u32 {
+    1
+}
+
+fn default_per_page() -> u32 {
+    20
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImagePage {
+    pub images: Vec<Image>,
+    /// Number of all found images
+    pub total: u32,
+    pub page: u32,
+    pub per_page: u32,
+    pub pages: u32,
+}
+
+/// Images found by the search, the same as on the image list page
+#[utoipa::path(
+    get,
+    path = "/api/v1/images",
+    tag = "images",
+    params(ListImagesQuery),
+    responses(
+        (status = 200, body = ImagePage),
+        (status = 400, description = "Invalid search or paging", body = ErrorBody),
+    )
+)]
+pub async fn list_images(
+    pool: Data<Pool<Sqlite>>,
+    query: web::Query<ListImagesQuery>,
+) -> Result<Json<ImagePage>, ApiError> {
+    if query.page == 0 {
+        return Err(ApiError::BadRequest("`page` starts from 1".to_string()));
+    }
+    if !(1..=MAX_PER_PAGE).contains(&query.per_page) {
+        return Err(ApiError::BadRequest(format!(
+            "`per_page` must be from 1 to {}",
+            MAX_PER_PAGE
+        )));
+    }
+    let search: SearchQuery = query.search.parse()?;
+
+    let limits = Limits::checked_from_page(query.page, query.per_page)
+        .ok_or_else(|| ApiError::BadRequest("`page` is too large".to_string()))?;
+
+    let mut connection = pool.acquire().await?;
+    let images = fetch_images(&mut connection, &search, query.sort, &limits).await?;
+    let total = fetch_images_count(&mut connection, &search).await?;
+
+    Ok(Json(ImagePage {
+        images,
+        total,
+        page: query.page,
+        per_page: query.per_page,
+        pages: total.div_ceil(query.per_page),
+    }))
+}
+
+/// Image by id, images in trash have `deleted_at` set
+#[utoipa::path(
+    get,
+    path = "/api/v1/images/{id}",
+    tag = "images",
+    params(("id" = i64, Path, description = "Image id")),
+    responses(
+        (status = 200, body = Image),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn get_image(
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+) -> Result<Json<Image>, ApiError> {
+    let (image_id,) = path.into_inner();
+    match fetch_image_by_id(pool.as_ref(), image_id).await? {
+        Some(image) => Ok(Json(image)),
+        None => Err(ApiError::NotFound(format!("No image {}", image_id))),
+    }
+}
+
+#[derive(Debug, MultipartForm, ToSchema)]
+pub struct UploadForm {
+    /// Images with generation parameters in metadata
+    #[multipart]
+    #[schema(value_type = Vec<String>, format = Binary)]
+    files: Vec<TempFile>,
+}
+
+/// Header required by [`upload_images`]. Multipart POST is a CORS simple request which other
+/// sites can send from the browser of a user, a custom header makes browsers ask first
+pub const REQUESTED_WITH_HEADER: &str = "X-Requested-With";
+
+/// Import uploaded `files`, every file gets its own result. Files which fail don't stop others
+#[utoipa::path(
+    post,
+    path = "/api/v1/images",
+    tag = "images",
+    params(("X-Requested-With" = String, Header, description = "Any value, required")),
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Result of every file in upload order", body = [UploadResult]),
+        (status = 400, body = ErrorBody),
+        (status = 403, description = "`X-Requested-With` header is missing", body = ErrorBody),
+    )
+)]
+pub async fn upload_images(
+    req: HttpRequest,
+    config: Data<Config>,
+    store: Data<dyn MediaStore>,
+    staging: Data<Staging>,
+    pool: Data<Pool<Sqlite>>,
+    MultipartForm(form): MultipartForm<UploadForm>,
+) -> Result<Json<Vec<UploadResult>>, ApiError> {
+    if !req.headers().contains_key(REQUESTED_WITH_HEADER) {
+        return Err(ApiError::Forbidden(format!(
+            "Provide `{}` header",
+            REQUESTED_WITH_HEADER
+        )));
+    }
+    if form.files.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Provide at least one file in `files`".to_string(),
+        ));
+    }
+    let files = form
+        .files
+        .iter()
+        .map(|file| (file.file_name.clone(), file.file.path()))
+        .collect();
+    let importer = Importer {
+        pool: pool.as_ref(),
+        staging: staging.as_ref(),
+        store: store.as_ref(),
+        layout: config.media_layout,
+        workers: config.upload_workers,
+    };
+    let results = importer.import_uploads(files).await;
+
+    Ok(Json(results))
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct DedupRequest {
+    #[serde(default)]
+    pub keep: DedupKeep,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DedupResult {
+    /// Number of duplicates moved to trash
+    pub deduplicated: usize,
+}
+
+/// Move to trash all but one image of every group of images with the same parameters
+#[utoipa::path(
+    post,
+    path = "/api/v1/dedup",
+    tag = "images",
+    request_body = DedupRequest,
+    responses(
+        (status = 200, body = DedupResult),
+        (status = 400, body = ErrorBody),
+    )
+)]
+pub async fn dedup(
+    pool: Data<Pool<Sqlite>>,
+    request: Json<DedupRequest>,
+) -> Result<Json<DedupResult>, ApiError> {
+    let mut connection = pool.acquire().await?;
+    let mut transaction = connection.begin().await?;
+    let deduplicated = dedup_images(&mut transaction, request.keep).await?;
+    transaction.commit().await?;
+
+    Ok(Json(DedupResult { deduplicated }))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeleteResult {
+    /// Number of images moved to trash or purged
+    pub deleted: usize,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteQuery {
+    /// Purge images and their files instead of moving them to trash
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+/// Delete images by ids and move them to trash, or purge them if `permanent`
+async fn delete(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    image_ids: &[i64],
+    permanent: bool,
+) -> Result<usize, ApiError> {
+    if permanent {
+        Ok(purge_images(pool, store, image_ids).await?)
+    } else {
+        Ok(trash_images(pool, image_ids).await? as usize)
+    }
+}
+
+/// Move image to trash, or purge it with its file if `permanent`
+#[utoipa::path(
+    delete,
+    path = "/api/v1/images/{id}",
+    tag = "images",
+    params(("id" = i64, Path, description = "Image id"), DeleteQuery),
+    responses(
+        (status = 200, body = DeleteResult),
+        (status = 404, description = "No image or it's already in trash", body = ErrorBody),
+    )
+)]
+pub async fn delete_image(
+    pool: Data<Pool<Sqlite>>,
+    store: Data<dyn MediaStore>,
+    path: web::Path<(i64,)>,
+    query: web::Query<DeleteQuery>,
+) -> Result<Json<DeleteResult>, ApiError> {
+    let (image_id,) = path.into_inner();
+    let image = fetch_image_by_id(pool.as_ref(), image_id).await?;
+    let deleted = match image {
+        Some(image) if query.permanent || image.deleted_at.is_none() => {
+            delete(&pool, store.as_ref(), &[image_id], query.permanent).await?
+        }
+        _ => 0,
+    };
+    if deleted == 0 {
+        return Err(ApiError::NotFound(format!("No image {}", image_id)));
+    }
+
+    Ok(Json(DeleteResult { deleted }))
+}
+
+/// Images to delete: either listed by ids or all images found by the search, which must not find every image
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteImagesRequest {
+    pub ids: Option<Vec<i64>>,
+    pub search: Option<String>,
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+/// Delete images listed by `ids` or all images found by `search`
+#[utoipa::path(
+    post,
+    path = "/api/v1/images/delete",
+    tag = "images",
+    request_body = DeleteImagesRequest,
+    responses(
+        (status = 200, body = DeleteResult),
+        (status = 400, body = ErrorBody),
+    )
+)]
+pub async fn delete_images(
+    pool: Data<Pool<Sqlite>>,
+    store: Data<dyn MediaStore>,
+    request: Json<DeleteImagesRequest>,
+) -> Result<Json<DeleteResult>, ApiError> {
+    let image_ids = match (&request.ids, &request.search) {
+        (Some(ids), None) => ids.clone(),
+        (None, Some(search)) => {
+            let search: SearchQuery = search.parse()?;
+            // Deleting the whole library takes more than a typo
+            let mut transaction = pool.begin().await?;
+            if search_finds_all(&mut transaction, &search).await? {
+                return Err(ApiError::BadRequest(
+                    "`search` must not find every image".to_string(),
+                ));
+            }
+            fetch_image_ids(&mut transaction, &search).await?
+        }
+        _ => {
+            return Err(ApiError::BadRequest(
+                "Exactly one of `ids` and `search` must be set".to_string(),
+            ))
+        }
+    };
+    let deleted = delete(&pool, store.as_ref(), &image_ids, request.permanent).await?;
+
+    Ok(Json(DeleteResult { deleted }))
+}
+
+const MAX_CHANGES: u32 = 1000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChangesQuery {
+    /// `cursor` of the previous response, 0 for all images
+    #[serde(default)]
+    pub since: i64,
+    /// Changed fields per response, at most 1000
+    #[serde(default = "default_changes_limit")]
+    #[param(minimum = 1, maximum = 1000)]
+    pub limit: u32,
+}
+
+fn default_changes_limit() -> u32 {
+    200
+}
+
+/// Images with fields changed after `since`, in order of changes. Every image comes with its
+/// current values and times of changes of the changed fields. Purged images are not listed
+#[utoipa::path(
+    get,
+    path = "/api/v1/changes",
+    tag = "sync",
+    params(ChangesQuery),
+    responses(
+        (status = 200, body = ChangeFeed),
+        (status = 400, body = ErrorBody),
+    )
+)]
+pub async fn list_changes(
+    pool: Data<Pool<Sqlite>>,
+    query: web::Query<ChangesQuery>,
+) -> Result<Json<ChangeFeed>, ApiError> {
+    if !(1..=MAX_CHANGES).contains(&query.limit) {
+        return Err(ApiError::BadRequest(format!(
+            "`limit` must be from 1 to {}",
+            MAX_CHANGES
+        )));
+    }
+    Ok(Json(fetch_changes(&pool, query.since, query.limit).await?))
+}
+
+/// Image file by its SHA-256 hash, redirects to the storage if it serves files itself
+#[utoipa::path(
+    get,
+    path = "/api/v1/media/{hash}",
+    tag = "sync",
+    params(("hash" = String, Path, description = "Hex SHA-256 of the file")),
+    responses(
+        (status = 200, description = "File contents", content_type = "application/octet-stream"),
+        (status = 307, description = "Redirect to the file in the storage"),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn get_media_by_hash(
+    pool: Data<Pool<Sqlite>>,
+    store: Data<dyn MediaStore>,
+    path: web::Path<(String,)>,
+) -> Result<Either<web::Redirect, HttpResponse>, ApiError> {
+    let (file_hash,) = path.into_inner();
+    let not_found = || ApiError::NotFound(format!("No file {}", file_hash));
+    let key = fetch_file_path_by_hash(pool.as_ref(), &file_hash)
+        .await?
+        .ok_or_else(not_found)?;
+
+    if let Some(url) = store.presign(&key) {
+        return Ok(Either::Left(web::Redirect::to(url).temporary()));
+    }
+    let stream = match store.get(&key).await {
+        Ok(stream) => stream,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+        Err(error) => return Err(anyhow::Error::from(error).into()),
+    };
+    Ok(Either::Right(
+        HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .streaming(stream),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, read_body_json, TestRequest},
+        web::{delete, post, resource, scope, to, Data},
+        App,
+    };
+    use serde_json::{json, Value};
+    use tempfile::{NamedTempFile, TempDir};
+
+    use utoipa::OpenApi;
+
+    use super::{configure, delete_image, delete_images, not_found, routes, ApiDoc};
+    use crate::{
+        config::Config,
+        models::{fetch_image_by_id, insert_test_image, new_test_image, new_test_pool, Image},
+        storage::{LocalStore, MediaStore, Staging},
+    };
+
+    #[actix_web::test]
+    async fn test_delete_endpoints() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+        let source = NamedTempFile::new().unwrap();
+        for key in ["images/cat.png", "images/dog.png", "images/bird.png"] {
+            store.put(key, source.path()).await.unwrap();
+        }
+        let cat_id = insert_test_image(
+            &pool,
+            Image {
+                prompt: "a cat".to_string(),
+                file_path: Some("images/cat.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let dog_id = insert_test_image(
+            &pool,
+            Image {
+                prompt: "a dog".to_string(),
+                file_path: Some("images/dog.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let bird_id = insert_test_image(
+            &pool,
+            Image {
+                prompt: "a bird".to_string(),
+                file_path: Some("images/bird.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+
+        let store: std::sync::Arc<dyn MediaStore> = std::sync::Arc::new(store);
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(pool.clone()))
+                .app_data(Data::from(store.clone()))
+                .service(resource("/images/delete").route(post().to(delete_images)))
+                .service(resource("/images/{id}").route(delete().to(delete_image))),
+        )
+        .await;
+
+        // Moved to trash, the file is kept
+        let req = TestRequest::delete()
+            .uri(&format!("/images/{}", cat_id))
+            .to_request();
+        let body: Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body, json!({"deleted": 1}));
+        let cat = fetch_image_by_id(&pool, cat_id).await.unwrap().unwrap();
+        assert!(cat.deleted_at.is_some());
+        assert!(store.exists("images/cat.png").await.unwrap());
+
+        // Already in trash
+        let req = TestRequest::delete()
+            .uri(&format!("/images/{}", cat_id))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "not_found");
+
+        let req = TestRequest::post()
+            .uri("/images/delete")
+            .set_json(json!({"search": "dog", "permanent": true}))
+            .to_request();
+        let body: Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body, json!({"deleted": 1}));
+        assert!(fetch_image_by_id(&pool, dog_id).await.unwrap().is_none());
+        assert!(!store.exists("images/dog.png").await.unwrap());
+
+        let req = TestRequest::post()
+            .uri("/images/delete")
+            .set_json(json!({"ids": [bird_id], "search": "bird"}))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        // Searches finding every image left (the bird)
+        for search in ["  ", r#""""#, "steps:>0", "bird"] {
+            let req = TestRequest::post()
+                .uri("/images/delete")
+                .set_json(json!({ "search": search }))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", search);
+        }
+        assert!(fetch_image_by_id(&pool, bird_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .deleted_at
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_read_endpoints() {
+        let pool = new_test_pool().await;
+        let cat_id = insert_test_image(
+            &pool,
+            Image {
+                prompt: "a cat".to_string(),
+                file_path: Some("images/cat.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        insert_test_image(
+            &pool,
+            Image {
+                prompt: "a cat".to_string(),
+                file_path: Some("images/cat2.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        insert_test_image(
+            &pool,
+            Image {
+                prompt: "a dog".to_string(),
+                file_path: Some("images/dog.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+
+        let app = init_service(
+            App::new().app_data(Data::new(pool.clone())).service(
+                scope("/api/v1")
+                    .configure(configure)
+                    .default_service(to(not_found)),
+            ),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/api/v1/images?search=cat&sort=oldest&per_page=1&page=2")
+            .to_request();
+        let body: Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["pages"], 2);
+        assert_eq!(body["images"][0]["file_path"], "images/cat2.png");
+
+        let req = TestRequest::get()
+            .uri(&format!("/api/v1/images/{}", cat_id))
+            .to_request();
+        let body: Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["prompt"], "a cat");
+
+        // Errors are JSON too
+        for (uri, status, code) in [
+            ("/api/v1/images/100", StatusCode::NOT_FOUND, "not_found"),
+            ("/api/v1/images/cat", StatusCode::BAD_REQUEST, "bad_request"),
+            (
+                "/api/v1/images?search=steps:many",
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+            (
+                "/api/v1/images?per_page=1000",
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+            (
+                "/api/v1/images?page=4294967295&per_page=100",
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+            (
+                "/api/v1/images?sort=random",
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+            ("/api/v1/unknown", StatusCode::NOT_FOUND, "not_found"),
+        ] {
+            let resp = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+            let body: Value = read_body_json(resp).await;
+            assert_eq!(body["error"]["code"], code, "{}", uri);
+        }
+
+        let req = TestRequest::post()
+            .uri("/api/v1/dedup")
+            .set_json(json!({"keep": "newest"}))
+            .to_request();
+        let body: Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body, json!({"deduplicated": 1}));
+        let req = TestRequest::post()
+            .uri("/api/v1/dedup")
+            .set_json(json!({"keep": "oldest"}))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_upload_requires_header() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store: std::sync::Arc<dyn MediaStore> =
+            std::sync::Arc::new(LocalStore::new(media_root.path()));
+        let config: Config = envy::from_iter([
+            ("HOST".to_string(), "127.0.0.1".to_string()),
+            ("PORT".to_string(), "0".to_string()),
+            ("DATABASE_URL".to_string(), "sqlite::memory:".to_string()),
+            (
+                "MEDIA_ROOT".to_string(),
+                media_root.path().display().to_string(),
+            ),
+        ])
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(pool.clone()))
+                .app_data(Data::from(store))
+                .app_data(Data::new(Staging::new(media_root.path())))
+                .app_data(Data::new(config))
+                .service(scope("/api/v1").configure(configure)),
+        )
+        .await;
+
+        let body = "--boundary\r\nContent-Disposition: form-data; name=\"files\"; \
+            filename=\"a.txt\"\r\n\r\nnot an image\r\n--boundary--\r\n";
+        let upload = || {
+            TestRequest::post()
+                .uri("/api/v1/images")
+                .insert_header(("Content-Type", "multipart/form-data; boundary=boundary"))
+                .set_payload(body)
+        };
+
+        // Other sites can send such request from the browser without asking
+        let resp = call_service(&app, upload().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "forbidden");
+
+        let req = upload()
+            .insert_header(("X-Requested-With", "test"))
+            .to_request();
+        let body: Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(
+            body,
+            json!([{"file_name": "a.txt", "status": "unsupported_format"}])
+        );
+    }
+
+    #[test]
+    fn test_openapi_has_all_routes() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for (path, method, _) in routes() {
+            let operation =
+                &spec["paths"][format!("/api/v1{}", path)][method.as_str().to_lowercase()];
+            assert!(operation.is_object(), "{} {} is not in spec", method, path);
+            assert!(operation["responses"]["200"].is_object());
+        }
+        assert!(spec["components"]["schemas"]["Image"].is_object());
+    }
+}
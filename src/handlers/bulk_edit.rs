@@ -0,0 +1,215 @@
+use actix_web::{
+    http::header::ContentType,
+    web::{self, Data, Form},
+    HttpRequest, HttpResponse,
+};
+use askama::Template;
+use serde::Deserialize;
+use sqlx::{Acquire, Pool, Sqlite};
+
+use crate::{
+    bulk_edit::{
+        bulk_edit, fetch_bulk_edits, undo_bulk_edit, BulkEdit, BulkEditReport, BulkOperation,
+        UndoError, UndoReport, FIELDS, TEXT_FIELDS,
+    },
+    utils::{
+        csrf::{self, CsrfToken},
+        errors::MapErrToInternal,
+        render::render_html,
+    },
+};
+
+/// How many previous bulk edits are listed
+const HISTORY_SIZE: i64 = 10;
+
+#[derive(Template)]
+#[template(path = "images/bulk_edit.html")]
+pub struct BulkEditTemplate {
+    form: BulkEditForm,
+    fields: &'static [&'static str],
+    text_fields: &'static [&'static str],
+    error: Option<String>,
+    report: Option<BulkEditReport>,
+    undo: Option<UndoReport>,
+    bulk_edits: Vec<BulkEdit>,
+    csrf_token: CsrfToken,
+}
+
+#[derive(Default, Deserialize)]
+pub struct BulkEditForm {
+    #[serde(default)]
+    csrf_token: String,
+    #[serde(default)]
+    search: String,
+    /// `set` or `replace`
+    #[serde(default)]
+    operation: String,
+    #[serde(default)]
+    field: String,
+    #[serde(default)]
+    value: String,
+    #[serde(default)]
+    pattern: String,
+    #[serde(default)]
+    replacement: String,
+    /// `preview` or `apply`
+    #[serde(default)]
+    action: String,
+}
+
+impl BulkEditForm {
+    fn is_field(&self, field: &str) -> bool {
+        self.field == field
+    }
+
+    fn operation(&self) -> Result<BulkOperation, String> {
+        let operation = match self.operation.as_str() {
+            "replace" => BulkOperation::replace(&self.field, &self.pattern, &self.replacement),
+            _ => BulkOperation::set(&self.field, &self.value),
+        };
+        operation.map_err(|error| error.to_string())
+    }
+}
+
+async fn render(
+    pool: &Pool<Sqlite>,
+    template: BulkEditTemplate,
+) -> actix_web::Result<HttpResponse> {
+    let bulk_edits = fetch_bulk_edits(pool, HISTORY_SIZE)
+        .await
+        .map_err_to_internal()?;
+    let response = match template.error {
+        Some(_) => HttpResponse::BadRequest(),
+        None => HttpResponse::Ok(),
+    };
+    render_html(
+        BulkEditTemplate {
+            bulk_edits,
+            ..template
+        },
+        response,
+    )
+}
+
+fn template(form: BulkEditForm, csrf_token: CsrfToken) -> BulkEditTemplate {
+    BulkEditTemplate {
+        form,
+        fields: &FIELDS,
+        text_fields: &TEXT_FIELDS,
+        error: None,
+        report: None,
+        undo: None,
+        bulk_edits: Vec::new(),
+        csrf_token,
+    }
+}
+
+pub async fn bulk_edit_get(
+    pool: Data<Pool<Sqlite>>,
+    query: web::Query<BulkEditForm>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<HttpResponse> {
+    render(&pool, template(query.into_inner(), csrf_token)).await
+}
+
+/// Preview or apply bulk edit. Preview runs the same code without writing anything.
+pub async fn bulk_edit_post(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    form: Form<BulkEditForm>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<HttpResponse> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let form = form.into_inner();
+    let operation = match form.operation() {
+        Ok(operation) => operation,
+        Err(error) => {
+            return render(
+                &pool,
+                BulkEditTemplate {
+                    error: Some(error),
+                    ..template(form, csrf_token)
+                },
+            )
+            .await
+        }
+    };
+
+    let dry_run = form.action != "apply";
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    let report = bulk_edit(&mut transaction, &form.search, &operation, dry_run).await;
+    let report = match report {
+        Ok(report) => report,
+        Err(error) => {
+            return render(
+                &pool,
+                BulkEditTemplate {
+                    error: Some(format!("{:#}", error)),
+                    ..template(form, csrf_token)
+                },
+            )
+            .await
+        }
+    };
+    transaction.commit().await.map_err_to_internal()?;
+    drop(connection);
+
+    render(
+        &pool,
+        BulkEditTemplate {
+            report: Some(report),
+            ..template(form, csrf_token)
+        },
+    )
+    .await
+}
+
+pub async fn undo_post(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<BulkEditForm>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<HttpResponse> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (bulk_edit_id,) = path.into_inner();
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    let undo = match undo_bulk_edit(&mut transaction, bulk_edit_id).await {
+        Ok(undo) => undo,
+        Err(error) => {
+            let error = match error.downcast::<UndoError>() {
+                Ok(UndoError::NotFound(_)) => {
+                    return Ok(HttpResponse::NotFound()
+                        .content_type(ContentType::html())
+                        .body("No bulk edit"))
+                }
+                Ok(error) => error,
+                Err(error) => Err(error).map_err_to_internal()?,
+            };
+            drop(transaction);
+            drop(connection);
+            return render(
+                &pool,
+                BulkEditTemplate {
+                    error: Some(error.to_string()),
+                    ..template(BulkEditForm::default(), csrf_token)
+                },
+            )
+            .await;
+        }
+    };
+    transaction.commit().await.map_err_to_internal()?;
+    drop(connection);
+
+    render(
+        &pool,
+        BulkEditTemplate {
+            undo: Some(undo),
+            ..template(BulkEditForm::default(), csrf_token)
+        },
+    )
+    .await
+}
@@ -0,0 +1,75 @@
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Data},
+    HttpResponse,
+};
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    export::{write_export, Export, NameTemplate, DEFAULT_NAME_TEMPLATE},
+    storage::MediaStore,
+    utils::search::{SearchQuery, SortOrder},
+};
+
+/// Bytes of the archive buffered between its writer and the response
+const BUFFER_SIZE: usize = 64 * 1024;
+
+fn default_name_template() -> String {
+    DEFAULT_NAME_TEMPLATE.to_owned()
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    search: String,
+    #[serde(default)]
+    sort: SortOrder,
+    #[serde(default = "default_name_template")]
+    name_template: String,
+    /// Add `metadata.csv`
+    #[serde(default)]
+    csv: bool,
+}
+
+/// Download ZIP of found images with their metadata, the archive is built while it's sent
+pub async fn export_images(
+    pool: Data<Pool<Sqlite>>,
+    store: Data<dyn MediaStore>,
+    query: web::Query<ExportQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let query = query.into_inner();
+    let export = Export {
+        search: query
+            .search
+            .parse::<SearchQuery>()
+            .map_err(actix_web::error::ErrorBadRequest)?,
+        sort: query.sort,
+        name_template: query
+            .name_template
+            .parse::<NameTemplate>()
+            .map_err(actix_web::error::ErrorBadRequest)?,
+        csv: query.csv,
+    };
+
+    let (writer, reader) = tokio::io::duplex(BUFFER_SIZE);
+    actix_web::rt::spawn(async move {
+        // Fails when the client disconnects too, the archive is truncated anyway
+        if let Err(error) = write_export(&pool, store.as_ref(), &export, writer).await {
+            log::error!("Failed to export images: {:#}", error);
+        }
+    });
+
+    let file_name = format!(
+        "sdgenbox-export-{}.zip",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        })
+        .streaming(ReaderStream::new(reader)))
+}
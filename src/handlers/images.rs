@@ -1,20 +1,34 @@
-use std::path::Path;
-
-use actix_multipart::form::{tempfile::TempFile, MultipartForm};
+use actix_multipart::form::{tempfile::TempFile, text::Text, MultipartForm};
 use actix_web::{
     http::header::ContentType,
-    web::{self, Data, Redirect},
-    HttpResponse, Responder,
+    web::{self, Data, Form, Redirect},
+    Either, HttpRequest, HttpResponse, Responder,
 };
 use askama::Template;
 use serde::Deserialize;
-use sqlx::{Connection, Pool, Sqlite, Transaction};
+use sqlx::{Connection, Executor, Pool, Sqlite};
 
 use crate::{
+    albums::{fetch_albums, fetch_image_album_ids, Album},
     config::Config,
-    models::{create_image, fetch_image_by_id, fetch_images, fetch_images_count, Image, Limits},
+    models::{
+        fetch_image_by_id, fetch_image_ids, fetch_images, fetch_images_count, search_finds_all,
+        set_image_favorite, set_image_note, set_image_rating, trash_found_images, trash_image,
+        trash_images, ExtraParams, Image, Limits,
+    },
+    revisions::{
+        fetch_image_revisions, revert_image, update_image_params, ImageParams, ImageRevision,
+    },
+    storage::{MediaStore, Staging},
+    tags::fetch_image_tags,
+    upload::{Importer, UploadResult},
     utils::{
-        errors::MapErrToInternal, image::extract_metadata_from_image, pager, render::render_html,
+        csrf::{self, CsrfToken, CSRF_FIELD},
+        errors::MapErrToInternal,
+        markdown::render_markdown,
+        pager,
+        render::{redirect_back, render_html},
+        search::{SearchQuery, SortOrder},
     },
 };
 
@@ -22,6 +36,7 @@ use crate::{
 #[template(path = "images/upload_form.html")]
 pub struct UploadFormTemplate<'a> {
     error_message: Option<&'a str>,
+    csrf_token: CsrfToken,
 }
 
 #[derive(serde::Deserialize)]
@@ -29,87 +44,99 @@ pub struct UploadGetQuery {
     error_message: Option<String>,
 }
 
-pub async fn upload_get(query: web::Query<UploadGetQuery>) -> actix_web::Result<HttpResponse> {
+pub async fn upload_get(
+    query: web::Query<UploadGetQuery>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<HttpResponse> {
     render_html(
         UploadFormTemplate {
             error_message: query.error_message.as_deref(),
+            csrf_token,
         },
         HttpResponse::Ok(),
     )
 }
 
-#[derive(Debug, thiserror::Error)]
-enum ParseAndSaveImageError {
-    #[error("Failed to parse metadata from image")]
-    ParseError,
-    #[error("Internal error occuried")]
-    InternalError(#[from] anyhow::Error),
+#[derive(Template)]
+#[template(path = "images/upload_result.html")]
+pub struct UploadResultTemplate {
+    results: Vec<UploadResult>,
 }
 
-async fn parse_and_save_image(
-    transaction: &mut Transaction<'_, Sqlite>,
-    original_file: TempFile,
-    media_root: &Path,
-) -> Result<Image, ParseAndSaveImageError> {
-    let mut image = extract_metadata_from_image(original_file.file.path().to_str().unwrap())?
-        .ok_or(ParseAndSaveImageError::ParseError)?;
-
-    create_image(
-        transaction,
-        &mut image,
-        original_file.file.path(),
-        media_root,
-    )
-    .await?;
-
-    Ok(image)
+impl UploadResultTemplate {
+    fn imported_count(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| result.outcome.is_imported())
+            .count()
+    }
 }
 
 #[derive(Debug, MultipartForm)]
 pub struct UploadForm {
     #[multipart]
     files: Vec<TempFile>,
+    csrf_token: Text<String>,
 }
 
 pub async fn upload_post(
+    req: HttpRequest,
     config: Data<Config>,
+    store: Data<dyn MediaStore>,
+    staging: Data<Staging>,
     MultipartForm(form): MultipartForm<UploadForm>,
     pool: Data<Pool<Sqlite>>,
-) -> actix_web::Result<impl Responder> {
-    let mut connection = pool.acquire().await.map_err_to_internal()?;
-    let mut transaction = connection.begin().await.map_err_to_internal()?;
-
-    let mut results = Vec::new();
-    for original_file in form.files {
-        let result =
-            parse_and_save_image(&mut transaction, original_file, &config.media_root).await;
-        results.push(result);
-    }
-
-    transaction.commit().await.map_err_to_internal()?;
+) -> actix_web::Result<Either<Redirect, HttpResponse>> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let files = form
+        .files
+        .iter()
+        .map(|file| (file.file_name.clone(), file.file.path()))
+        .collect();
+    let importer = Importer {
+        pool: pool.as_ref(),
+        staging: staging.as_ref(),
+        store: store.as_ref(),
+        layout: config.media_layout,
+        workers: config.upload_workers,
+    };
+    let results = importer.import_uploads(files).await;
 
-    match &results[..] {
-        [] => {
-            Ok(Redirect::to("/images/upload?error_message=Provide at least one file").see_other())
-        }
-        [Ok(image)] => Ok(Redirect::to(format!("/images/{}", image.id)).see_other()),
-        _ if results.iter().all(|r| r.is_ok()) => Ok(Redirect::to("/images").see_other()),
-        _ => Ok(Redirect::to(
-            "/images/upload?error_message=There are error while saving at least one image",
-        )
-        .see_other()),
+    if results.is_empty() {
+        return Ok(Either::Left(
+            Redirect::to("/images/upload?error_message=Provide at least one file").see_other(),
+        ));
     }
+    Ok(Either::Right(render_html(
+        UploadResultTemplate { results },
+        HttpResponse::Ok(),
+    )?))
 }
 
 #[derive(Template)]
 #[template(path = "images/image.html")]
 pub struct GetImageTemplate {
     image: Image,
+    revisions: Vec<ImageRevision>,
+    tags: Vec<String>,
+    albums: Vec<Album>,
+    /// Albums containing the image
+    image_album_ids: Vec<i64>,
+    /// Sanitized HTML of the note
+    note_html: String,
+    csrf_token: CsrfToken,
+}
+
+impl GetImageTemplate {
+    fn in_album(&self, album: &Album) -> bool {
+        self.image_album_ids.contains(&album.id)
+    }
 }
 
 pub async fn get_image(
     pool: web::Data<Pool<Sqlite>>,
     path: web::Path<(i64,)>,
+    csrf_token: CsrfToken,
 ) -> actix_web::Result<impl Responder> {
     let (image_id,) = path.into_inner();
 
@@ -125,8 +152,30 @@ pub async fn get_image(
         }
         Some(image) => image,
     };
+    let revisions = fetch_image_revisions(&mut connection, image_id)
+        .await
+        .map_err_to_internal()?;
+    let tags = fetch_image_tags(&mut connection, image_id)
+        .await
+        .map_err_to_internal()?;
+    let albums = fetch_albums(&mut connection).await.map_err_to_internal()?;
+    let image_album_ids = fetch_image_album_ids(&mut connection, image_id)
+        .await
+        .map_err_to_internal()?;
+    let note_html = render_markdown(&image.note);
 
-    render_html(GetImageTemplate { image }, HttpResponse::Created())
+    render_html(
+        GetImageTemplate {
+            image,
+            revisions,
+            tags,
+            albums,
+            image_album_ids,
+            note_html,
+            csrf_token,
+        },
+        HttpResponse::Created(),
+    )
 }
 
 #[derive(Template)]
@@ -134,18 +183,31 @@ pub async fn get_image(
 pub struct ListImagesTemplate<'a> {
     images: &'a [Image],
     search_form: &'a SearchForm,
+    search_error: Option<String>,
+    count: u32,
     current_page: &'a u32,
     pager: Vec<Option<u32>>,
+    albums: Vec<Album>,
+    sort_orders: &'static [SortOrder],
+    csrf_token: CsrfToken,
 }
 
 #[derive(Deserialize)]
 pub struct SearchForm {
     search: Option<String>,
+    #[serde(default)]
+    sort: SortOrder,
+}
+
+impl SearchForm {
+    fn is_sorted_by(&self, sort: &SortOrder) -> bool {
+        self.sort == *sort
+    }
 }
 
 #[derive(Deserialize)]
 pub struct PageQuery {
-    page: Option<u32>,
+    pub page: Option<u32>,
 }
 
 const PAGE_SIZE: u32 = 18;
@@ -154,22 +216,33 @@ pub async fn list_images(
     pool: web::Data<Pool<Sqlite>>,
     search_form: web::Query<SearchForm>,
     page_query: web::Query<PageQuery>,
+    csrf_token: CsrfToken,
 ) -> actix_web::Result<impl Responder> {
     let mut connection = pool.acquire().await.map_err_to_internal()?;
     let search_form = search_form.into_inner();
-    let search = &search_form.search.as_deref();
     let page = match page_query.page {
         Some(n) if n >= 1 => n,
         _ => 1,
     };
 
+    let (search, search_error) = match search_form.search.as_deref().unwrap_or_default().parse() {
+        Ok(search) => (search, None),
+        Err(error) => (SearchQuery::default(), Some(format!("{}", error))),
+    };
     let limits = Limits::from_page(page, PAGE_SIZE);
-    let images = fetch_images(&mut connection, *search, &limits)
-        .await
-        .map_err_to_internal()?;
-    let count = fetch_images_count(&mut connection, *search)
-        .await
-        .map_err_to_internal()?;
+    let (images, count) = match search_error {
+        Some(_) => (Vec::new(), 0),
+        None => (
+            fetch_images(&mut connection, &search, search_form.sort, &limits)
+                .await
+                .map_err_to_internal()?,
+            fetch_images_count(&mut connection, &search)
+                .await
+                .map_err_to_internal()?,
+        ),
+    };
+
+    let albums = fetch_albums(&mut connection).await.map_err_to_internal()?;
 
     let pages = (count as f32 / PAGE_SIZE as f32).ceil() as usize;
     let pager = pager::pager(pages as u32, page, 2, 2);
@@ -177,12 +250,301 @@ pub async fn list_images(
         ListImagesTemplate {
             images: &images[..],
             search_form: &search_form,
+            search_error,
+            count,
             current_page: &page,
             pager,
+            albums,
+            sort_orders: SortOrder::ALL,
+            csrf_token,
         },
         HttpResponse::Ok(),
     )
 }
 
+#[derive(Deserialize)]
+pub struct CsrfForm {
+    pub csrf_token: String,
+}
+
+/// Move image to trash, its file is removed when trash is purged
+pub async fn delete_image(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<CsrfForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    trash_image(pool.as_ref(), image_id)
+        .await
+        .map_err_to_internal()?;
+    Ok(Redirect::to("/images").see_other())
+}
+
+/// Form of bulk actions on the image list. Selected images are sent as repeated `ids` fields,
+/// so the form is read as list of pairs; `scope=all` selects all images found by `search`.
+pub struct BulkForm(Vec<(String, String)>);
+
+impl BulkForm {
+    pub fn new(form: Form<Vec<(String, String)>>) -> Self {
+        BulkForm(form.into_inner())
+    }
+
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn verify_csrf(&self, req: &HttpRequest) -> actix_web::Result<()> {
+        csrf::verify(req, self.field(CSRF_FIELD).unwrap_or_default())
+    }
+
+    pub fn search(&self) -> actix_web::Result<SearchQuery> {
+        let search = self.field("search").unwrap_or_default();
+        search.parse().map_err(actix_web::error::ErrorBadRequest)
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.field("scope") == Some("all")
+    }
+
+    pub fn selected_ids(&self) -> actix_web::Result<Vec<i64>> {
+        self.0
+            .iter()
+            .filter(|(key, _)| key == "ids")
+            .map(|(_, value)| value.parse())
+            .collect::<Result<Vec<i64>, _>>()
+            .map_err(actix_web::error::ErrorBadRequest)
+    }
+
+    /// Ids of selected images or all found images depending on scope
+    pub async fn image_ids(
+        &self,
+        executor: impl Executor<'_, Database = Sqlite>,
+    ) -> actix_web::Result<Vec<i64>> {
+        if self.is_all() {
+            Ok(fetch_image_ids(executor, &self.search()?)
+                .await
+                .map_err_to_internal()?)
+        } else {
+            self.selected_ids()
+        }
+    }
+
+    /// Back to the list with the same search
+    pub fn redirect(&self) -> actix_web::Result<Redirect> {
+        let search = self.field("search").unwrap_or_default();
+        let query = serde_urlencoded::to_string([("search", search)]).map_err_to_internal()?;
+        Ok(Redirect::to(format!("/images?{}", query)).see_other())
+    }
+}
+
+/// Move selected images or all images found by the search to trash
+pub async fn delete_images(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    form: Form<Vec<(String, String)>>,
+) -> actix_web::Result<impl Responder> {
+    let form = BulkForm::new(form);
+    form.verify_csrf(&req)?;
+
+    if form.is_all() {
+        let search = form.search()?;
+        let mut transaction = pool.begin().await.map_err_to_internal()?;
+        if search_finds_all(&mut transaction, &search)
+            .await
+            .map_err_to_internal()?
+        {
+            return Err(actix_web::error::ErrorBadRequest(
+                "Search finds every image, narrow it down or select images to delete",
+            ));
+        }
+        trash_found_images(&mut transaction, &search)
+            .await
+            .map_err_to_internal()?;
+        transaction.commit().await.map_err_to_internal()?;
+    } else {
+        trash_images(pool.as_ref(), &form.selected_ids()?)
+            .await
+            .map_err_to_internal()?;
+    }
+
+    form.redirect()
+}
+
+#[derive(Deserialize)]
+pub struct RatingForm {
+    csrf_token: String,
+    /// Stars from 0 to 5
+    rating: i64,
+}
+
+pub async fn set_rating(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<RatingForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    if !(0..=5).contains(&form.rating) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Rating must be from 0 to 5",
+        ));
+    }
+    set_image_rating(pool.as_ref(), image_id, form.rating)
+        .await
+        .map_err_to_internal()?;
+    Ok(redirect_back(&req, &format!("/images/{}", image_id)))
+}
+
+#[derive(Deserialize)]
+pub struct FavoriteForm {
+    csrf_token: String,
+    favorite: bool,
+}
+
+pub async fn set_favorite(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<FavoriteForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    set_image_favorite(pool.as_ref(), image_id, form.favorite)
+        .await
+        .map_err_to_internal()?;
+    Ok(redirect_back(&req, &format!("/images/{}", image_id)))
+}
+
+#[derive(Deserialize)]
+pub struct NoteForm {
+    csrf_token: String,
+    /// Markdown, empty removes the note
+    note: String,
+}
+
+pub async fn set_note(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<NoteForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    let note = form.note.replace("\r\n", "\n");
+    set_image_note(pool.as_ref(), image_id, note.trim_end())
+        .await
+        .map_err_to_internal()?;
+    Ok(Redirect::to(format!("/images/{}#note", image_id)).see_other())
+}
+
+#[derive(Deserialize)]
+pub struct EditImageForm {
+    csrf_token: String,
+    prompt: String,
+    negative_prompt: String,
+    steps: i64,
+    sampler: String,
+    cfg_scale: f64,
+    seed: i64,
+    width: i64,
+    height: i64,
+    model_hash: String,
+    model: String,
+    /// Empty if not set
+    clip_skip: String,
+    /// `Key: value` lines
+    extra_params: String,
+}
+
+impl EditImageForm {
+    fn to_params(&self) -> Result<ImageParams, String> {
+        let clip_skip = match self.clip_skip.trim() {
+            "" => None,
+            clip_skip => Some(
+                clip_skip
+                    .parse()
+                    .map_err(|_| format!("Invalid clip skip: {}", clip_skip))?,
+            ),
+        };
+        let mut extra_params = ExtraParams::new();
+        for line in self.extra_params.lines().filter(|l| !l.trim().is_empty()) {
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("Parameter must be \"Key: value\": {}", line))?;
+            extra_params.insert(key.trim().to_string(), value.trim().to_string());
+        }
+
+        Ok(ImageParams {
+            prompt: self.prompt.clone(),
+            negative_prompt: self.negative_prompt.clone(),
+            steps: self.steps,
+            sampler: self.sampler.clone(),
+            cfg_scale: self.cfg_scale,
+            seed: self.seed,
+            width: self.width,
+            height: self.height,
+            model_hash: self.model_hash.clone(),
+            model: self.model.clone(),
+            clip_skip,
+            extra_params,
+        })
+    }
+}
+
+/// Change metadata of image, previous values are kept in revision
+pub async fn edit_image(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<EditImageForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    let params = form
+        .to_params()
+        .map_err(actix_web::error::ErrorBadRequest)?;
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    update_image_params(&mut transaction, image_id, &params, None)
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    Ok(Redirect::to(format!("/images/{}", image_id)).see_other())
+}
+
+#[derive(Deserialize)]
+pub struct RevertImageForm {
+    csrf_token: String,
+    /// Image gets values it had after this revision, 0 is the original metadata
+    revision_id: i64,
+}
+
+pub async fn revert_image_post(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<RevertImageForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    revert_image(&mut transaction, image_id, form.revision_id)
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    Ok(Redirect::to(format!("/images/{}", image_id)).see_other())
+}
+
 #[cfg(test)]
 mod test {}
@@ -0,0 +1,166 @@
+use actix_web::{
+    web::{self, Data, Form, Redirect},
+    HttpRequest, HttpResponse, Responder,
+};
+use askama::Template;
+use serde::Deserialize;
+use sqlx::{Connection, Pool, Sqlite};
+
+use crate::{
+    albums::{fetch_albums, Album},
+    handlers::images::CsrfForm,
+    inbox::{fetch_inbox_count, fetch_next_inbox_image, keep_all, triage_image, Triage},
+    models::Image,
+    tags::{fetch_image_tags, parse_names},
+    utils::{
+        csrf::{self, CsrfToken},
+        errors::MapErrToInternal,
+        render::render_html,
+    },
+};
+
+#[derive(Template)]
+#[template(path = "inbox.html")]
+pub struct InboxTemplate {
+    image: Option<Image>,
+    tags: Vec<String>,
+    albums: Vec<Album>,
+    /// Images left in inbox
+    remaining: i64,
+    /// Images triaged since the triage started
+    done: u32,
+    csrf_token: CsrfToken,
+}
+
+impl InboxTemplate {
+    /// Percent of triaged images
+    fn progress(&self) -> i64 {
+        let total = self.done as i64 + self.remaining;
+        match total {
+            0 => 100,
+            total => self.done as i64 * 100 / total,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InboxQuery {
+    /// Show the image after this one, the skipped images are shown after the rest
+    #[serde(default)]
+    after: i64,
+    #[serde(default)]
+    done: u32,
+}
+
+/// Show the next inbox image to triage
+pub async fn inbox_get(
+    pool: Data<Pool<Sqlite>>,
+    query: web::Query<InboxQuery>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<impl Responder> {
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut image = fetch_next_inbox_image(&mut connection, query.after)
+        .await
+        .map_err_to_internal()?;
+    if image.is_none() && query.after > 0 {
+        image = fetch_next_inbox_image(&mut connection, 0)
+            .await
+            .map_err_to_internal()?;
+    }
+    let tags = match &image {
+        Some(image) => fetch_image_tags(&mut connection, image.id)
+            .await
+            .map_err_to_internal()?,
+        None => Vec::new(),
+    };
+    let albums = fetch_albums(&mut connection).await.map_err_to_internal()?;
+    let remaining = fetch_inbox_count(&mut connection)
+        .await
+        .map_err_to_internal()?;
+
+    render_html(
+        InboxTemplate {
+            image,
+            tags,
+            albums,
+            remaining,
+            done: query.done,
+            csrf_token,
+        },
+        HttpResponse::Ok(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct TriageForm {
+    csrf_token: String,
+    /// `keep` or `trash`
+    #[serde(default)]
+    action: String,
+    /// Empty keeps the rating
+    #[serde(default)]
+    rating: String,
+    /// Comma separated tag names to add
+    #[serde(default)]
+    tags: String,
+    /// Empty doesn't add to album
+    #[serde(default)]
+    album_id: String,
+    #[serde(default)]
+    done: u32,
+}
+
+/// Optional number from form field, empty field is `None`
+fn parse_optional(value: &str) -> actix_web::Result<Option<i64>> {
+    match value.trim() {
+        "" => Ok(None),
+        value => Ok(Some(
+            value.parse().map_err(actix_web::error::ErrorBadRequest)?,
+        )),
+    }
+}
+
+/// Triage inbox image and show the next one
+pub async fn triage(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<TriageForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    let rating = parse_optional(&form.rating)?;
+    if matches!(rating, Some(rating) if !(0..=5).contains(&rating)) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Rating must be from 0 to 5",
+        ));
+    }
+    let triage = Triage {
+        trash: form.action == "trash",
+        rating,
+        tags: parse_names(&form.tags).map_err(actix_web::error::ErrorBadRequest)?,
+        album_id: parse_optional(&form.album_id)?,
+    };
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    let triaged = triage_image(&mut transaction, image_id, &triage)
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    // Image triaged twice (e.g. by resubmitted form) isn't counted again
+    let done = form.done.saturating_add(triaged as u32);
+    Ok(Redirect::to(format!("/inbox?after={}&done={}", image_id, done)).see_other())
+}
+
+/// Keep all inbox images without triage
+pub async fn keep_all_post(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    form: Form<CsrfForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    keep_all(pool.as_ref()).await.map_err_to_internal()?;
+    Ok(Redirect::to("/inbox").see_other())
+}
@@ -1,14 +1,20 @@
 use actix_web::{
-    body::BoxBody, http::header::ContentType, web::Data, HttpResponse, HttpResponseBuilder,
-    Responder,
+    body::BoxBody,
+    http::header::ContentType,
+    web::{Data, Form},
+    HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
 };
 use askama::Template;
+use serde::Deserialize;
 use sqlx::{Acquire, Pool, Sqlite};
 
 use crate::{
-    config::Config,
-    models::dedup_images,
-    utils::{errors::MapErrToInternal, render::render_html},
+    models::{dedup_images, DedupKeep},
+    utils::{
+        csrf::{self, CsrfToken},
+        errors::MapErrToInternal,
+        render::render_html,
+    },
 };
 
 pub struct TemplateResponse<T: Template> {
@@ -37,10 +43,15 @@ impl<T: Template> Responder for TemplateResponse<T> {
 
 #[derive(Template)]
 #[template(path = "index.html")]
-pub struct IndexTemplate;
+pub struct IndexTemplate {
+    csrf_token: CsrfToken,
+}
 
-pub async fn index() -> actix_web::Result<TemplateResponse<IndexTemplate>> {
-    Ok(TemplateResponse::new(IndexTemplate, HttpResponse::Ok()))
+pub async fn index(csrf_token: CsrfToken) -> actix_web::Result<TemplateResponse<IndexTemplate>> {
+    Ok(TemplateResponse::new(
+        IndexTemplate { csrf_token },
+        HttpResponse::Ok(),
+    ))
 }
 
 #[derive(Template)]
@@ -49,13 +60,22 @@ pub struct DeduplicationResultTemplate {
     deduplicated: usize,
 }
 
+#[derive(Deserialize)]
+pub struct DedupForm {
+    csrf_token: String,
+    #[serde(default)]
+    keep: DedupKeep,
+}
+
 pub async fn deduplicate_images(
+    req: HttpRequest,
     pool: Data<Pool<Sqlite>>,
-    config: Data<Config>,
+    form: Form<DedupForm>,
 ) -> actix_web::Result<HttpResponse> {
+    csrf::verify(&req, &form.csrf_token)?;
     let mut connection = pool.acquire().await.map_err_to_internal()?;
     let mut transaction = connection.begin().await.map_err_to_internal()?;
-    let deduplicated = dedup_images(&mut transaction, &config.media_root)
+    let deduplicated = dedup_images(&mut transaction, form.keep)
         .await
         .map_err_to_internal()?;
     transaction.commit().await.map_err_to_internal()?;
@@ -69,12 +89,13 @@ pub async fn deduplicate_images(
 mod test {
     use actix_web::{http::StatusCode, test::TestRequest, Responder};
 
-    use crate::handlers::index::index;
+    use crate::{handlers::index::index, utils::csrf::CsrfToken};
 
     #[actix_web::test]
     async fn test_index_ok() {
         let req = TestRequest::default().to_http_request();
-        let resp = index().await.unwrap().respond_to(&req);
+        let csrf_token = CsrfToken("token".to_string());
+        let resp = index(csrf_token).await.unwrap().respond_to(&req);
         assert_eq!(resp.status(), StatusCode::OK);
     }
 }
@@ -0,0 +1,110 @@
+use std::io::ErrorKind;
+
+use actix_web::{
+    http::header::{CacheControl, CacheDirective, ContentType},
+    web::{self, Data, Redirect},
+    Either, HttpResponse,
+};
+
+use crate::{
+    config::MediaLayout, models::get_image_file_path, storage::MediaStore,
+    utils::errors::MapErrToInternal,
+};
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound()
+        .content_type(ContentType::plaintext())
+        .body("No file")
+}
+
+/// Serve image file from configured storage: redirect to presigned URL if storage supports it,
+/// otherwise proxy file contents. Other keys (staged uploads, quarantine, restore leftovers)
+/// are never served
+pub async fn get_media(
+    store: Data<dyn MediaStore>,
+    path: web::Path<(String,)>,
+) -> actix_web::Result<Either<Redirect, HttpResponse>> {
+    let (key,) = path.into_inner();
+    if !key.starts_with("images/") {
+        return Ok(Either::Right(not_found()));
+    }
+
+    if let Some(url) = store.presign(&key) {
+        return Ok(Either::Left(Redirect::to(url).temporary()));
+    }
+
+    let mut result = store.get(&key).await;
+    // File may be moved to another layout by running `migrate-layout`
+    for layout in [MediaLayout::Flat, MediaLayout::Sharded] {
+        let moved_key = get_image_file_path(layout, &key);
+        if moved_key == key {
+            continue;
+        }
+        match &result {
+            Err(e) if e.kind() == ErrorKind::NotFound => result = store.get(&moved_key).await,
+            _ => break,
+        }
+    }
+
+    let stream = match result {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::InvalidInput) => {
+            return Ok(Either::Right(not_found()))
+        }
+        Err(e) => return Err(e).map_err_to_internal()?,
+    };
+    let extension = key.rsplit_once('.').map(|(_, ext)| ext).unwrap_or_default();
+
+    Ok(Either::Right(
+        HttpResponse::Ok()
+            .content_type(actix_files::file_extension_to_mime(extension))
+            // Media files are never modified in place, new file gets new key
+            .insert_header(CacheControl(vec![
+                CacheDirective::Public,
+                CacheDirective::MaxAge(31536000),
+            ]))
+            .streaming(stream),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        web::{get, resource, Data},
+        App,
+    };
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::get_media;
+    use crate::storage::{LocalStore, MediaStore};
+
+    #[actix_web::test]
+    async fn test_only_images_served() {
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+        let source = NamedTempFile::new().unwrap();
+        for key in ["images/x.png", ".staging/x", "quarantine/x"] {
+            store.put(key, source.path()).await.unwrap();
+        }
+        let store: Arc<dyn MediaStore> = Arc::new(store);
+        let app = init_service(
+            App::new()
+                .app_data(Data::from(store))
+                .service(resource("/media/{key:.+}").route(get().to(get_media))),
+        )
+        .await;
+
+        for (uri, status) in [
+            ("/media/images/x.png", StatusCode::OK),
+            ("/media/.staging/x", StatusCode::NOT_FOUND),
+            ("/media/quarantine/x", StatusCode::NOT_FOUND),
+        ] {
+            let req = TestRequest::get().uri(uri).to_request();
+            assert_eq!(call_service(&app, req).await.status(), status, "{}", uri);
+        }
+    }
+}
@@ -1,2 +1,13 @@
+pub mod admin;
+pub mod albums;
+pub mod api;
+pub mod bulk_edit;
+pub mod export;
 pub mod images;
+pub mod inbox;
 pub mod index;
+pub mod media;
+pub mod ranking;
+pub mod saved_searches;
+pub mod tags;
+pub mod trash;
@@ -0,0 +1,100 @@
+use actix_web::{
+    web::{self, Data, Form, Redirect},
+    HttpRequest, HttpResponse, Responder,
+};
+use askama::Template;
+use serde::Deserialize;
+use sqlx::{Connection, Pool, Sqlite};
+
+use crate::{
+    models::Image,
+    ranking::{fetch_pair, record_comparison},
+    utils::{
+        csrf::{self, CsrfToken},
+        errors::MapErrToInternal,
+        render::render_html,
+        search::SearchQuery,
+    },
+};
+
+#[derive(Template)]
+#[template(path = "rank.html")]
+pub struct RankTemplate {
+    search: String,
+    search_error: Option<String>,
+    pair: Option<(Image, Image)>,
+    csrf_token: CsrfToken,
+}
+
+impl RankTemplate {
+    /// Each image of the pair with its opponent
+    fn sides(&self) -> Vec<(&Image, &Image)> {
+        match &self.pair {
+            Some((left, right)) => vec![(left, right), (right, left)],
+            None => Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RankQuery {
+    #[serde(default)]
+    search: String,
+}
+
+/// Show two found images to pick the better one
+pub async fn rank_get(
+    pool: Data<Pool<Sqlite>>,
+    query: web::Query<RankQuery>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<impl Responder> {
+    let query = query.into_inner();
+    let (pair, search_error) = match query.search.parse::<SearchQuery>() {
+        Ok(search) => {
+            let mut connection = pool.acquire().await.map_err_to_internal()?;
+            let pair = fetch_pair(&mut connection, &search)
+                .await
+                .map_err_to_internal()?;
+            (pair, None)
+        }
+        Err(error) => (None, Some(error.to_string())),
+    };
+
+    render_html(
+        RankTemplate {
+            search: query.search,
+            search_error,
+            pair,
+            csrf_token,
+        },
+        HttpResponse::Ok(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct CompareForm {
+    csrf_token: String,
+    #[serde(default)]
+    search: String,
+    winner_id: i64,
+    loser_id: i64,
+}
+
+/// Record the pick and show the next pair
+pub async fn rank_post(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    form: Form<CompareForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    record_comparison(&mut transaction, form.winner_id, form.loser_id)
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    let query = serde_urlencoded::to_string([("search", &form.search)]).map_err_to_internal()?;
+    Ok(Redirect::to(format!("/rank?{}", query)).see_other())
+}
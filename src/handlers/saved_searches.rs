@@ -0,0 +1,79 @@
+use actix_web::{
+    web::{self, Data, Form, Json, Redirect},
+    HttpRequest, Responder,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    handlers::images::CsrfForm,
+    saved_searches::{
+        delete_saved_search, fetch_saved_search_links, save_search, SavedSearchError,
+    },
+    utils::{csrf, errors::MapErrToInternal, render::error_redirect, search::SortOrder},
+};
+
+#[derive(Serialize)]
+pub struct MenuItem {
+    name: String,
+    url: String,
+}
+
+/// Saved searches shown in the navbar of every page. Searches are not run here,
+/// counts of found images are on the Albums page
+pub async fn menu(pool: Data<Pool<Sqlite>>) -> actix_web::Result<impl Responder> {
+    let items: Vec<_> = fetch_saved_search_links(pool.as_ref())
+        .await
+        .map_err_to_internal()?
+        .into_iter()
+        .map(|link| MenuItem {
+            name: link.name,
+            url: link.url,
+        })
+        .collect();
+    Ok(Json(items))
+}
+
+#[derive(Deserialize)]
+pub struct SaveSearchForm {
+    csrf_token: String,
+    name: String,
+    #[serde(default)]
+    search: String,
+    #[serde(default)]
+    sort: SortOrder,
+}
+
+/// Save search from the image list and show its results
+pub async fn save(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    form: Form<SaveSearchForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    match save_search(pool.as_ref(), &form.name, &form.search, form.sort).await {
+        Ok(_) => {
+            let query = serde_urlencoded::to_string([
+                ("search", form.search.trim()),
+                ("sort", form.sort.name()),
+            ])
+            .map_err_to_internal()?;
+            Ok(Redirect::to(format!("/images?{}", query)).see_other())
+        }
+        Err(error) => error_redirect::<SavedSearchError>("/albums#saved-searches", error),
+    }
+}
+
+pub async fn delete(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<CsrfForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (saved_search_id,) = path.into_inner();
+    delete_saved_search(pool.as_ref(), saved_search_id)
+        .await
+        .map_err_to_internal()?;
+    Ok(Redirect::to("/albums#saved-searches").see_other())
+}
@@ -0,0 +1,192 @@
+use actix_web::{
+    web::{self, Data, Form, Json, Redirect},
+    HttpRequest, HttpResponse, Responder,
+};
+use askama::Template;
+use serde::Deserialize;
+use sqlx::{Connection, Pool, Sqlite};
+
+use crate::{
+    handlers::images::BulkForm,
+    tags::{
+        add_tags, fetch_tag_names_by_prefix, fetch_tags, merge_tag, parse_names, remove_tags,
+        rename_tag, Tag, TagError,
+    },
+    utils::{
+        csrf::{self, CsrfToken},
+        errors::MapErrToInternal,
+        render::{error_redirect, render_html},
+    },
+};
+
+#[derive(Template)]
+#[template(path = "tags.html")]
+pub struct TagsTemplate<'a> {
+    tags: Vec<Tag>,
+    error_message: Option<&'a str>,
+    csrf_token: CsrfToken,
+}
+
+#[derive(Deserialize)]
+pub struct TagsQuery {
+    error_message: Option<String>,
+}
+
+pub async fn list_tags(
+    pool: Data<Pool<Sqlite>>,
+    query: web::Query<TagsQuery>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<impl Responder> {
+    let tags = fetch_tags(pool.as_ref()).await.map_err_to_internal()?;
+    render_html(
+        TagsTemplate {
+            tags,
+            error_message: query.error_message.as_deref(),
+            csrf_token,
+        },
+        HttpResponse::Ok(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct AutocompleteQuery {
+    #[serde(default)]
+    q: String,
+}
+
+/// Names of tags starting with `q`, used by tag inputs
+pub async fn autocomplete(
+    pool: Data<Pool<Sqlite>>,
+    query: web::Query<AutocompleteQuery>,
+) -> actix_web::Result<impl Responder> {
+    let names = fetch_tag_names_by_prefix(pool.as_ref(), query.q.trim(), 10)
+        .await
+        .map_err_to_internal()?;
+    Ok(Json(names))
+}
+
+#[derive(Deserialize)]
+pub struct RenameTagForm {
+    csrf_token: String,
+    name: String,
+}
+
+pub async fn rename(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<RenameTagForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (tag_id,) = path.into_inner();
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    if let Err(error) = rename_tag(&mut transaction, tag_id, &form.name).await {
+        return error_redirect::<TagError>("/tags", error);
+    }
+    transaction.commit().await.map_err_to_internal()?;
+
+    Ok(Redirect::to("/tags").see_other())
+}
+
+#[derive(Deserialize)]
+pub struct MergeTagForm {
+    csrf_token: String,
+    /// Name of tag which gets images of the merged tag
+    target: String,
+}
+
+pub async fn merge(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<MergeTagForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (tag_id,) = path.into_inner();
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    if let Err(error) = merge_tag(&mut transaction, tag_id, &form.target).await {
+        return error_redirect::<TagError>("/tags", error);
+    }
+    transaction.commit().await.map_err_to_internal()?;
+
+    Ok(Redirect::to("/tags").see_other())
+}
+
+#[derive(Deserialize)]
+pub struct ImageTagsForm {
+    csrf_token: String,
+    /// Comma separated tag names
+    tags: String,
+}
+
+pub async fn add_image_tags(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<ImageTagsForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    let names = parse_names(&form.tags).map_err(actix_web::error::ErrorBadRequest)?;
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    add_tags(&mut transaction, &[image_id], &names)
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    Ok(Redirect::to(format!("/images/{}", image_id)).see_other())
+}
+
+pub async fn remove_image_tags(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<ImageTagsForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    let names = parse_names(&form.tags).map_err(actix_web::error::ErrorBadRequest)?;
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    remove_tags(&mut transaction, &[image_id], &names)
+        .await
+        .map_err_to_internal()?;
+    transaction.commit().await.map_err_to_internal()?;
+
+    Ok(Redirect::to(format!("/images/{}", image_id)).see_other())
+}
+
+/// Add or remove (`tag_action=remove`) `tags` of selected or all found images
+pub async fn bulk_tag_images(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    form: Form<Vec<(String, String)>>,
+) -> actix_web::Result<impl Responder> {
+    let form = BulkForm::new(form);
+    form.verify_csrf(&req)?;
+    let names = parse_names(form.field("tags").unwrap_or_default())
+        .map_err(actix_web::error::ErrorBadRequest)?;
+
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let mut transaction = connection.begin().await.map_err_to_internal()?;
+    let image_ids = form.image_ids(&mut transaction).await?;
+    if form.field("tag_action") == Some("remove") {
+        remove_tags(&mut transaction, &image_ids, &names)
+            .await
+            .map_err_to_internal()?;
+    } else {
+        add_tags(&mut transaction, &image_ids, &names)
+            .await
+            .map_err_to_internal()?;
+    }
+    transaction.commit().await.map_err_to_internal()?;
+
+    form.redirect()
+}
@@ -0,0 +1,121 @@
+use actix_web::{
+    web::{self, Data, Form, Redirect},
+    HttpRequest, HttpResponse, Responder,
+};
+use askama::Template;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    config::Config,
+    handlers::images::{CsrfForm, PageQuery},
+    models::{
+        fetch_trashed_image_ids, fetch_trashed_images, fetch_trashed_images_count, purge_images,
+        restore_image, Image, Limits,
+    },
+    storage::MediaStore,
+    utils::{
+        csrf::{self, CsrfToken},
+        errors::MapErrToInternal,
+        pager,
+        render::render_html,
+    },
+};
+
+#[derive(Template)]
+#[template(path = "trash.html")]
+pub struct TrashTemplate<'a> {
+    images: &'a [Image],
+    count: u32,
+    retention_days: u32,
+    current_page: &'a u32,
+    pager: Vec<Option<u32>>,
+    csrf_token: CsrfToken,
+}
+
+const PAGE_SIZE: u32 = 18;
+
+pub async fn list_trash(
+    pool: Data<Pool<Sqlite>>,
+    config: Data<Config>,
+    page_query: web::Query<PageQuery>,
+    csrf_token: CsrfToken,
+) -> actix_web::Result<impl Responder> {
+    let mut connection = pool.acquire().await.map_err_to_internal()?;
+    let page = match page_query.page {
+        Some(n) if n >= 1 => n,
+        _ => 1,
+    };
+
+    let limits = Limits::from_page(page, PAGE_SIZE);
+    let images = fetch_trashed_images(&mut connection, &limits)
+        .await
+        .map_err_to_internal()?;
+    let count = fetch_trashed_images_count(&mut connection)
+        .await
+        .map_err_to_internal()?;
+
+    let pages = (count as f32 / PAGE_SIZE as f32).ceil() as usize;
+    let pager = pager::pager(pages as u32, page, 2, 2);
+    render_html(
+        TrashTemplate {
+            images: &images[..],
+            count,
+            retention_days: config.trash_retention_days,
+            current_page: &page,
+            pager,
+            csrf_token,
+        },
+        HttpResponse::Ok(),
+    )
+}
+
+pub async fn restore(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    path: web::Path<(i64,)>,
+    form: Form<CsrfForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    restore_image(pool.as_ref(), image_id)
+        .await
+        .map_err_to_internal()?;
+    Ok(Redirect::to(format!("/images/{}", image_id)).see_other())
+}
+
+pub async fn purge(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    store: Data<dyn MediaStore>,
+    path: web::Path<(i64,)>,
+    form: Form<CsrfForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let (image_id,) = path.into_inner();
+    // Only images in trash can be purged
+    let trashed = fetch_trashed_image_ids(pool.as_ref(), None)
+        .await
+        .map_err_to_internal()?;
+    if trashed.contains(&image_id) {
+        purge_images(&pool, store.as_ref(), &[image_id])
+            .await
+            .map_err_to_internal()?;
+    }
+    Ok(Redirect::to("/trash").see_other())
+}
+
+pub async fn empty(
+    req: HttpRequest,
+    pool: Data<Pool<Sqlite>>,
+    store: Data<dyn MediaStore>,
+    form: Form<CsrfForm>,
+) -> actix_web::Result<impl Responder> {
+    csrf::verify(&req, &form.csrf_token)?;
+    let trashed = fetch_trashed_image_ids(pool.as_ref(), None)
+        .await
+        .map_err_to_internal()?;
+    purge_images(&pool, store.as_ref(), &trashed)
+        .await
+        .map_err_to_internal()?;
+    Ok(Redirect::to("/trash").see_other())
+}
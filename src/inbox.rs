@@ -0,0 +1,175 @@
+use sqlx::{Executor, Sqlite, Transaction};
+
+use crate::{
+    albums::add_album_images,
+    models::{set_image_rating, trash_image, Image, IMAGE_COLUMNS},
+    tags::add_tags,
+};
+
+/// Decision about an inbox image, the image leaves inbox with it
+#[derive(Debug, Default)]
+pub struct Triage {
+    /// Move image to trash instead of keeping it
+    pub trash: bool,
+    pub rating: Option<i64>,
+    pub tags: Vec<String>,
+    pub album_id: Option<i64>,
+}
+
+/// The oldest inbox image with id greater than `after_id`
+pub async fn fetch_next_inbox_image(
+    executor: impl Executor<'_, Database = Sqlite>,
+    after_id: i64,
+) -> sqlx::Result<Option<Image>> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM image WHERE inbox AND deleted_at IS NULL AND id > ? ORDER BY id LIMIT 1",
+        IMAGE_COLUMNS
+    ))
+    .bind(after_id)
+    .fetch_optional(executor)
+    .await
+}
+
+pub async fn fetch_inbox_count(
+    executor: impl Executor<'_, Database = Sqlite>,
+) -> sqlx::Result<i64> {
+    sqlx::query_scalar!(
+        r#"SELECT count(*) as "count!: i64" FROM image WHERE inbox AND deleted_at IS NULL"#
+    )
+    .fetch_one(executor)
+    .await
+}
+
+/// Apply `triage` to inbox image. Returns `false` if the image is not in inbox
+pub async fn triage_image(
+    transaction: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+    triage: &Triage,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE image SET inbox = FALSE WHERE id = ? AND inbox AND deleted_at IS NULL",
+        image_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    if triage.trash {
+        trash_image(&mut *transaction, image_id).await?;
+        return Ok(true);
+    }
+    if let Some(rating) = triage.rating {
+        set_image_rating(&mut *transaction, image_id, rating).await?;
+    }
+    if !triage.tags.is_empty() {
+        add_tags(transaction, &[image_id], &triage.tags).await?;
+    }
+    if let Some(album_id) = triage.album_id {
+        add_album_images(transaction, album_id, &[image_id]).await?;
+    }
+    Ok(true)
+}
+
+/// Keep all inbox images as they are, returns number of kept images
+pub async fn keep_all(executor: impl Executor<'_, Database = Sqlite>) -> sqlx::Result<u64> {
+    let result = sqlx::query!("UPDATE image SET inbox = FALSE WHERE inbox")
+        .execute(executor)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{fetch_inbox_count, fetch_next_inbox_image, keep_all, triage_image, Triage};
+    use crate::{
+        albums::{create_album, fetch_album},
+        models::{
+            fetch_image_by_id, fetch_images_count, insert_test_image, new_test_image,
+            new_test_pool, Image,
+        },
+        tags::fetch_image_tags,
+        utils::search::SearchQuery,
+    };
+
+    #[actix_web::test]
+    async fn test_triage() {
+        let pool = new_test_pool().await;
+        let library = insert_test_image(&pool, new_test_image()).await;
+        let kept = insert_test_image(
+            &pool,
+            Image {
+                inbox: true,
+                ..new_test_image()
+            },
+        )
+        .await;
+        let trashed = insert_test_image(
+            &pool,
+            Image {
+                inbox: true,
+                ..new_test_image()
+            },
+        )
+        .await;
+        let skipped = insert_test_image(
+            &pool,
+            Image {
+                inbox: true,
+                ..new_test_image()
+            },
+        )
+        .await;
+        let album_id = create_album(&pool, "Keepers", "").await.unwrap();
+
+        assert_eq!(fetch_inbox_count(&pool).await.unwrap(), 3);
+        let search: SearchQuery = "-is:inbox".parse().unwrap();
+        assert_eq!(fetch_images_count(&pool, &search).await.unwrap(), 1);
+        let next = fetch_next_inbox_image(&pool, 0).await.unwrap().unwrap();
+        assert_eq!(next.id, kept);
+
+        let mut transaction = pool.begin().await.unwrap();
+        let triage = Triage {
+            rating: Some(4),
+            tags: vec!["hands".to_string()],
+            album_id: Some(album_id),
+            ..Default::default()
+        };
+        assert!(triage_image(&mut transaction, kept, &triage).await.unwrap());
+        let triage = Triage {
+            trash: true,
+            ..Default::default()
+        };
+        assert!(triage_image(&mut transaction, trashed, &triage)
+            .await
+            .unwrap());
+        // Images out of inbox are not triaged again
+        assert!(!triage_image(&mut transaction, library, &triage)
+            .await
+            .unwrap());
+        transaction.commit().await.unwrap();
+
+        let image = fetch_image_by_id(&pool, kept).await.unwrap().unwrap();
+        assert_eq!((image.inbox, image.rating), (false, 4));
+        assert_eq!(fetch_image_tags(&pool, kept).await.unwrap(), vec!["hands"]);
+        let album = fetch_album(&pool, album_id).await.unwrap().unwrap();
+        assert_eq!(album.image_count, 1);
+        let image = fetch_image_by_id(&pool, trashed).await.unwrap().unwrap();
+        assert!(!image.inbox && image.deleted_at.is_some());
+        let image = fetch_image_by_id(&pool, library).await.unwrap().unwrap();
+        assert!(image.deleted_at.is_none());
+
+        assert_eq!(fetch_inbox_count(&pool).await.unwrap(), 1);
+        let next = fetch_next_inbox_image(&pool, kept).await.unwrap().unwrap();
+        assert_eq!(next.id, skipped);
+        assert!(fetch_next_inbox_image(&pool, skipped)
+            .await
+            .unwrap()
+            .is_none());
+
+        assert_eq!(keep_all(&pool).await.unwrap(), 1);
+        assert_eq!(fetch_inbox_count(&pool).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,192 @@
+use sqlx::{Acquire, Pool, Sqlite};
+
+use crate::{
+    config::MediaLayout,
+    models::{fetch_image_files_after, get_image_file_path, set_image_file_path},
+    storage::MediaStore,
+};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrateLayoutSummary {
+    /// Images whose files were moved to the new location
+    pub moved: usize,
+    /// Images whose files were already at the new location (e.g. after interrupted migration)
+    pub updated: usize,
+    /// Images whose files exist at neither location, they are left as is
+    pub missing: Vec<i64>,
+}
+
+/// Move image files to locations of `layout`, updating `file_path` of images.
+///
+/// Images are processed in batches of `batch_size`: files of the batch are moved first, then
+/// their rows are updated in one transaction. A file is always moved before its row is
+/// updated, so the migration can be interrupted at any point and run again.
+pub async fn migrate_layout(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    layout: MediaLayout,
+    batch_size: i64,
+) -> anyhow::Result<MigrateLayoutSummary> {
+    let mut summary = MigrateLayoutSummary::default();
+    let mut last_id = 0;
+    loop {
+        let images = fetch_image_files_after(pool, last_id, batch_size).await?;
+        let Some(last) = images.last() else {
+            break;
+        };
+        last_id = last.id;
+
+        let mut moved = Vec::new();
+        for image in images {
+            let target = get_image_file_path(layout, &image.file_path);
+            if target == image.file_path {
+                continue;
+            }
+
+            if store.exists(&image.file_path).await? {
+                store.rename(&image.file_path, &target).await?;
+                summary.moved += 1;
+            } else if store.exists(&target).await? {
+                summary.updated += 1;
+            } else {
+                summary.missing.push(image.id);
+                continue;
+            }
+            moved.push((image.id, target));
+        }
+
+        let mut connection = pool.acquire().await?;
+        let mut transaction = connection.begin().await?;
+        for (image_id, target) in &moved {
+            set_image_file_path(&mut *transaction, *image_id, target).await?;
+        }
+        transaction.commit().await?;
+        log::info!(
+            "Migrated {} images up to id {}",
+            summary.moved + summary.updated,
+            last_id
+        );
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::{migrate_layout, MigrateLayoutSummary};
+    use crate::{
+        config::MediaLayout,
+        models::{
+            fetch_image_by_id, get_image_file_path, insert_test_image, new_test_image,
+            new_test_pool, Image,
+        },
+        storage::{LocalStore, MediaStore},
+    };
+
+    async fn file_path(pool: &Pool<Sqlite>, image_id: i64) -> String {
+        let image = fetch_image_by_id(pool, image_id).await.unwrap().unwrap();
+        image.file_path.unwrap()
+    }
+
+    #[test]
+    fn test_get_image_file_path() {
+        assert_eq!(
+            get_image_file_path(MediaLayout::Flat, "images/ab/cd/a.png"),
+            "images/a.png"
+        );
+        let sharded = get_image_file_path(MediaLayout::Sharded, "images/a.png");
+        assert!(sharded.starts_with("images/") && sharded.ends_with("/a.png"));
+        assert_eq!(sharded.split('/').count(), 4);
+        assert_eq!(get_image_file_path(MediaLayout::Sharded, &sharded), sharded);
+    }
+
+    #[actix_web::test]
+    async fn test_migrate_and_back() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+        let source = NamedTempFile::new().unwrap();
+        let mut ids = Vec::new();
+        for name in ["a", "b", "c"] {
+            let key = format!("images/{}.png", name);
+            store.put(&key, source.path()).await.unwrap();
+            ids.push(
+                insert_test_image(
+                    &pool,
+                    Image {
+                        file_path: Some(key),
+                        ..new_test_image()
+                    },
+                )
+                .await,
+            );
+        }
+        let missing_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/missing.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+
+        let summary = migrate_layout(&pool, &store, MediaLayout::Sharded, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            MigrateLayoutSummary {
+                moved: 3,
+                updated: 0,
+                missing: vec![missing_id],
+            }
+        );
+        for image_id in &ids {
+            let path = file_path(&pool, *image_id).await;
+            assert_eq!(get_image_file_path(MediaLayout::Sharded, &path), path);
+            assert!(store.exists(&path).await.unwrap());
+        }
+
+        migrate_layout(&pool, &store, MediaLayout::Flat, 2)
+            .await
+            .unwrap();
+        assert_eq!(file_path(&pool, ids[0]).await, "images/a.png");
+        assert!(store.exists("images/a.png").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_resume_after_interruption() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+        let source = NamedTempFile::new().unwrap();
+        store.put("images/a.png", source.path()).await.unwrap();
+        let image_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/a.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+
+        // Interrupted after the file was moved, but before the row was updated
+        let target = get_image_file_path(MediaLayout::Sharded, "images/a.png");
+        store.rename("images/a.png", &target).await.unwrap();
+
+        let summary = migrate_layout(&pool, &store, MediaLayout::Sharded, 10)
+            .await
+            .unwrap();
+        assert_eq!(summary.updated, 1);
+        assert_eq!(file_path(&pool, image_id).await, target);
+
+        // Nothing to do on the next run
+        let summary = migrate_layout(&pool, &store, MediaLayout::Sharded, 10)
+            .await
+            .unwrap();
+        assert_eq!(summary, MigrateLayoutSummary::default());
+    }
+}
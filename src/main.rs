@@ -1,18 +1,51 @@
+use std::sync::Arc;
+
 use actix_web::{
-    web::{get, post, resource, Data, PayloadConfig},
+    dev::Service,
+    web::{self, get, post, resource, scope, Data, PayloadConfig},
     App, HttpServer,
 };
+use clap::Parser;
+use futures_util::TryFutureExt;
+use sqlx::{Pool, Sqlite};
 use tokio::fs::create_dir_all;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
-use crate::config::Config;
+use crate::{
+    cli::{Cli, Command},
+    config::Config,
+    storage::{MediaStore, Staging},
+};
 
+mod albums;
+mod archive;
+mod backup;
+mod bulk_edit;
+mod cli;
 mod config;
+mod dump;
+mod export;
+mod fsck;
 mod handlers;
+mod inbox;
+mod layout;
+mod merge;
 mod models;
+mod ranking;
+mod revisions;
+mod saved_searches;
+mod storage;
+mod sync;
+mod tags;
+mod trash;
+mod upload;
 mod utils;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
     // Load config
     let dotenvy_result = dotenvy::dotenv();
     match dotenvy_result {
@@ -27,6 +60,7 @@ async fn main() -> anyhow::Result<()> {
 
     // Create missing folders
     create_dir_all(config.media_root.join("images")).await?;
+    let store = storage::from_config(&config)?;
 
     // Establish sqlite connection
     let pool = sqlx::sqlite::SqlitePoolOptions::new()
@@ -34,29 +68,160 @@ async fn main() -> anyhow::Result<()> {
         .await?;
     sqlx::migrate!("./migrations").run(&pool).await?;
 
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config, pool, store).await,
+        Command::Fsck(args) => cli::fsck(&pool, store.as_ref(), args).await,
+        Command::MigrateLayout(args) => {
+            cli::migrate_layout(&pool, store.as_ref(), config.media_layout, args).await
+        }
+        Command::DumpMetadata(args) => cli::dump_metadata(&pool, args).await,
+        Command::LoadMetadata(args) => cli::load_metadata(&pool, args).await,
+        Command::Backup(args) => cli::backup(&pool, store.as_ref(), args).await,
+        Command::Restore(args) => cli::restore(pool, &config, store.as_ref(), args).await,
+        Command::Merge(args) => cli::merge(&pool, &config, store.as_ref(), args).await,
+        Command::Sync(args) => cli::sync(&pool, &config, store.as_ref(), args).await,
+    }
+}
+
+async fn serve(
+    config: Config,
+    pool: Pool<Sqlite>,
+    store: Arc<dyn MediaStore>,
+) -> anyhow::Result<()> {
+    // Finish uploads interrupted by previous shutdown. Only the server does it: other
+    // commands may run next to a live server, whose staged files are not committed yet
+    let staging = Staging::new(&config.media_root);
+    let recovered = staging.recover(&pool, store.as_ref()).await?;
+    if recovered.published > 0 || recovered.removed > 0 {
+        log::info!(
+            "Published {} and removed {} staged files left by previous run",
+            recovered.published,
+            recovered.removed
+        );
+    }
+
+    trash::spawn_purge_task(pool.clone(), store.clone(), config.trash_retention_days);
+    let store = Data::from(store);
+    let staging = Data::new(staging);
     let app_config = config.clone();
     let app = HttpServer::new(move || {
         App::new()
+            .wrap_fn(|req, srv| srv.call(req).map_ok(utils::csrf::set_cookie))
             .app_data(PayloadConfig::new(1000000 * 250))
             // Files serving
-            .service(actix_files::Files::new(
-                "/media",
-                config.media_root.to_str().unwrap(),
-            ))
+            .service(resource("/media/{key:.+}").route(get().to(handlers::media::get_media)))
             .service(actix_files::Files::new("/static", "./static"))
             // Dynamic handlers
             .service(resource("/").route(get().to(handlers::index::index)))
             .service(resource("/dedup").route(post().to(handlers::index::deduplicate_images)))
+            .service(
+                resource("/admin/fsck")
+                    .route(get().to(handlers::admin::fsck_get))
+                    .route(post().to(handlers::admin::fsck_post)),
+            )
+            .service(
+                resource("/admin/backup")
+                    .route(get().to(handlers::admin::backup_get))
+                    .route(post().to(handlers::admin::backup_post)),
+            )
             .service(resource("/images").route(get().to(handlers::images::list_images)))
             .service(
                 resource("/images/upload")
                     .route(get().to(handlers::images::upload_get))
                     .route(post().to(handlers::images::upload_post)),
             )
+            .service(resource("/images/delete").route(post().to(handlers::images::delete_images)))
+            .service(resource("/images/export").route(get().to(handlers::export::export_images)))
+            .service(resource("/images/tags").route(post().to(handlers::tags::bulk_tag_images)))
+            .service(resource("/images/albums").route(post().to(handlers::albums::add_images)))
+            .service(
+                resource("/images/bulk-edit")
+                    .route(get().to(handlers::bulk_edit::bulk_edit_get))
+                    .route(post().to(handlers::bulk_edit::bulk_edit_post)),
+            )
+            .service(
+                resource("/images/bulk-edit/{id}/undo")
+                    .route(post().to(handlers::bulk_edit::undo_post)),
+            )
             .service(resource("/images/{id}").route(get().to(handlers::images::get_image)))
+            .service(
+                resource("/images/{id}/delete").route(post().to(handlers::images::delete_image)),
+            )
+            .service(resource("/images/{id}/edit").route(post().to(handlers::images::edit_image)))
+            .service(resource("/images/{id}/rating").route(post().to(handlers::images::set_rating)))
+            .service(resource("/images/{id}/note").route(post().to(handlers::images::set_note)))
+            .service(
+                resource("/images/{id}/favorite").route(post().to(handlers::images::set_favorite)),
+            )
+            .service(
+                resource("/images/{id}/revert")
+                    .route(post().to(handlers::images::revert_image_post)),
+            )
+            .service(resource("/images/{id}/tags").route(post().to(handlers::tags::add_image_tags)))
+            .service(
+                resource("/images/{id}/tags/remove")
+                    .route(post().to(handlers::tags::remove_image_tags)),
+            )
+            .service(resource("/images/{id}/albums").route(post().to(handlers::albums::add_image)))
+            .service(
+                resource("/images/{id}/albums/remove")
+                    .route(post().to(handlers::albums::remove_from_album)),
+            )
+            .service(
+                resource("/albums")
+                    .route(get().to(handlers::albums::list_albums))
+                    .route(post().to(handlers::albums::create)),
+            )
+            .service(resource("/albums/{id}").route(get().to(handlers::albums::get_album)))
+            .service(resource("/albums/{id}/edit").route(post().to(handlers::albums::edit)))
+            .service(resource("/albums/{id}/delete").route(post().to(handlers::albums::delete)))
+            .service(resource("/albums/{id}/cover").route(post().to(handlers::albums::set_cover)))
+            .service(resource("/albums/{id}/reorder").route(post().to(handlers::albums::reorder)))
+            .service(
+                resource("/albums/{id}/images/{image_id}/move")
+                    .route(post().to(handlers::albums::move_image)),
+            )
+            .service(
+                resource("/albums/{id}/images/{image_id}/remove")
+                    .route(post().to(handlers::albums::remove_image)),
+            )
+            .service(resource("/inbox").route(get().to(handlers::inbox::inbox_get)))
+            .service(resource("/inbox/keep-all").route(post().to(handlers::inbox::keep_all_post)))
+            .service(resource("/inbox/{id}").route(post().to(handlers::inbox::triage)))
+            .service(
+                resource("/rank")
+                    .route(get().to(handlers::ranking::rank_get))
+                    .route(post().to(handlers::ranking::rank_post)),
+            )
+            .service(resource("/searches").route(post().to(handlers::saved_searches::save)))
+            .service(resource("/searches/menu").route(get().to(handlers::saved_searches::menu)))
+            .service(
+                resource("/searches/{id}/delete")
+                    .route(post().to(handlers::saved_searches::delete)),
+            )
+            .service(resource("/tags").route(get().to(handlers::tags::list_tags)))
+            .service(resource("/tags/autocomplete").route(get().to(handlers::tags::autocomplete)))
+            .service(resource("/tags/{id}/rename").route(post().to(handlers::tags::rename)))
+            .service(resource("/tags/{id}/merge").route(post().to(handlers::tags::merge)))
+            .service(resource("/trash").route(get().to(handlers::trash::list_trash)))
+            .service(resource("/trash/empty").route(post().to(handlers::trash::empty)))
+            .service(resource("/trash/{id}/restore").route(post().to(handlers::trash::restore)))
+            .service(resource("/trash/{id}/purge").route(post().to(handlers::trash::purge)))
+            // JSON API
+            .service(
+                SwaggerUi::new("/api/docs/{_:.*}")
+                    .url("/api/openapi.json", handlers::api::ApiDoc::openapi()),
+            )
+            .service(
+                scope("/api/v1")
+                    .configure(handlers::api::configure)
+                    .default_service(web::to(handlers::api::not_found)),
+            )
             // Services
             .app_data(Data::new(pool.clone()))
             .app_data(Data::new(app_config.clone()))
+            .app_data(store.clone())
+            .app_data(staging.clone())
     })
     .bind((config.host, config.port))?;
     log::debug!("Running server on http://{}:{}/", config.host, config.port);
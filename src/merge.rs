@@ -0,0 +1,474 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Pool, Sqlite, SqliteConnection, Transaction,
+};
+use tempfile::NamedTempFile;
+
+use crate::{
+    albums::add_album_images,
+    archive::is_safe_path,
+    backup::{fetch_applied_migration, latest_migration},
+    config::MediaLayout,
+    dump::{
+        fetch_records, get_or_create_album, merge_curation, normalized_tags, set_ranking_and_dates,
+        Conflict, ImageRecord,
+    },
+    models::{insert_image, stage_image_file, Image, IMAGE_COLUMNS},
+    storage::{MediaStore, StagedBatch, Staging},
+    tags::add_tags,
+    utils::hash::sha256_file,
+};
+
+/// What happened to images of the other library
+#[derive(Debug, Default, PartialEq)]
+pub struct MergeReport {
+    /// Ids of copied images in the other library and here
+    pub added: Vec<(i64, i64)>,
+    /// Images already here which got tags, ratings or notes of their copies
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Ids of images in the other library which are not copied, with reasons
+    pub skipped: Vec<(i64, String)>,
+    /// Images added to albums, both copied and already present
+    pub album_images: u64,
+    /// Local values replaced by values of the other library
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Album of the other library with ids of its images in order
+struct SourceAlbum {
+    name: String,
+    description: String,
+    image_ids: Vec<i64>,
+}
+
+/// Images of the other library are merged by this many, each chunk in its own transaction
+const CHUNK_SIZE: usize = 100;
+
+/// Copy images of another sdgenbox library into this one.
+/// The other database is copied to a temporary file and migrated, so libraries of older
+/// versions are merged too; the other library itself is only read. Images are matched
+/// by file hash, then by generation parameters. Missing images are copied with their tags,
+/// curation of present images is merged like by [`crate::dump::load_records`]. Albums are
+/// matched by name, images are appended to them in their order. Trashed images are skipped.
+/// Files are hashed and staged before every chunk of images is committed in a short
+/// transaction, so the library stays usable during the merge and merging again after
+/// a failure continues where it stopped
+pub async fn merge_library(
+    pool: &Pool<Sqlite>,
+    staging: &Staging,
+    store: &dyn MediaStore,
+    layout: MediaLayout,
+    source_database: &Path,
+    source_media_root: &Path,
+) -> anyhow::Result<MergeReport> {
+    let snapshot = NamedTempFile::new()?;
+    let source = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(
+            SqliteConnectOptions::new()
+                .filename(source_database)
+                .read_only(true),
+        )
+        .await
+        .with_context(|| format!("Failed to open {}", source_database.display()))?;
+    let migration = fetch_applied_migration(&source)
+        .await
+        .context("Not an sdgenbox database")?;
+    if migration > latest_migration() {
+        bail!(
+            "The other library is made by a newer version of sdgenbox (migration {})",
+            migration
+        );
+    }
+    sqlx::query("VACUUM INTO ?")
+        .bind(snapshot.path().to_string_lossy().as_ref())
+        .execute(&source)
+        .await?;
+    source.close().await;
+
+    let source = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(SqliteConnectOptions::new().filename(snapshot.path()))
+        .await?;
+    sqlx::migrate!().run(&source).await?;
+    let records = fetch_records(&source).await?;
+    let albums = fetch_source_albums(&source).await?;
+    source.close().await;
+
+    let mut report = MergeReport::default();
+    let mut ids = HashMap::new();
+    for chunk in records.chunks(CHUNK_SIZE) {
+        let mut batch = staging.batch(store);
+        let result = async {
+            let mut prepared = Vec::with_capacity(chunk.len());
+            for record in chunk {
+                let prepared_record = prepare_record(
+                    pool,
+                    &mut batch,
+                    layout,
+                    source_media_root,
+                    record.clone(),
+                    &mut report,
+                )
+                .await
+                .with_context(|| format!("Failed to copy file of image {}", record.id))?;
+                prepared.extend(prepared_record);
+            }
+
+            let mut transaction = pool.begin().await?;
+            for record in prepared {
+                let record_id = record.record.id;
+                let image_id = merge_record(&mut transaction, &mut batch, record, &mut report)
+                    .await
+                    .with_context(|| format!("Failed to merge image {}", record_id))?;
+                if let Some(image_id) = image_id {
+                    ids.insert(record_id, image_id);
+                }
+            }
+            transaction.commit().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(error) = result {
+            batch.discard().await?;
+            return Err(error);
+        }
+        // Images are already saved, files failed to publish are retried on next server start
+        if let Err(error) = batch.publish().await {
+            log::error!("Failed to publish merged files: {}", error);
+        }
+    }
+
+    let mut transaction = pool.begin().await?;
+    for album in albums {
+        let album_id =
+            get_or_create_album(&mut transaction, &album.name, &album.description).await?;
+        let image_ids: Vec<i64> = album
+            .image_ids
+            .iter()
+            .filter_map(|image_id| ids.get(image_id).copied())
+            .collect();
+        report.album_images += add_album_images(&mut transaction, album_id, &image_ids).await?;
+    }
+    transaction.commit().await?;
+    Ok(report)
+}
+
+/// Image of the other library with its file staged if it's going to be copied
+struct PreparedRecord {
+    record: ImageRecord,
+    /// Media key of the staged file, `None` if the image is here or its file is missing
+    staged_file: Option<String>,
+}
+
+/// Hash file of the image and stage it unless the image is here, without a transaction.
+/// Returns `None` if the image is skipped
+async fn prepare_record(
+    pool: &Pool<Sqlite>,
+    batch: &mut StagedBatch<'_>,
+    layout: MediaLayout,
+    source_media_root: &Path,
+    mut record: ImageRecord,
+    report: &mut MergeReport,
+) -> anyhow::Result<Option<PreparedRecord>> {
+    if record.deleted_at.is_some() {
+        report.skipped.push((record.id, "In trash".to_owned()));
+        return Ok(None);
+    }
+    let source_file = match &record.file_path {
+        Some(file_path) if is_safe_path(file_path) => {
+            let source_file = source_media_root.join(file_path);
+            tokio::fs::try_exists(&source_file)
+                .await?
+                .then_some(source_file)
+        }
+        _ => None,
+    };
+    // Older libraries have no hashes
+    if let (None, Some(source_file)) = (&record.file_hash, &source_file) {
+        record.file_hash = Some(sha256_file(source_file).await?);
+    }
+
+    let mut connection = pool.acquire().await?;
+    let staged_file = match source_file {
+        Some(source_file)
+            if find_matching_image(&mut connection, &record)
+                .await?
+                .is_none() =>
+        {
+            Some(stage_image_file(batch, layout, &source_file).await?)
+        }
+        _ => None,
+    };
+    Ok(Some(PreparedRecord {
+        record,
+        staged_file,
+    }))
+}
+
+/// Merge one prepared image, returns id of the matching or copied image unless it's skipped
+async fn merge_record(
+    transaction: &mut Transaction<'_, Sqlite>,
+    batch: &mut StagedBatch<'_>,
+    prepared: PreparedRecord,
+    report: &mut MergeReport,
+) -> anyhow::Result<Option<i64>> {
+    let PreparedRecord {
+        record,
+        staged_file,
+    } = prepared;
+    // Matched again in the transaction, an earlier image of the chunk may have the same file
+    if let Some(image) = find_matching_image(transaction, &record).await? {
+        if let Some(staged_file) = &staged_file {
+            batch.unstage(staged_file).await?;
+        }
+        match merge_curation(transaction, &image, &record, &mut report.conflicts).await? {
+            true => report.updated += 1,
+            false => report.unchanged += 1,
+        }
+        return Ok(Some(image.id));
+    }
+    let Some(file_path) = staged_file else {
+        report
+            .skipped
+            .push((record.id, "File is missing".to_owned()));
+        return Ok(None);
+    };
+    let mut image = Image {
+        file_path: Some(file_path),
+        ..record.to_image()
+    };
+    insert_image(&mut *transaction, &mut image).await?;
+    set_ranking_and_dates(&mut *transaction, image.id, &record).await?;
+    add_tags(transaction, &[image.id], &normalized_tags(&record)?).await?;
+    report.added.push((record.id, image.id));
+    Ok(Some(image.id))
+}
+
+/// Image with the same file, or generated with the same parameters.
+/// Images not in trash are preferred
+async fn find_matching_image(
+    connection: &mut SqliteConnection,
+    record: &ImageRecord,
+) -> sqlx::Result<Option<Image>> {
+    if let Some(file_hash) = &record.file_hash {
+        let image = sqlx::query_as(&format!(
+            "SELECT {} FROM image WHERE file_hash = ?
+            ORDER BY deleted_at IS NOT NULL, id LIMIT 1",
+            IMAGE_COLUMNS
+        ))
+        .bind(file_hash)
+        .fetch_optional(&mut *connection)
+        .await?;
+        if image.is_some() {
+            return Ok(image);
+        }
+    }
+    sqlx::query_as(&format!(
+        "SELECT {} FROM image WHERE prompt = ? AND negative_prompt = ? AND seed = ?
+        AND steps = ? AND sampler = ? AND cfg_scale = ? AND width = ? AND height = ?
+        AND model_hash = ?
+        ORDER BY deleted_at IS NOT NULL, id LIMIT 1",
+        IMAGE_COLUMNS
+    ))
+    .bind(&record.prompt)
+    .bind(&record.negative_prompt)
+    .bind(record.seed)
+    .bind(record.steps)
+    .bind(&record.sampler)
+    .bind(record.cfg_scale)
+    .bind(record.width)
+    .bind(record.height)
+    .bind(&record.model_hash)
+    .fetch_optional(&mut *connection)
+    .await
+}
+
+async fn fetch_source_albums(source: &Pool<Sqlite>) -> sqlx::Result<Vec<SourceAlbum>> {
+    let rows: Vec<(i64, String, String)> =
+        sqlx::query_as("SELECT id, name, description FROM album ORDER BY id")
+            .fetch_all(source)
+            .await?;
+    let mut image_ids: HashMap<i64, Vec<i64>> = HashMap::new();
+    let album_images: Vec<(i64, i64)> =
+        sqlx::query_as("SELECT album_id, image_id FROM album_image ORDER BY album_id, position")
+            .fetch_all(source)
+            .await?;
+    for (album_id, image_id) in album_images {
+        image_ids.entry(album_id).or_default().push(image_id);
+    }
+    Ok(rows
+        .into_iter()
+        .map(|(id, name, description)| SourceAlbum {
+            name,
+            description,
+            image_ids: image_ids.remove(&id).unwrap_or_default(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{borrow::Cow, path::Path};
+
+    use sqlx::{migrate, Pool, Sqlite};
+    use tempfile::TempDir;
+
+    use super::merge_library;
+    use crate::{
+        albums::{create_album, fetch_album},
+        config::MediaLayout,
+        dump::fetch_records,
+        models::new_test_pool,
+        storage::{LocalStore, MediaStore, Staging},
+    };
+
+    async fn open_database(path: &Path) -> Pool<Sqlite> {
+        sqlx::sqlite::SqlitePoolOptions::new()
+            .connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_merge_older_library() {
+        let root = TempDir::new().unwrap();
+        // The other library has only the first migration, without hashes and albums
+        let source_database = root.path().join("source.sqlite3");
+        let source = open_database(&source_database).await;
+        let mut migrator = migrate!();
+        let first_version = migrator.migrations.iter().map(|m| m.version).min();
+        migrator.migrations = Cow::Owned(
+            migrator
+                .migrations
+                .iter()
+                .filter(|migration| Some(migration.version) == first_version)
+                .cloned()
+                .collect(),
+        );
+        migrator.run(&source).await.unwrap();
+        for (prompt, file_path) in [
+            ("cat", "images/cat.png"),
+            ("dog", "images/dog.png"),
+            ("cat again", "images/cat.png"),
+        ] {
+            sqlx::query(
+                "INSERT INTO image (prompt, negative_prompt, steps, sampler, cfg_scale, seed,
+                width, height, model_hash, model, file_path)
+                VALUES (?, '', 20, 'Euler a', 7.0, 1, 512, 512, 'hash', 'model', ?)",
+            )
+            .bind(prompt)
+            .bind(file_path)
+            .execute(&source)
+            .await
+            .unwrap();
+        }
+        source.close().await;
+        let source_media = root.path().join("source_media");
+        std::fs::create_dir_all(source_media.join("images")).unwrap();
+        std::fs::write(source_media.join("images/cat.png"), "cat").unwrap();
+
+        let media_root = root.path().join("media");
+        let pool = new_test_pool().await;
+        let store = LocalStore::new(&media_root);
+        let staging = Staging::new(&media_root);
+        let merge = || {
+            merge_library(
+                &pool,
+                &staging,
+                &store,
+                MediaLayout::Flat,
+                &source_database,
+                &source_media,
+            )
+        };
+
+        let report = merge().await.unwrap();
+        assert_eq!(report.added.len(), 1);
+        assert_eq!(report.skipped, vec![(2, "File is missing".to_string())]);
+        // The same file is copied once, the staged copy of the duplicate is removed
+        assert_eq!(report.unchanged, 1);
+        // Listing includes the staging directory
+        assert_eq!(store.list("").await.unwrap().len(), 1);
+        let records = fetch_records(&pool).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].prompt, "cat");
+        let file_path = records[0].file_path.as_deref().unwrap();
+        assert!(store.exists(file_path).await.unwrap());
+
+        // Present images are matched by hash computed from the file
+        let report = merge().await.unwrap();
+        assert_eq!((report.added.len(), report.unchanged), (0, 2));
+        assert_eq!(fetch_records(&pool).await.unwrap(), records);
+    }
+
+    #[actix_web::test]
+    async fn test_merge_albums() {
+        let root = TempDir::new().unwrap();
+        let source_database = root.path().join("source.sqlite3");
+        let source = open_database(&source_database).await;
+        migrate!().run(&source).await.unwrap();
+        let source_media = root.path().join("source_media");
+        std::fs::create_dir_all(source_media.join("images")).unwrap();
+        for (seed, rating) in [(1, 5), (2, 0)] {
+            sqlx::query(
+                "INSERT INTO image (prompt, negative_prompt, steps, sampler, cfg_scale, seed,
+                width, height, model_hash, model, file_path, file_hash, rating)
+                VALUES ('cat', '', 20, 'Euler a', 7.0, ?1, 512, 512, 'hash', 'model',
+                'images/' || ?1 || '.png', 'hash' || ?1, ?2)",
+            )
+            .bind(seed)
+            .bind(rating)
+            .execute(&source)
+            .await
+            .unwrap();
+            std::fs::write(source_media.join(format!("images/{}.png", seed)), "cat").unwrap();
+        }
+        let album_id = create_album(&source, "Cats", "All of them").await.unwrap();
+        let mut transaction = source.begin().await.unwrap();
+        crate::albums::add_album_images(&mut transaction, album_id, &[2, 1])
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+        source.close().await;
+
+        // The first image is here with another file
+        let media_root = root.path().join("media");
+        let pool = new_test_pool().await;
+        sqlx::query(
+            "INSERT INTO image (prompt, negative_prompt, steps, sampler, cfg_scale, seed,
+            width, height, model_hash, model, file_path, file_hash, rating)
+            VALUES ('cat', '', 20, 'Euler a', 7.0, 1, 512, 512, 'hash', 'model',
+            'images/local.png', 'local', 3)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let store = LocalStore::new(&media_root);
+        let staging = Staging::new(&media_root);
+        let report = merge_library(
+            &pool,
+            &staging,
+            &store,
+            MediaLayout::Flat,
+            &source_database,
+            &source_media,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.added, vec![(2, 2)]);
+        assert_eq!(report.updated, 1);
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.album_images, 2);
+        let album = fetch_album(&pool, 1).await.unwrap().unwrap();
+        assert_eq!((album.name.as_str(), album.image_count), ("Cats", 2));
+        let records = fetch_records(&pool).await.unwrap();
+        assert_eq!(records[0].rating, 5);
+        assert_eq!(records[1].albums, vec!["Cats"]);
+    }
+}
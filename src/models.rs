@@ -1,11 +1,20 @@
 use std::{
-    io::ErrorKind,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap},
+    path::Path,
 };
 
 use rand::{thread_rng, Rng};
-use sqlx::{Executor, QueryBuilder, Row, Sqlite, Transaction};
-use tokio::fs::remove_file;
+use sha2::{Digest, Sha256};
+use sqlx::{types::Json, Acquire, Executor, Pool, QueryBuilder, Row, Sqlite, Transaction};
+
+use crate::{
+    config::MediaLayout,
+    storage::{MediaStore, StagedBatch},
+    utils::{
+        hash::sha256_file,
+        search::{SearchQuery, SortOrder},
+    },
+};
 
 /// Parameters what were used to generate image
 ///
@@ -22,7 +31,9 @@ use tokio::fs::remove_file;
 /// Model: anything-v4.5-inpainting.inpainting,
 /// Conditional mask weight: 1.0,
 /// Clip skip: 2
-#[derive(Debug, PartialEq, serde::Serialize, sqlx::FromRow)]
+///
+/// Parameters without own field (like `Conditional mask weight`) are kept in `extra_params`
+#[derive(Debug, PartialEq, serde::Serialize, sqlx::FromRow, utoipa::ToSchema)]
 pub struct Image {
     pub id: i64,
     pub prompt: String,
@@ -36,26 +47,89 @@ pub struct Image {
     pub model_hash: String,
     pub model: String,
     pub clip_skip: Option<i64>,
+    #[schema(value_type = BTreeMap<String, String>)]
+    pub extra_params: Json<ExtraParams>,
     pub file_path: Option<String>,
+    pub file_hash: Option<String>,
+    pub file_missing: bool,
+    /// Stars from 0 (not rated) to 5
+    pub rating: i64,
+    pub favorite: bool,
+    /// Elo score from pairwise comparisons, see [`crate::ranking`]
+    pub score: f64,
+    pub comparisons: i64,
+    /// Markdown note, see [`crate::utils::markdown`]
+    pub note: String,
+    /// Newly imported image which is not triaged yet, see [`crate::inbox`]
+    pub inbox: bool,
     pub created_at: chrono::NaiveDateTime,
+    /// Set when image is moved to trash
+    pub deleted_at: Option<chrono::NaiveDateTime>,
 }
 
+impl Image {
+    /// Which of 5 stars are filled
+    pub fn stars(&self) -> [bool; 5] {
+        std::array::from_fn(|i| (i as i64) < self.rating)
+    }
+}
+
+/// Infotext parameters by their names
+pub type ExtraParams = BTreeMap<String, String>;
+
+/// Insert image and stage its file. The file is moved into media storage
+/// by [`StagedBatch::publish`] after the transaction is committed.
+/// `image.file_hash` is computed unless already known
 pub async fn create_image(
     transaction: &mut Transaction<'_, Sqlite>,
     image: &mut Image,
     image_file: &Path,
-    media_root: &Path,
+    layout: MediaLayout,
+    batch: &mut StagedBatch<'_>,
 ) -> anyhow::Result<()> {
-    let file_path: PathBuf = generate_image_path();
+    if image.file_hash.is_none() {
+        image.file_hash = Some(sha256_file(image_file).await?);
+    }
+    let file_path = stage_image_file(batch, layout, image_file).await?;
+    image.file_path = Some(file_path.clone());
+    if let Err(error) = insert_image(&mut *transaction, image).await {
+        // Other images of the transaction still may be committed
+        batch.unstage(&file_path).await?;
+        return Err(error.into());
+    }
 
-    let destination_path = media_root.join(&file_path);
-    tokio::fs::copy(image_file, destination_path).await?;
+    Ok(())
+}
+
+/// Stage `image_file` under new media key, which is returned
+pub async fn stage_image_file(
+    batch: &mut StagedBatch<'_>,
+    layout: MediaLayout,
+    image_file: &Path,
+) -> anyhow::Result<String> {
+    // Never overwrite file of another image on the (unlikely) name collision
+    let mut file_path = generate_image_path(layout);
+    while batch.is_staged(&file_path) || batch.store().exists(&file_path).await? {
+        file_path = generate_image_path(layout);
+    }
+    batch.stage(&file_path, image_file).await?;
+    Ok(file_path)
+}
 
-    let file_path = file_path.to_string_lossy();
+/// Insert image row as is, referencing already stored file `image.file_path`, and record it
+/// in the change feed, all in one transaction (savepoint inside a transaction). Rating and favorite flag of other images with the same file are kept,
+/// so they survive reimport of the file
+pub async fn insert_image<'a>(
+    connection: impl Acquire<'a, Database = Sqlite>,
+    image: &mut Image,
+) -> sqlx::Result<()> {
+    let mut transaction = connection.begin().await?;
     let id = sqlx::query_scalar!(
         r#"INSERT INTO image
-         (prompt, negative_prompt, steps, sampler, cfg_scale, seed, width, height, model_hash, model, clip_skip, file_path)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        (prompt, negative_prompt, steps, sampler, cfg_scale, seed, width, height, model_hash,
+        model, clip_skip, extra_params, file_path, file_hash, file_missing, rating, favorite,
+        note, inbox)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         RETURNING id"#,
         image.prompt,
         image.negative_prompt,
@@ -68,23 +142,72 @@ pub async fn create_image(
         image.model_hash,
         image.model,
         image.clip_skip,
-        file_path,
-    ).fetch_one(&mut *transaction).await?;
+        image.extra_params,
+        image.file_path,
+        image.file_hash,
+        image.file_missing,
+        image.rating,
+        image.favorite,
+        image.note,
+        image.inbox,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
     image.id = id;
-    image.file_path = Some(file_path.to_string());
+    sqlx::query!(
+        "INSERT OR REPLACE INTO image_change (image_id, field) VALUES (?, 'image')",
+        id
+    )
+    .execute(&mut *transaction)
+    .await?;
 
-    Ok(())
+    if let Some(file_hash) = &image.file_hash {
+        let other = sqlx::query!(
+            r#"SELECT max(rating) as "rating: i64", max(favorite) as "favorite: bool"
+            FROM image WHERE file_hash = ? AND id != ?"#,
+            file_hash,
+            id
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        let rating = image.rating.max(other.rating.unwrap_or_default());
+        let favorite = image.favorite || other.favorite.unwrap_or_default();
+        if (rating, favorite) != (image.rating, image.favorite) {
+            sqlx::query!(
+                "UPDATE image SET rating = ?, favorite = ? WHERE id = ?",
+                rating,
+                favorite,
+                id
+            )
+            .execute(&mut *transaction)
+            .await?;
+            image.rating = rating;
+            image.favorite = favorite;
+        }
+    }
+
+    transaction.commit().await
 }
 
-pub fn get_image_file_path(media_root: &Path, file_path: &str) -> PathBuf {
-    media_root.join("images").join(file_path)
+/// Media key for new image file
+pub fn generate_image_path(layout: MediaLayout) -> String {
+    let file_name = format!("{:016x}.png", thread_rng().gen::<u64>());
+    get_image_file_path(layout, &file_name)
 }
 
-pub fn generate_image_path() -> PathBuf {
-    let random_file_id = format!("{:16x}", thread_rng().gen::<u64>());
-    let mut image_path = Path::new("images").join(random_file_id.as_str());
-    image_path.set_extension("png");
-    image_path
+/// Media key of image file with `file_path` (or just file name) in the `layout`.
+///
+/// Sharded layout places file into `images/ab/cd/<name>`, where `abcd` are the first
+/// hex digits of SHA-256 of the file name, so location of any file is known by its name.
+pub fn get_image_file_path(layout: MediaLayout, file_path: &str) -> String {
+    let file_name = file_path.rsplit('/').next().unwrap_or(file_path);
+    match layout {
+        MediaLayout::Flat => format!("images/{}", file_name),
+        MediaLayout::Sharded => {
+            let shard = hex::encode(Sha256::digest(file_name.as_bytes()));
+            format!("images/{}/{}/{}", &shard[0..2], &shard[2..4], file_name)
+        }
+    }
 }
 
 pub async fn fetch_image_by_id(
@@ -94,7 +217,10 @@ pub async fn fetch_image_by_id(
     sqlx::query_as!(
         Image,
         r#"SELECT id, prompt, negative_prompt, steps, sampler, cfg_scale, seed,
-        width, height, model_hash, model, clip_skip, file_path, created_at as "created_at: _"
+        width, height, model_hash, model, clip_skip, extra_params as "extra_params: _",
+        file_path, file_hash, file_missing, rating, favorite, score, comparisons, note, inbox,
+        created_at as "created_at: _",
+        deleted_at as "deleted_at: _"
         FROM image WHERE id = ?"#,
         image_id,
     )
@@ -102,9 +228,25 @@ pub async fn fetch_image_by_id(
     .await
 }
 
+/// Id of image not in trash with the file, and whether the image is missing its file
+/// (see [`Image::file_missing`]). Images with files come first
+pub async fn fetch_image_id_by_file_hash(
+    executor: impl Executor<'_, Database = Sqlite>,
+    file_hash: &str,
+) -> sqlx::Result<Option<(i64, bool)>> {
+    let row = sqlx::query!(
+        r#"SELECT id as "id!", file_missing as "file_missing!: bool" FROM image
+        WHERE file_hash = ? AND deleted_at IS NULL ORDER BY file_missing, id LIMIT 1"#,
+        file_hash
+    )
+    .fetch_optional(executor)
+    .await?;
+    Ok(row.map(|row| (row.id, row.file_missing)))
+}
+
 pub struct Limits {
-    offset: u32,
-    limit: u32,
+    pub offset: u32,
+    pub limit: u32,
 }
 
 impl Limits {
@@ -114,40 +256,42 @@ impl Limits {
             limit: page_size,
         }
     }
+
+    /// Limits of `page` (from 1), `None` if its offset doesn't fit
+    pub fn checked_from_page(page: u32, page_size: u32) -> Option<Self> {
+        Some(Limits {
+            offset: page_size.checked_mul(page.checked_sub(1)?)?,
+            limit: page_size,
+        })
+    }
+
+    /// No limits, all rows are fetched
+    pub fn all() -> Self {
+        Limits {
+            offset: 0,
+            limit: u32::MAX,
+        }
+    }
 }
 
-fn add_filter_to_query(query: &mut QueryBuilder<Sqlite>, search: &str) {
-    query
-        .push(" WHERE cast(id as text) LIKE ")
-        .push_bind(format!("%{}%", search.to_uppercase()))
-        .push(" OR upper(prompt) LIKE ")
-        .push_bind(format!("%{}%", search.to_uppercase()))
-        .push(" OR upper(negative_prompt) LIKE ")
-        .push_bind(format!("%{}%", search.to_uppercase()))
-        .push(" OR upper(sampler) LIKE ")
-        .push_bind(format!("%{}%", search.to_uppercase()))
-        .push(" OR upper(model_hash) LIKE ")
-        .push_bind(format!("%{}%", search.to_uppercase()))
-        .push(" OR upper(model) LIKE ")
-        .push_bind(format!("%{}%", search.to_uppercase()))
-        .push(" OR upper(seed) LIKE ")
-        .push_bind(format!("%{}%", search.to_uppercase()));
+/// Columns of [`Image`] for dynamic queries
+pub const IMAGE_COLUMNS: &str =
+    "id, prompt, negative_prompt, steps, sampler, cfg_scale, seed, width,
+    height, model_hash, model, clip_skip, extra_params, file_path, file_hash, file_missing,
+    rating, favorite, score, comparisons, note, inbox, created_at, deleted_at";
+
+/// Filter images not in trash and matching the search
+pub fn add_filter_to_query(query: &mut QueryBuilder<Sqlite>, search: &SearchQuery) {
+    query.push(" WHERE deleted_at IS NULL");
+    search.push_conditions(query);
 }
 
 pub async fn fetch_images_count(
     executor: impl Executor<'_, Database = Sqlite>,
-    search: Option<&str>,
+    search: &SearchQuery,
 ) -> sqlx::Result<u32> {
-    // Empty search is the same as no search
-    let search = match search {
-        Some("") => None,
-        other => other,
-    };
-
     let mut query = sqlx::QueryBuilder::new("SELECT count(*) FROM image");
-    if let Some(search) = search {
-        add_filter_to_query(&mut query, search);
-    }
+    add_filter_to_query(&mut query, search);
     let size = query.build().fetch_one(executor).await?.try_get(0)?;
 
     Ok(size)
@@ -155,24 +299,13 @@ pub async fn fetch_images_count(
 
 pub async fn fetch_images(
     executor: impl Executor<'_, Database = Sqlite>,
-    search: Option<&str>,
+    search: &SearchQuery,
+    sort: SortOrder,
     limits: &Limits,
 ) -> sqlx::Result<Vec<Image>> {
-    // Empty search is the same as no search
-    let search = match search {
-        Some("") => None,
-        other => other,
-    };
-
-    let mut images_query = sqlx::QueryBuilder::new(
-        "SELECT id, prompt, negative_prompt, steps, sampler, cfg_scale, seed, width,
-        height, model_hash, model, clip_skip, file_path, created_at
-        FROM image",
-    );
-    if let Some(search) = search {
-        add_filter_to_query(&mut images_query, search);
-    }
-    images_query.push(" ORDER BY created_at DESC");
+    let mut images_query = sqlx::QueryBuilder::new(format!("SELECT {} FROM image", IMAGE_COLUMNS));
+    add_filter_to_query(&mut images_query, search);
+    images_query.push(sort.order_by());
     images_query
         .push(" LIMIT ")
         .push_bind(limits.limit)
@@ -190,6 +323,358 @@ pub async fn fetch_images(
     Ok(images)
 }
 
+pub async fn fetch_trashed_images_count(
+    executor: impl Executor<'_, Database = Sqlite>,
+) -> sqlx::Result<u32> {
+    let count = sqlx::query_scalar!("SELECT count(*) FROM image WHERE deleted_at IS NOT NULL")
+        .fetch_one(executor)
+        .await?;
+    Ok(count as u32)
+}
+
+/// Images in trash, recently deleted first
+pub async fn fetch_trashed_images(
+    executor: impl Executor<'_, Database = Sqlite>,
+    limits: &Limits,
+) -> sqlx::Result<Vec<Image>> {
+    let images = sqlx::QueryBuilder::new(format!(
+        "SELECT {} FROM image WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC, id DESC",
+        IMAGE_COLUMNS
+    ))
+    .push(" LIMIT ")
+    .push_bind(limits.limit)
+    .push(" OFFSET ")
+    .push_bind(limits.offset)
+    .build()
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .map(|row| sqlx::FromRow::from_row(&row).unwrap())
+    .collect();
+
+    Ok(images)
+}
+
+/// Ids of images which are in trash since `deleted_before` or earlier,
+/// or of all trashed images if `deleted_before` is not set
+pub async fn fetch_trashed_image_ids(
+    executor: impl Executor<'_, Database = Sqlite>,
+    deleted_before: Option<chrono::NaiveDateTime>,
+) -> sqlx::Result<Vec<i64>> {
+    let deleted_before = deleted_before.map(|time| time.timestamp());
+    sqlx::query_scalar!(
+        r#"SELECT id as "id!" FROM image
+        WHERE deleted_at IS NOT NULL AND (? IS NULL OR deleted_at <= ?)"#,
+        deleted_before,
+        deleted_before,
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Move image to trash
+pub async fn trash_image(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE image SET deleted_at = unixepoch() WHERE id = ? AND deleted_at IS NULL",
+        image_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Move images to trash, returns number of images which were not in trash yet
+pub async fn trash_images(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_ids: &[i64],
+) -> sqlx::Result<u64> {
+    if image_ids.is_empty() {
+        return Ok(0);
+    }
+    let mut query = QueryBuilder::new(
+        "UPDATE image SET deleted_at = unixepoch() WHERE deleted_at IS NULL AND id IN (",
+    );
+    let mut ids = query.separated(", ");
+    for image_id in image_ids {
+        ids.push_bind(image_id);
+    }
+    query.push(")");
+    let result = query.build().execute(executor).await?;
+    Ok(result.rows_affected())
+}
+
+/// Move all images matching the search to trash
+pub async fn trash_found_images(
+    executor: impl Executor<'_, Database = Sqlite>,
+    search: &SearchQuery,
+) -> sqlx::Result<u64> {
+    let mut query = QueryBuilder::new("UPDATE image SET deleted_at = unixepoch()");
+    add_filter_to_query(&mut query, search);
+    let result = query.build().execute(executor).await?;
+    Ok(result.rows_affected())
+}
+
+/// Whether `search` finds the whole library (ignoring trash), which is never what deleting
+/// by search is meant to do
+pub async fn search_finds_all<'a>(
+    connection: impl Acquire<'a, Database = Sqlite>,
+    search: &SearchQuery,
+) -> sqlx::Result<bool> {
+    if search.is_empty() {
+        return Ok(true);
+    }
+    let mut connection = connection.acquire().await?;
+    let total = fetch_images_count(&mut *connection, &SearchQuery::default()).await?;
+    let found = fetch_images_count(&mut *connection, search).await?;
+    Ok(total > 0 && found == total)
+}
+
+/// Images not in trash with ids from `image_ids`, in the same order
+pub async fn fetch_images_by_ids(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_ids: &[i64],
+) -> sqlx::Result<Vec<Image>> {
+    if image_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut query = QueryBuilder::new(format!(
+        "SELECT {} FROM image WHERE deleted_at IS NULL AND id IN (",
+        IMAGE_COLUMNS
+    ));
+    let mut ids = query.separated(", ");
+    for id in image_ids {
+        ids.push_bind(id);
+    }
+    query.push(")");
+    let mut images: HashMap<i64, Image> = query
+        .build_query_as::<Image>()
+        .fetch_all(executor)
+        .await?
+        .into_iter()
+        .map(|image| (image.id, image))
+        .collect();
+    Ok(image_ids
+        .iter()
+        .filter_map(|id| images.remove(id))
+        .collect())
+}
+
+/// Ids of all images matching the search
+pub async fn fetch_image_ids(
+    executor: impl Executor<'_, Database = Sqlite>,
+    search: &SearchQuery,
+) -> sqlx::Result<Vec<i64>> {
+    let mut query = QueryBuilder::new("SELECT id FROM image");
+    add_filter_to_query(&mut query, search);
+    let ids = query
+        .build()
+        .fetch_all(executor)
+        .await?
+        .iter()
+        .map(|row| row.try_get(0))
+        .collect::<sqlx::Result<_>>()?;
+    Ok(ids)
+}
+
+/// Set stars of image, from 0 (not rated) to 5
+pub async fn set_image_rating(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    rating: i64,
+) -> sqlx::Result<bool> {
+    let rating = rating.clamp(0, 5);
+    let result = sqlx::query!("UPDATE image SET rating = ? WHERE id = ?", rating, image_id)
+        .execute(executor)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn set_image_favorite(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    favorite: bool,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE image SET favorite = ? WHERE id = ?",
+        favorite,
+        image_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn set_image_note(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    note: &str,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!("UPDATE image SET note = ? WHERE id = ?", note, image_id)
+        .execute(executor)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Take image back from trash
+pub async fn restore_image(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE image SET deleted_at = NULL WHERE id = ? AND deleted_at IS NOT NULL",
+        image_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Permanently remove images and their files. Files are removed after the rows are,
+/// so a failure can leave only orphan files (see [`crate::fsck`]), never broken images
+pub async fn purge_images(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    image_ids: &[i64],
+) -> anyhow::Result<usize> {
+    let mut connection = pool.acquire().await?;
+    let mut transaction = connection.begin().await?;
+    let mut purged = 0;
+    let mut file_paths = Vec::new();
+    for image_id in image_ids {
+        if let Some(image) = fetch_image_by_id(&mut *transaction, *image_id).await? {
+            file_paths.extend(image.file_path);
+            remove_image(&mut transaction, image.id).await?;
+            purged += 1;
+        }
+    }
+    transaction.commit().await?;
+
+    for file_path in &file_paths {
+        if let Err(error) = store.delete(file_path).await {
+            log::error!(
+                "Failed to remove file {} of purged image: {}",
+                file_path,
+                error
+            );
+        }
+    }
+    Ok(purged)
+}
+
+/// Media file referenced by image row
+#[derive(Debug, PartialEq)]
+pub struct ImageFile {
+    pub id: i64,
+    pub file_path: String,
+    pub file_hash: Option<String>,
+    pub file_missing: bool,
+}
+
+pub async fn fetch_image_files(
+    executor: impl Executor<'_, Database = Sqlite>,
+) -> sqlx::Result<Vec<ImageFile>> {
+    sqlx::query_as!(
+        ImageFile,
+        r#"SELECT id, file_path as "file_path!", file_hash, file_missing
+        FROM image WHERE file_path IS NOT NULL ORDER BY id"#
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Page of [`ImageFile`]s with id greater than `after_id`
+pub async fn fetch_image_files_after(
+    executor: impl Executor<'_, Database = Sqlite>,
+    after_id: i64,
+    limit: i64,
+) -> sqlx::Result<Vec<ImageFile>> {
+    sqlx::query_as!(
+        ImageFile,
+        r#"SELECT id, file_path as "file_path!", file_hash, file_missing
+        FROM image WHERE file_path IS NOT NULL AND id > ? ORDER BY id LIMIT ?"#,
+        after_id,
+        limit,
+    )
+    .fetch_all(executor)
+    .await
+}
+
+pub async fn set_image_file_path(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    file_path: &str,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE image SET file_path = ? WHERE id = ?",
+        file_path,
+        image_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+pub async fn image_file_path_exists(
+    executor: impl Executor<'_, Database = Sqlite>,
+    file_path: &str,
+) -> sqlx::Result<bool> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM image WHERE file_path = ?) as "exists!: bool""#,
+        file_path
+    )
+    .fetch_one(executor)
+    .await
+}
+
+pub async fn set_image_file_hash(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    file_hash: &str,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE image SET file_hash = ? WHERE id = ?",
+        file_hash,
+        image_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Reference stored file `file_path` from image which was missing its file
+pub async fn attach_image_file(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    file_path: &str,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE image SET file_path = ?, file_missing = FALSE WHERE id = ?",
+        file_path,
+        image_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+pub async fn set_image_file_missing(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    file_missing: bool,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE image SET file_missing = ? WHERE id = ?",
+        file_missing,
+        image_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
 pub async fn remove_image(
     transaction: &mut Transaction<'_, Sqlite>,
     image_id: i64,
@@ -200,83 +685,119 @@ pub async fn remove_image(
     sqlx::Result::Ok(())
 }
 
+/// Which image of a group of duplicates is kept by [`dedup_images`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupKeep {
+    #[default]
+    Newest,
+    /// The best rated image, favorites win among equally rated, then the newest
+    BestRated,
+    /// The image with the highest score from comparisons, then the newest
+    BestScored,
+}
+
+/// Move to trash all but one image of every group of images with the same parameters
 pub async fn dedup_images(
     transaction: &mut Transaction<'_, Sqlite>,
-    media_root: &Path,
+    keep: DedupKeep,
 ) -> sqlx::Result<usize> {
-    let duplicates_seeds =
-        sqlx::query_scalar!("SELECT DISTINCT seed FROM image GROUP BY prompt, negative_prompt, steps, sampler, cfg_scale, seed, width, height, model_hash, model, clip_skip HAVING count(*) > 1")
-            .fetch_all(&mut *transaction)
-            .await?;
+    let by_rating = keep == DedupKeep::BestRated;
+    let by_score = keep == DedupKeep::BestScored;
+    let duplicate_ids = sqlx::query_scalar!(
+        r#"SELECT id as "id!" FROM (
+            SELECT id, row_number() OVER (
+                PARTITION BY prompt, negative_prompt, steps, sampler, cfg_scale, seed, width,
+                    height, model_hash, model, clip_skip
+                ORDER BY CASE WHEN ?1 THEN rating ELSE 0 END DESC,
+                    CASE WHEN ?1 THEN favorite ELSE 0 END DESC,
+                    CASE WHEN ?2 THEN score ELSE 0 END DESC,
+                    created_at DESC, id DESC
+            ) AS position
+            FROM image WHERE deleted_at IS NULL
+        ) WHERE position > 1"#,
+        by_rating,
+        by_score
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
 
-    let mut deduplicated = 0;
-    for seed in duplicates_seeds {
-        let ids = sqlx::query_scalar!(
-            "SELECT id FROM image WHERE seed = ? ORDER BY created_at DESC",
-            seed
-        )
-        .fetch_all(&mut *transaction)
-        .await?;
-        for id in &ids[1..] {
-            let id = id.unwrap();
-            let image = fetch_image_by_id(&mut *transaction, id).await?;
-            if let Some(image) = image {
-                if let Some(file_path) = image.file_path {
-                    let image_path = get_image_file_path(media_root, &file_path);
-                    match remove_file(image_path).await {
-                        Ok(_) => Ok(()),
-                        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
-                        Err(e) => Err(e),
-                    }?;
-                }
-                remove_image(&mut *transaction, image.id).await?;
-                deduplicated += 1;
-            }
-        }
+    trash_images(&mut *transaction, &duplicate_ids).await?;
+
+    sqlx::Result::Ok(duplicate_ids.len())
+}
+
+/// In-memory database with all migrations applied
+#[cfg(test)]
+pub async fn new_test_pool() -> sqlx::Pool<Sqlite> {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect(":memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    pool
+}
+
+/// Image with made up parameters and no file
+#[cfg(test)]
+pub fn new_test_image() -> Image {
+    Image {
+        id: 0,
+        prompt: "prompt".to_string(),
+        negative_prompt: "negative prompt".to_string(),
+        steps: 42,
+        sampler: "sampler".to_string(),
+        cfg_scale: 4.2,
+        seed: 1234,
+        width: 400,
+        height: 600,
+        model_hash: "modelhash".to_string(),
+        model: "model".to_string(),
+        clip_skip: Some(1),
+        extra_params: Default::default(),
+        file_path: None,
+        file_hash: None,
+        file_missing: false,
+        rating: 0,
+        favorite: false,
+        score: 1500.0,
+        comparisons: 0,
+        note: String::new(),
+        inbox: false,
+        created_at: chrono::NaiveDate::from_ymd_opt(2023, 4, 24)
+            .unwrap()
+            .and_hms_opt(11, 22, 33)
+            .unwrap(),
+        deleted_at: None,
     }
+}
 
-    sqlx::Result::Ok(deduplicated)
+/// Insert `image` as is and return its id
+#[cfg(test)]
+pub async fn insert_test_image(pool: &sqlx::Pool<Sqlite>, mut image: Image) -> i64 {
+    insert_image(pool, &mut image).await.unwrap();
+    image.id
 }
 
 #[cfg(test)]
 mod test {
     use std::fs::create_dir;
 
-    use chrono::NaiveDate;
-    use sqlx::{migrate, pool::PoolConnection, Acquire, Sqlite};
+    use sqlx::{pool::PoolConnection, Acquire, Sqlite};
     use tempfile::{NamedTempFile, TempDir};
 
-    use super::{create_image, fetch_image_by_id, Image};
-
-    fn new_test_image() -> Image {
-        Image {
-            id: 0,
-            prompt: "prompt".to_string(),
-            negative_prompt: "negative prompt".to_string(),
-            steps: 42,
-            sampler: "sampler".to_string(),
-            cfg_scale: 4.2,
-            seed: 1234,
-            width: 400,
-            height: 600,
-            model_hash: "modelhash".to_string(),
-            model: "model".to_string(),
-            clip_skip: Some(1),
-            file_path: None,
-            created_at: NaiveDate::from_ymd_opt(2023, 4, 24)
-                .unwrap()
-                .and_hms_opt(11, 22, 33)
-                .unwrap(),
-        }
-    }
+    use super::{
+        create_image, dedup_images, fetch_image_by_id, fetch_images, fetch_images_count,
+        fetch_trashed_images_count, insert_image, new_test_image, new_test_pool, restore_image,
+        set_image_favorite, set_image_rating, trash_image, DedupKeep, Image, Limits,
+    };
+    use crate::config::MediaLayout;
+    use crate::storage::{LocalStore, Staging};
+    use crate::utils::search::{SearchQuery, SortOrder};
 
     async fn new_connection() -> PoolConnection<Sqlite> {
-        let pool = sqlx::sqlite::SqlitePoolOptions::new()
-            .connect(":memory:")
-            .await
-            .unwrap();
-        migrate!().run(&pool).await.unwrap();
-        pool.acquire().await.unwrap()
+        new_test_pool().await.acquire().await.unwrap()
     }
 
     fn prepare_media() -> TempDir {
@@ -291,18 +812,30 @@ mod test {
         let mut transaction = connection.begin().await.unwrap();
         let media_root = prepare_media();
 
+        let store = LocalStore::new(media_root.path());
+        let staging = Staging::new(media_root.path());
+        let mut batch = staging.batch(&store);
+
         let mut image = new_test_image();
         let original_file = NamedTempFile::new().unwrap();
         create_image(
             &mut transaction,
             &mut image,
             original_file.path(),
-            media_root.path(),
+            MediaLayout::Flat,
+            &mut batch,
         )
         .await
         .unwrap();
+        transaction.commit().await.unwrap();
+        batch.publish().await.unwrap();
 
         assert_ne!(image.id, 0);
+        // SHA-256 of empty file
+        assert_eq!(
+            image.file_hash.as_deref(),
+            Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
         let image_file = image.file_path.unwrap();
         assert!(media_root.path().join(image_file).exists());
     }
@@ -313,13 +846,18 @@ mod test {
         let mut transaction = connection.begin().await.unwrap();
         let media_root = prepare_media();
 
+        let store = LocalStore::new(media_root.path());
+        let staging = Staging::new(media_root.path());
+        let mut batch = staging.batch(&store);
+
         let mut image = new_test_image();
         let original_file = NamedTempFile::new().unwrap();
         create_image(
             &mut transaction,
             &mut image,
             original_file.path(),
-            media_root.path(),
+            MediaLayout::Flat,
+            &mut batch,
         )
         .await
         .unwrap();
@@ -336,4 +874,174 @@ mod test {
             }
         )
     }
+
+    #[actix_web::test]
+    async fn test_trash_and_restore() {
+        let mut connection = new_connection().await;
+        let mut image = new_test_image();
+        insert_image(&mut connection, &mut image).await.unwrap();
+
+        assert!(trash_image(&mut connection, image.id).await.unwrap());
+        // Already in trash
+        assert!(!trash_image(&mut connection, image.id).await.unwrap());
+        let trashed = fetch_image_by_id(&mut connection, image.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(trashed.deleted_at.is_some());
+        assert_eq!(
+            fetch_images_count(&mut connection, &SearchQuery::default())
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            fetch_images_count(&mut connection, &"prompt".parse().unwrap())
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            fetch_trashed_images_count(&mut connection).await.unwrap(),
+            1
+        );
+
+        assert!(restore_image(&mut connection, image.id).await.unwrap());
+        let images = fetch_images(
+            &mut connection,
+            &SearchQuery::default(),
+            SortOrder::default(),
+            &Limits::from_page(1, 10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].deleted_at, None);
+    }
+
+    #[actix_web::test]
+    async fn test_dedup_moves_to_trash() {
+        let mut connection = new_connection().await;
+        let mut ids = Vec::new();
+        for seed in [1, 1, 1, 2] {
+            let mut image = Image {
+                seed,
+                ..new_test_image()
+            };
+            insert_image(&mut connection, &mut image).await.unwrap();
+            ids.push(image.id);
+        }
+
+        let mut transaction = connection.begin().await.unwrap();
+        assert_eq!(
+            dedup_images(&mut transaction, DedupKeep::Newest)
+                .await
+                .unwrap(),
+            2
+        );
+        transaction.commit().await.unwrap();
+
+        // Newest of the duplicates and the unique image are kept
+        for (image_id, trashed) in ids.iter().zip([true, true, false, false]) {
+            let image = fetch_image_by_id(&mut connection, *image_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(image.deleted_at.is_some(), trashed);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_rating() {
+        let mut connection = new_connection().await;
+        let mut image = Image {
+            file_hash: Some("abc".to_string()),
+            ..new_test_image()
+        };
+        insert_image(&mut connection, &mut image).await.unwrap();
+        assert!(set_image_rating(&mut connection, image.id, 4)
+            .await
+            .unwrap());
+        assert!(set_image_favorite(&mut connection, image.id, true)
+            .await
+            .unwrap());
+
+        // Reimported file gets rating of the first copy
+        let mut copy = Image {
+            file_hash: Some("abc".to_string()),
+            ..new_test_image()
+        };
+        insert_image(&mut connection, &mut copy).await.unwrap();
+        assert_eq!((copy.rating, copy.favorite), (4, true));
+        let mut unrated = new_test_image();
+        insert_image(&mut connection, &mut unrated).await.unwrap();
+        assert_eq!((unrated.rating, unrated.favorite), (0, false));
+
+        let search: SearchQuery = "rating:>=4 is:favorite".parse().unwrap();
+        assert_eq!(
+            fetch_images_count(&mut connection, &search).await.unwrap(),
+            2
+        );
+        let images = fetch_images(
+            &mut connection,
+            &SearchQuery::default(),
+            SortOrder::Rating,
+            &Limits::all(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(images.last().unwrap().id, unrated.id);
+
+        // The best rated duplicate is kept even though it's older
+        set_image_rating(&mut connection, copy.id, 0).await.unwrap();
+        set_image_favorite(&mut connection, copy.id, false)
+            .await
+            .unwrap();
+        let mut transaction = connection.begin().await.unwrap();
+        assert_eq!(
+            dedup_images(&mut transaction, DedupKeep::BestRated)
+                .await
+                .unwrap(),
+            2
+        );
+        transaction.commit().await.unwrap();
+        let kept = fetch_image_by_id(&mut connection, image.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(kept.deleted_at, None);
+    }
+
+    #[actix_web::test]
+    async fn test_search() {
+        let mut connection = new_connection().await;
+        for (model_hash, steps) in [("aaa", 20), ("bbb", 30), ("aaa", 40)] {
+            let mut image = Image {
+                model_hash: model_hash.to_string(),
+                steps,
+                ..new_test_image()
+            };
+            insert_image(&mut connection, &mut image).await.unwrap();
+        }
+
+        for (search, count) in [
+            ("", 3),
+            ("PROMPT", 3),
+            ("model_hash:aaa", 2),
+            ("model_hash:a*", 2),
+            ("model_hash:aa", 0),
+            ("hash:aaa steps:>20", 1),
+            ("-steps:<=30", 1),
+            ("prompt:negative", 0),
+            ("negative:negative", 3),
+        ] {
+            let search: SearchQuery = search.parse().unwrap();
+            assert_eq!(
+                fetch_images_count(&mut connection, &search).await.unwrap(),
+                count,
+                "{:?}",
+                search
+            );
+        }
+    }
 }
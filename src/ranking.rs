@@ -0,0 +1,221 @@
+use rand::{seq::SliceRandom, thread_rng, Rng};
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection, Transaction};
+
+use crate::{
+    models::{add_filter_to_query, Image, IMAGE_COLUMNS},
+    utils::search::SearchQuery,
+};
+
+/// How much one comparison can change score
+const K_FACTOR: f64 = 32.0;
+/// Opponent is picked randomly among this many best matching images
+const OPPONENT_CANDIDATES: i64 = 5;
+/// Difference of scores which counts as much as one more comparison when picking opponent
+const SCORE_PER_COMPARISON: f64 = 100.0;
+
+/// Probability that image with `score` wins against image with `opponent_score`
+pub fn expected_score(score: f64, opponent_score: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent_score - score) / 400.0))
+}
+
+/// New scores of winner and loser
+pub fn updated_scores(winner_score: f64, loser_score: f64) -> (f64, f64) {
+    let change = K_FACTOR * (1.0 - expected_score(winner_score, loser_score));
+    (winner_score + change, loser_score - change)
+}
+
+async fn fetch_found_images(
+    connection: &mut SqliteConnection,
+    search: &SearchQuery,
+    query: impl FnOnce(&mut QueryBuilder<'_, Sqlite>),
+) -> sqlx::Result<Vec<Image>> {
+    let mut images_query = QueryBuilder::new(format!("SELECT {} FROM image", IMAGE_COLUMNS));
+    add_filter_to_query(&mut images_query, search);
+    query(&mut images_query);
+    let images = images_query
+        .build()
+        .fetch_all(connection)
+        .await?
+        .into_iter()
+        .map(|row| sqlx::FromRow::from_row(&row).unwrap())
+        .collect();
+    Ok(images)
+}
+
+/// Two found images to compare, `None` if less than two images are found.
+///
+/// The first image is one of the least compared, so every image gets compared. Its opponent
+/// has few comparisons and close score, because such comparison tells the most.
+pub async fn fetch_pair(
+    connection: &mut SqliteConnection,
+    search: &SearchQuery,
+) -> sqlx::Result<Option<(Image, Image)>> {
+    let Some(first) = fetch_found_images(&mut *connection, search, |query| {
+        query.push(" ORDER BY comparisons, random() LIMIT 1");
+    })
+    .await?
+    .pop() else {
+        return Ok(None);
+    };
+
+    let mut candidates = fetch_found_images(&mut *connection, search, |query| {
+        query
+            .push(" AND id != ")
+            .push_bind(first.id)
+            .push(" ORDER BY comparisons + abs(score - ")
+            .push_bind(first.score)
+            .push(") / ")
+            .push_bind(SCORE_PER_COMPARISON)
+            .push(", random() LIMIT ")
+            .push_bind(OPPONENT_CANDIDATES);
+    })
+    .await?;
+    let mut rng = thread_rng();
+    candidates.shuffle(&mut rng);
+    let Some(second) = candidates.pop() else {
+        return Ok(None);
+    };
+
+    // Don't let the less compared image always be on the left
+    if rng.gen() {
+        Ok(Some((first, second)))
+    } else {
+        Ok(Some((second, first)))
+    }
+}
+
+/// Record that `winner_id` is better than `loser_id` and update their scores.
+/// Returns new scores of winner and loser or `None` if any of images doesn't exist
+pub async fn record_comparison(
+    transaction: &mut Transaction<'_, Sqlite>,
+    winner_id: i64,
+    loser_id: i64,
+) -> sqlx::Result<Option<(f64, f64)>> {
+    if winner_id == loser_id {
+        return Ok(None);
+    }
+    let winner_score = sqlx::query_scalar!("SELECT score FROM image WHERE id = ?", winner_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    let loser_score = sqlx::query_scalar!("SELECT score FROM image WHERE id = ?", loser_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    let (Some(winner_score), Some(loser_score)) = (winner_score, loser_score) else {
+        return Ok(None);
+    };
+
+    let (winner_score, loser_score) = updated_scores(winner_score, loser_score);
+    for (image_id, score) in [(winner_id, winner_score), (loser_id, loser_score)] {
+        sqlx::query!(
+            "UPDATE image SET score = ?, comparisons = comparisons + 1 WHERE id = ?",
+            score,
+            image_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    sqlx::query!(
+        "INSERT INTO comparison (winner_id, loser_id) VALUES (?, ?)",
+        winner_id,
+        loser_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(Some((winner_score, loser_score)))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{expected_score, fetch_pair, record_comparison, updated_scores};
+    use crate::{
+        models::{
+            fetch_image_by_id, fetch_images_count, insert_test_image, new_test_image,
+            new_test_pool, Image,
+        },
+        utils::search::SearchQuery,
+    };
+
+    #[test]
+    fn test_scores() {
+        assert_eq!(expected_score(1500.0, 1500.0), 0.5);
+        assert_eq!(updated_scores(1500.0, 1500.0), (1516.0, 1484.0));
+        // Expected win changes scores less than upset
+        let (winner, _) = updated_scores(1700.0, 1500.0);
+        let (upset, _) = updated_scores(1500.0, 1700.0);
+        assert!(winner - 1700.0 < upset - 1500.0);
+    }
+
+    #[actix_web::test]
+    async fn test_compare() {
+        let pool = new_test_pool().await;
+        let first = insert_test_image(
+            &pool,
+            Image {
+                seed: 1,
+                ..new_test_image()
+            },
+        )
+        .await;
+        let second = insert_test_image(
+            &pool,
+            Image {
+                seed: 1,
+                ..new_test_image()
+            },
+        )
+        .await;
+        let other = insert_test_image(
+            &pool,
+            Image {
+                seed: 2,
+                ..new_test_image()
+            },
+        )
+        .await;
+
+        let mut transaction = pool.begin().await.unwrap();
+        let scores = record_comparison(&mut transaction, second, first)
+            .await
+            .unwrap();
+        assert_eq!(scores, Some((1516.0, 1484.0)));
+        assert_eq!(
+            record_comparison(&mut transaction, second, 100)
+                .await
+                .unwrap(),
+            None
+        );
+        transaction.commit().await.unwrap();
+        let winner = fetch_image_by_id(&pool, second).await.unwrap().unwrap();
+        assert_eq!((winner.score, winner.comparisons), (1516.0, 1));
+
+        // Never compared image is always in the pair
+        let mut connection = pool.acquire().await.unwrap();
+        for _ in 0..5 {
+            let (left, right) = fetch_pair(&mut connection, &SearchQuery::default())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_ne!(left.id, right.id);
+            assert!(left.id == other || right.id == other);
+        }
+        let search: SearchQuery = "seed:2".parse().unwrap();
+        assert!(fetch_pair(&mut connection, &search)
+            .await
+            .unwrap()
+            .is_none());
+
+        // The winner is the best of its duplicates
+        let search: SearchQuery = "is:best".parse().unwrap();
+        assert_eq!(
+            fetch_images_count(&mut connection, &search).await.unwrap(),
+            2
+        );
+        let search: SearchQuery = "is:best seed:1".parse().unwrap();
+        let best = super::fetch_found_images(&mut connection, &search, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(best[0].id, second);
+    }
+}
@@ -0,0 +1,295 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{types::Json, Executor, Sqlite, Transaction};
+
+use crate::models::{fetch_image_by_id, ExtraParams, Image};
+
+/// Generation parameters of image which can be edited
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageParams {
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub steps: i64,
+    pub sampler: String,
+    pub cfg_scale: f64,
+    pub seed: i64,
+    pub width: i64,
+    pub height: i64,
+    pub model_hash: String,
+    pub model: String,
+    pub clip_skip: Option<i64>,
+    pub extra_params: ExtraParams,
+}
+
+impl From<&Image> for ImageParams {
+    fn from(image: &Image) -> Self {
+        ImageParams {
+            prompt: image.prompt.clone(),
+            negative_prompt: image.negative_prompt.clone(),
+            steps: image.steps,
+            sampler: image.sampler.clone(),
+            cfg_scale: image.cfg_scale,
+            seed: image.seed,
+            width: image.width,
+            height: image.height,
+            model_hash: image.model_hash.clone(),
+            model: image.model.clone(),
+            clip_skip: image.clip_skip,
+            extra_params: image.extra_params.0.clone(),
+        }
+    }
+}
+
+impl ImageParams {
+    pub fn to_map(&self) -> Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map,
+            _ => unreachable!("ImageParams is serialized to object"),
+        }
+    }
+}
+
+/// One edit of image metadata, values are objects with changed fields of [`ImageParams`]
+#[derive(Debug, PartialEq, Serialize, sqlx::FromRow)]
+pub struct ImageRevision {
+    pub id: i64,
+    pub image_id: i64,
+    pub old_values: Json<Map<String, Value>>,
+    pub new_values: Json<Map<String, Value>>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// Change of one field for display
+pub struct FieldChange {
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+impl ImageRevision {
+    pub fn changes(&self) -> Vec<FieldChange> {
+        field_changes(&self.old_values, &self.new_values)
+    }
+}
+
+pub fn field_changes(
+    old_values: &Map<String, Value>,
+    new_values: &Map<String, Value>,
+) -> Vec<FieldChange> {
+    new_values
+        .iter()
+        .map(|(field, new_value)| FieldChange {
+            field: field.clone(),
+            old_value: display_value(old_values.get(field).unwrap_or(&Value::Null)),
+            new_value: display_value(new_value),
+        })
+        .collect()
+}
+
+/// Old and new values of fields which differ
+pub fn diff(old: &ImageParams, new: &ImageParams) -> (Map<String, Value>, Map<String, Value>) {
+    let old_map = old.to_map();
+    let mut old_values = Map::new();
+    let mut new_values = Map::new();
+    for (field, new_value) in new.to_map() {
+        let old_value = old_map.get(&field).cloned().unwrap_or(Value::Null);
+        if old_value != new_value {
+            old_values.insert(field.clone(), old_value);
+            new_values.insert(field, new_value);
+        }
+    }
+    (old_values, new_values)
+}
+
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(value) => value.clone(),
+        Value::Object(params) => params
+            .iter()
+            .map(|(key, value)| format!("{}: {}", key, display_value(value)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        value => value.to_string(),
+    }
+}
+
+/// Set parameters of image and record the revision, which is a part of bulk edit if
+/// `bulk_edit_id` is set, and the change of `image` field synced to other instances. Returns id of the revision or `None` if the image doesn't exist
+/// or nothing is changed
+pub async fn update_image_params(
+    transaction: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+    params: &ImageParams,
+    bulk_edit_id: Option<i64>,
+) -> anyhow::Result<Option<i64>> {
+    let Some(image) = fetch_image_by_id(&mut *transaction, image_id).await? else {
+        return Ok(None);
+    };
+    let (old_values, new_values) = diff(&ImageParams::from(&image), params);
+    if new_values.is_empty() {
+        return Ok(None);
+    }
+
+    let extra_params = Json(&params.extra_params);
+    sqlx::query!(
+        "UPDATE image SET prompt = ?, negative_prompt = ?, steps = ?, sampler = ?, cfg_scale = ?,
+        seed = ?, width = ?, height = ?, model_hash = ?, model = ?, clip_skip = ?, extra_params = ?
+        WHERE id = ?",
+        params.prompt,
+        params.negative_prompt,
+        params.steps,
+        params.sampler,
+        params.cfg_scale,
+        params.seed,
+        params.width,
+        params.height,
+        params.model_hash,
+        params.model,
+        params.clip_skip,
+        extra_params,
+        image_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        "INSERT OR REPLACE INTO image_change (image_id, field) VALUES (?, 'image')",
+        image_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let old_values = Json(old_values);
+    let new_values = Json(new_values);
+    let revision_id = sqlx::query_scalar!(
+        "INSERT INTO image_revision (image_id, old_values, new_values, bulk_edit_id)
+        VALUES (?, ?, ?, ?)
+        RETURNING id",
+        image_id,
+        old_values,
+        new_values,
+        bulk_edit_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    Ok(Some(revision_id))
+}
+
+/// Revisions of image, newest first
+pub async fn fetch_image_revisions(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+) -> sqlx::Result<Vec<ImageRevision>> {
+    sqlx::query_as!(
+        ImageRevision,
+        r#"SELECT id as "id!", image_id, old_values as "old_values: _",
+        new_values as "new_values: _",
+        created_at as "created_at: _"
+        FROM image_revision WHERE image_id = ? ORDER BY id DESC"#,
+        image_id,
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Return image to the state right after revision `revision_id` by undoing all later revisions,
+/// `revision_id` 0 returns the image to the state before the first edit.
+/// Reverting is recorded as a new revision, so it can be reverted too.
+pub async fn revert_image(
+    transaction: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+    revision_id: i64,
+) -> anyhow::Result<Option<i64>> {
+    let Some(image) = fetch_image_by_id(&mut *transaction, image_id).await? else {
+        return Ok(None);
+    };
+    let mut values = ImageParams::from(&image).to_map();
+    for revision in fetch_image_revisions(&mut *transaction, image_id).await? {
+        if revision.id <= revision_id {
+            break;
+        }
+        values.extend(revision.old_values.0);
+    }
+    let params: ImageParams = serde_json::from_value(Value::Object(values))?;
+
+    update_image_params(transaction, image_id, &params, None).await
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::Acquire;
+
+    use super::{fetch_image_revisions, revert_image, update_image_params, ImageParams};
+    use crate::models::{fetch_image_by_id, insert_test_image, new_test_image, new_test_pool};
+
+    #[actix_web::test]
+    async fn test_edit_and_revert() {
+        let pool = new_test_pool().await;
+        let image_id = insert_test_image(&pool, new_test_image()).await;
+        let original = ImageParams::from(&new_test_image());
+        let mut connection = pool.acquire().await.unwrap();
+        let mut transaction = connection.begin().await.unwrap();
+
+        let mut renamed = original.clone();
+        renamed.model = "renamed".to_string();
+        let first = update_image_params(&mut transaction, image_id, &renamed, None)
+            .await
+            .unwrap()
+            .unwrap();
+        // Nothing changed, no revision
+        assert_eq!(
+            update_image_params(&mut transaction, image_id, &renamed, None)
+                .await
+                .unwrap(),
+            None
+        );
+        let mut rehashed = renamed.clone();
+        rehashed.model_hash = "fixed".to_string();
+        rehashed
+            .extra_params
+            .insert("Version".to_string(), "v1".to_string());
+        update_image_params(&mut transaction, image_id, &rehashed, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let revisions = fetch_image_revisions(&mut transaction, image_id)
+            .await
+            .unwrap();
+        assert_eq!(revisions.len(), 2);
+        let changes = revisions[0].changes();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].field, "extra_params");
+        assert_eq!(changes[0].new_value, "Version: v1");
+        assert_eq!(changes[1].old_value, "modelhash");
+        assert_eq!(changes[1].new_value, "fixed");
+
+        revert_image(&mut transaction, image_id, first)
+            .await
+            .unwrap()
+            .unwrap();
+        let reverted = fetch_image_by_id(&mut transaction, image_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ImageParams::from(&reverted), renamed);
+
+        revert_image(&mut transaction, image_id, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let reverted = fetch_image_by_id(&mut transaction, image_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ImageParams::from(&reverted), original);
+        assert_eq!(
+            fetch_image_revisions(&mut transaction, image_id)
+                .await
+                .unwrap()
+                .len(),
+            4
+        );
+    }
+}
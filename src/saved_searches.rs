@@ -0,0 +1,239 @@
+use sqlx::{Executor, Sqlite, SqliteConnection};
+
+use crate::{
+    models::{fetch_images, fetch_images_count, Limits},
+    utils::search::{SearchError, SearchQuery, SortOrder},
+};
+
+/// Named search of `/images`, shown as an album which contents follow the search
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct SavedSearch {
+    pub id: i64,
+    pub name: String,
+    /// Search box query
+    pub query: String,
+    pub sort: SortOrder,
+    /// Number of found images, `None` if the query is no longer valid
+    pub image_count: Option<u32>,
+    /// File of the first found image
+    pub cover_file_path: Option<String>,
+}
+
+impl SavedSearch {
+    /// Search results page
+    pub fn url(&self) -> String {
+        search_url(&self.query, self.sort)
+    }
+}
+
+/// Saved search without counts, cheap to fetch on every page
+#[derive(Debug, PartialEq)]
+pub struct SavedSearchLink {
+    pub name: String,
+    /// Search results page
+    pub url: String,
+}
+
+fn search_url(query: &str, sort: SortOrder) -> String {
+    let query =
+        serde_urlencoded::to_string([("search", query), ("sort", sort.name())]).unwrap_or_default();
+    format!("/images?{}", query)
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum SavedSearchError {
+    #[error("Saved search name is empty")]
+    EmptyName,
+    #[error("Invalid search: {0}")]
+    InvalidQuery(SearchError),
+}
+
+/// All saved searches by name with live counts of found images
+pub async fn fetch_saved_searches(
+    connection: &mut SqliteConnection,
+) -> sqlx::Result<Vec<SavedSearch>> {
+    let rows =
+        sqlx::query!(r#"SELECT id as "id!", name, query, sort FROM saved_search ORDER BY name"#)
+            .fetch_all(&mut *connection)
+            .await?;
+
+    let mut saved_searches = Vec::with_capacity(rows.len());
+    for row in rows {
+        let sort = SortOrder::from_name(&row.sort).unwrap_or_default();
+        let (image_count, cover_file_path) = match row.query.parse::<SearchQuery>() {
+            Ok(search) => {
+                let count = fetch_images_count(&mut *connection, &search).await?;
+                let first = fetch_images(&mut *connection, &search, sort, &Limits::from_page(1, 1))
+                    .await?
+                    .pop();
+                (Some(count), first.and_then(|image| image.file_path))
+            }
+            Err(_) => (None, None),
+        };
+        saved_searches.push(SavedSearch {
+            id: row.id,
+            name: row.name,
+            query: row.query,
+            sort,
+            image_count,
+            cover_file_path,
+        });
+    }
+    Ok(saved_searches)
+}
+
+/// All saved searches by name, without running them
+pub async fn fetch_saved_search_links(
+    executor: impl Executor<'_, Database = Sqlite>,
+) -> sqlx::Result<Vec<SavedSearchLink>> {
+    let rows = sqlx::query!("SELECT name, query, sort FROM saved_search ORDER BY name")
+        .fetch_all(executor)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| SavedSearchLink {
+            url: search_url(
+                &row.query,
+                SortOrder::from_name(&row.sort).unwrap_or_default(),
+            ),
+            name: row.name,
+        })
+        .collect())
+}
+
+/// Save search under `name`, replacing the search saved under the same name
+pub async fn save_search(
+    executor: impl Executor<'_, Database = Sqlite>,
+    name: &str,
+    query: &str,
+    sort: SortOrder,
+) -> anyhow::Result<i64> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(SavedSearchError::EmptyName.into());
+    }
+    let query = query.trim();
+    query
+        .parse::<SearchQuery>()
+        .map_err(SavedSearchError::InvalidQuery)?;
+
+    let sort = sort.name();
+    let id = sqlx::query_scalar!(
+        r#"INSERT INTO saved_search (name, query, sort) VALUES (?, ?, ?)
+        ON CONFLICT (name) DO UPDATE SET query = excluded.query, sort = excluded.sort
+        RETURNING id as "id!""#,
+        name,
+        query,
+        sort
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(id)
+}
+
+pub async fn delete_saved_search(
+    executor: impl Executor<'_, Database = Sqlite>,
+    saved_search_id: i64,
+) -> sqlx::Result<()> {
+    sqlx::query!("DELETE FROM saved_search WHERE id = ?", saved_search_id)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{
+        delete_saved_search, fetch_saved_search_links, fetch_saved_searches, save_search,
+        SavedSearchError, SavedSearchLink,
+    };
+    use crate::{
+        models::{insert_test_image, new_test_image, new_test_pool, Image},
+        utils::search::SortOrder,
+    };
+
+    #[actix_web::test]
+    async fn test_saved_searches() {
+        let pool = new_test_pool().await;
+        insert_test_image(
+            &pool,
+            Image {
+                cfg_scale: 5.0,
+                file_path: Some("a.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let id = save_search(&pool, " low cfg ", "cfg:<6", SortOrder::Oldest)
+            .await
+            .unwrap();
+        let error = save_search(&pool, "bad", "steps:many", SortOrder::Newest)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(SavedSearchError::InvalidQuery(_))
+        ));
+        assert!(save_search(&pool, " ", "", SortOrder::Newest)
+            .await
+            .is_err());
+
+        let mut connection = pool.acquire().await.unwrap();
+        let saved = fetch_saved_searches(&mut connection).await.unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].name, "low cfg");
+        assert_eq!(saved[0].image_count, Some(1));
+        assert_eq!(saved[0].url(), "/images?search=cfg%3A%3C6&sort=oldest");
+        let links = fetch_saved_search_links(&mut connection).await.unwrap();
+        assert_eq!(
+            links,
+            vec![SavedSearchLink {
+                name: "low cfg".to_string(),
+                url: saved[0].url(),
+            }]
+        );
+        drop(connection);
+
+        // Contents follow new images
+        insert_test_image(
+            &pool,
+            Image {
+                cfg_scale: 4.0,
+                file_path: Some("b.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        insert_test_image(
+            &pool,
+            Image {
+                cfg_scale: 7.0,
+                file_path: Some("c.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let mut connection = pool.acquire().await.unwrap();
+        let saved = fetch_saved_searches(&mut connection).await.unwrap();
+        assert_eq!(saved[0].image_count, Some(2));
+        assert_eq!(saved[0].cover_file_path.as_deref(), Some("a.png"));
+        drop(connection);
+
+        // Saving under the same name replaces the search
+        let same_id = save_search(&pool, "low cfg", "cfg:<5", SortOrder::Newest)
+            .await
+            .unwrap();
+        assert_eq!(same_id, id);
+        let mut connection = pool.acquire().await.unwrap();
+        let saved = fetch_saved_searches(&mut connection).await.unwrap();
+        assert_eq!(saved[0].image_count, Some(1));
+        assert_eq!(saved[0].cover_file_path.as_deref(), Some("b.png"));
+
+        delete_saved_search(&mut connection, id).await.unwrap();
+        assert!(fetch_saved_searches(&mut connection)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
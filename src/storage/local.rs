@@ -0,0 +1,195 @@
+use std::{
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+};
+
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+use super::{ByteStream, MediaStore};
+
+/// Media store keeping files in a directory on local filesystem
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &Path) -> Self {
+        LocalStore {
+            root: root.to_path_buf(),
+        }
+    }
+
+    /// Path of the file with `key` on filesystem.
+    /// Keys escaping the root (absolute or containing `..`) are rejected
+    pub fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let key = Path::new(key);
+        if !key.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid media key: {}", key.display()),
+            ));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl MediaStore for LocalStore {
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+        let destination = self.path(key)?;
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::copy(source, destination).await?;
+        Ok(())
+    }
+
+    /// Atomic rename, `source` must be on the same filesystem (see [`super::Staging`])
+    async fn publish(&self, key: &str, source: &Path) -> io::Result<()> {
+        let destination = self.path(key)?;
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(source, destination).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<ByteStream> {
+        let file = fs::File::open(self.path(key)?).await?;
+        Ok(Box::pin(ReaderStream::new(file)))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        fs::try_exists(self.path(key)?).await
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        // Walk only the directory containing the prefix, then filter by the rest of it
+        let directory = match prefix.rsplit_once('/') {
+            Some((directory, _)) => self.path(directory)?,
+            None => self.root.clone(),
+        };
+
+        let mut keys = Vec::new();
+        let mut directories = vec![directory];
+        while let Some(directory) = directories.pop() {
+            let mut entries = match fs::read_dir(&directory).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                let key = path
+                    .strip_prefix(&self.root)
+                    .map_err(io::Error::other)?
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if key.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let destination = self.path(to)?;
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(self.path(from)?, destination).await
+    }
+
+    fn presign(&self, _key: &str) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Write};
+
+    use futures_util::TryStreamExt;
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::LocalStore;
+    use crate::storage::MediaStore;
+
+    #[actix_web::test]
+    async fn test_put_get_delete() {
+        let root = TempDir::new().unwrap();
+        let store = LocalStore::new(root.path());
+        let mut source = NamedTempFile::new().unwrap();
+        source.write_all(b"content").unwrap();
+
+        store.put("images/a.png", source.path()).await.unwrap();
+        assert!(store.exists("images/a.png").await.unwrap());
+        let chunks: Vec<_> = store
+            .get("images/a.png")
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"content");
+
+        store.delete("images/a.png").await.unwrap();
+        assert!(!store.exists("images/a.png").await.unwrap());
+        // Removing twice is fine
+        store.delete("images/a.png").await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_list_and_rename() {
+        let root = TempDir::new().unwrap();
+        let store = LocalStore::new(root.path());
+        let source = NamedTempFile::new().unwrap();
+        store.put("images/a.png", source.path()).await.unwrap();
+        store.put("images/ab/b.png", source.path()).await.unwrap();
+        store.put("other/c.png", source.path()).await.unwrap();
+
+        assert_eq!(
+            store.list("images/").await.unwrap(),
+            vec!["images/a.png", "images/ab/b.png"]
+        );
+        assert_eq!(
+            store.list("images/ab/").await.unwrap(),
+            vec!["images/ab/b.png"]
+        );
+        assert!(store.list("missing/").await.unwrap().is_empty());
+
+        store
+            .rename("images/a.png", "quarantine/images/a.png")
+            .await
+            .unwrap();
+        assert!(!store.exists("images/a.png").await.unwrap());
+        assert!(store.exists("quarantine/images/a.png").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_escaping_keys_rejected() {
+        let root = TempDir::new().unwrap();
+        let store = LocalStore::new(root.path());
+
+        for key in ["../secret", "/etc/passwd", "images/../../secret"] {
+            let error = store.exists(key).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::{io, path::Path, pin::Pin, sync::Arc};
+
+use bytes::Bytes;
+use futures_util::Stream;
+
+use crate::config::{Config, StorageBackend};
+
+pub mod local;
+pub mod s3;
+pub mod staging;
+
+pub use local::LocalStore;
+pub use s3::S3Store;
+pub use staging::{StagedBatch, Staging};
+
+/// Stream of file contents returned by [`MediaStore::get`]
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Storage of media files (images etc.)
+///
+/// Files are addressed by keys, which are the relative paths stored in the database,
+/// e.g. `images/0123456789abcdef.png`.
+#[async_trait::async_trait]
+pub trait MediaStore: Send + Sync {
+    /// Upload local file `source` under `key`, replacing existing file if any
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()>;
+
+    /// Move local file `source` into storage under `key`.
+    /// `source` is removed only if the file was stored successfully
+    async fn publish(&self, key: &str, source: &Path) -> io::Result<()> {
+        self.put(key, source).await?;
+        tokio::fs::remove_file(source).await
+    }
+
+    /// Stream contents of the file. Fails with [`io::ErrorKind::NotFound`] if there is no such file
+    async fn get(&self, key: &str) -> io::Result<ByteStream>;
+
+    /// Remove the file. Removing missing file is not an error
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// Keys of all files starting with `prefix`
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Move file to another key, replacing existing file if any
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// URL which client can use to download the file directly from storage.
+    /// `None` means the file must be proxied through sdgenbox (see [`MediaStore::get`])
+    fn presign(&self, key: &str) -> Option<String>;
+}
+
+/// Build media store configured by `config`
+pub fn from_config(config: &Config) -> anyhow::Result<Arc<dyn MediaStore>> {
+    let store: Arc<dyn MediaStore> = match config.storage {
+        StorageBackend::Local => Arc::new(LocalStore::new(&config.media_root)),
+        StorageBackend::S3 => Arc::new(S3Store::from_config(config)?),
+    };
+    Ok(store)
+}
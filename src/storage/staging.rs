@@ -0,0 +1,421 @@
+use std::{io, path::Path};
+
+use sqlx::{Pool, Sqlite};
+
+use super::{LocalStore, MediaStore};
+use crate::models::image_file_path_exists;
+
+/// Directory inside media root where uploads wait for their database transaction
+pub const STAGING_DIR: &str = ".staging";
+
+/// Staging area for new media files
+///
+/// Files are copied to the staging directory under their final key before the database
+/// transaction referencing them is committed, and are moved into media storage only after
+/// the commit (see [`StagedBatch`]). The directory is on the same filesystem as media root,
+/// so with local storage the move is an atomic rename.
+pub struct Staging {
+    files: LocalStore,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RecoverSummary {
+    /// Files of committed images which were not moved into storage
+    pub published: usize,
+    /// Files of rolled back transactions
+    pub removed: usize,
+}
+
+impl Staging {
+    pub fn new(media_root: &Path) -> Self {
+        Staging {
+            files: LocalStore::new(&media_root.join(STAGING_DIR)),
+        }
+    }
+
+    pub fn batch<'a>(&'a self, store: &'a dyn MediaStore) -> StagedBatch<'a> {
+        StagedBatch {
+            staging: self,
+            store,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Clean up files left after crash: publish files whose images were committed
+    /// and remove the rest. Must be run before accepting uploads
+    pub async fn recover(
+        &self,
+        pool: &Pool<Sqlite>,
+        store: &dyn MediaStore,
+    ) -> anyhow::Result<RecoverSummary> {
+        let mut summary = RecoverSummary::default();
+        for key in self.files.list("").await? {
+            if image_file_path_exists(pool, &key).await? {
+                store.publish(&key, &self.files.path(&key)?).await?;
+                summary.published += 1;
+            } else {
+                self.files.delete(&key).await?;
+                summary.removed += 1;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// Files staged for one database transaction.
+/// Call [`StagedBatch::publish`] after commit or [`StagedBatch::discard`] after rollback
+pub struct StagedBatch<'a> {
+    staging: &'a Staging,
+    store: &'a dyn MediaStore,
+    keys: Vec<String>,
+}
+
+impl<'a> StagedBatch<'a> {
+    pub fn store(&self) -> &'a dyn MediaStore {
+        self.store
+    }
+
+    /// Copy `source` to the staging directory to be stored under `key` later
+    pub async fn stage(&mut self, key: &str, source: &Path) -> io::Result<()> {
+        if self.staging.files.exists(key).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is already staged", key),
+            ));
+        }
+        if let Err(error) = self.staging.files.put(key, source).await {
+            // Don't leave partially copied file
+            self.staging.files.delete(key).await?;
+            return Err(error);
+        }
+        self.keys.push(key.to_owned());
+        Ok(())
+    }
+
+    /// Remove staged file, e.g. if its image failed to insert
+    pub async fn unstage(&mut self, key: &str) -> io::Result<()> {
+        self.keys.retain(|staged| staged != key);
+        self.staging.files.delete(key).await
+    }
+
+    pub fn is_staged(&self, key: &str) -> bool {
+        self.keys.iter().any(|staged| staged == key)
+    }
+
+    /// Move staged files into media storage.
+    /// Files failed to move stay staged and are retried by [`Staging::recover`]
+    pub async fn publish(self) -> io::Result<()> {
+        let mut result = Ok(());
+        for key in &self.keys {
+            let source = self.staging.files.path(key)?;
+            if let Err(error) = self.store.publish(key, &source).await {
+                log::error!("Failed to publish staged file {}: {}", key, error);
+                result = result.and(Err(error));
+            }
+        }
+        result
+    }
+
+    /// Remove staged files
+    pub async fn discard(self) -> io::Result<()> {
+        for key in &self.keys {
+            self.staging.files.delete(key).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        path::Path,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use sqlx::{Acquire, Pool, Sqlite};
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::{RecoverSummary, Staging, STAGING_DIR};
+    use crate::{
+        config::MediaLayout,
+        models::{create_image, new_test_image, new_test_pool, Image},
+        storage::{ByteStream, LocalStore, MediaStore},
+    };
+
+    /// Local store which fails to publish files while `fail` is set
+    struct FailingStore {
+        inner: LocalStore,
+        fail: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl MediaStore for FailingStore {
+        async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+            self.inner.put(key, source).await
+        }
+        async fn publish(&self, key: &str, source: &Path) -> io::Result<()> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(io::Error::other("injected failure"));
+            }
+            self.inner.publish(key, source).await
+        }
+        async fn get(&self, key: &str) -> io::Result<ByteStream> {
+            self.inner.get(key).await
+        }
+        async fn delete(&self, key: &str) -> io::Result<()> {
+            self.inner.delete(key).await
+        }
+        async fn exists(&self, key: &str) -> io::Result<bool> {
+            self.inner.exists(key).await
+        }
+        async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+            self.inner.list(prefix).await
+        }
+        async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.inner.rename(from, to).await
+        }
+        fn presign(&self, key: &str) -> Option<String> {
+            self.inner.presign(key)
+        }
+    }
+
+    struct Setup {
+        pool: Pool<Sqlite>,
+        media_root: TempDir,
+        staging: Staging,
+        store: FailingStore,
+    }
+
+    impl Setup {
+        async fn new() -> Self {
+            let pool = new_test_pool().await;
+            let media_root = TempDir::new().unwrap();
+            Setup {
+                pool,
+                staging: Staging::new(media_root.path()),
+                store: FailingStore {
+                    inner: LocalStore::new(media_root.path()),
+                    fail: AtomicBool::new(false),
+                },
+                media_root,
+            }
+        }
+
+        async fn images_count(&self) -> i64 {
+            sqlx::query_scalar("SELECT count(*) FROM image")
+                .fetch_one(&self.pool)
+                .await
+                .unwrap()
+        }
+
+        async fn files(&self, prefix: &str) -> Vec<String> {
+            LocalStore::new(self.media_root.path())
+                .list(prefix)
+                .await
+                .unwrap()
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_commit_then_publish() {
+        let setup = Setup::new().await;
+        let source = NamedTempFile::new().unwrap();
+        let mut connection = setup.pool.acquire().await.unwrap();
+        let mut transaction = connection.begin().await.unwrap();
+        let mut batch = setup.staging.batch(&setup.store);
+
+        let mut image = new_test_image();
+        create_image(
+            &mut transaction,
+            &mut image,
+            source.path(),
+            MediaLayout::Flat,
+            &mut batch,
+        )
+        .await
+        .unwrap();
+        let file_path = image.file_path.unwrap();
+        // Nothing is visible in storage before commit
+        assert!(!setup.store.exists(&file_path).await.unwrap());
+
+        transaction.commit().await.unwrap();
+        batch.publish().await.unwrap();
+        assert!(setup.store.exists(&file_path).await.unwrap());
+        assert!(setup.files(STAGING_DIR).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_staging_failure() {
+        let setup = Setup::new().await;
+        let mut connection = setup.pool.acquire().await.unwrap();
+        let mut transaction = connection.begin().await.unwrap();
+        let mut batch = setup.staging.batch(&setup.store);
+
+        let result = create_image(
+            &mut transaction,
+            &mut new_test_image(),
+            Path::new("/nonexistent/source.png"),
+            MediaLayout::Flat,
+            &mut batch,
+        )
+        .await;
+        assert!(result.is_err());
+
+        transaction.rollback().await.unwrap();
+        batch.discard().await.unwrap();
+        drop(connection);
+        assert_eq!(setup.images_count().await, 0);
+        assert!(setup.files("").await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_insert_failure() {
+        let setup = Setup::new().await;
+        sqlx::query(
+            "CREATE TRIGGER fail_insert BEFORE INSERT ON image WHEN NEW.seed = 666
+            BEGIN SELECT RAISE(ABORT, 'injected failure'); END",
+        )
+        .execute(&setup.pool)
+        .await
+        .unwrap();
+        let source = NamedTempFile::new().unwrap();
+        let mut connection = setup.pool.acquire().await.unwrap();
+        let mut transaction = connection.begin().await.unwrap();
+        let mut batch = setup.staging.batch(&setup.store);
+
+        let mut good_image = new_test_image();
+        create_image(
+            &mut transaction,
+            &mut good_image,
+            source.path(),
+            MediaLayout::Flat,
+            &mut batch,
+        )
+        .await
+        .unwrap();
+        let mut bad_image = Image {
+            seed: 666,
+            ..new_test_image()
+        };
+        let result = create_image(
+            &mut transaction,
+            &mut bad_image,
+            source.path(),
+            MediaLayout::Flat,
+            &mut batch,
+        )
+        .await;
+        assert!(result.is_err());
+
+        // The rest of the batch is still committed
+        transaction.commit().await.unwrap();
+        batch.publish().await.unwrap();
+        drop(connection);
+        assert_eq!(setup.images_count().await, 1);
+        assert_eq!(setup.files("").await, vec![good_image.file_path.unwrap()]);
+    }
+
+    #[actix_web::test]
+    async fn test_rollback() {
+        let setup = Setup::new().await;
+        let source = NamedTempFile::new().unwrap();
+        let mut connection = setup.pool.acquire().await.unwrap();
+        let mut transaction = connection.begin().await.unwrap();
+        let mut batch = setup.staging.batch(&setup.store);
+
+        create_image(
+            &mut transaction,
+            &mut new_test_image(),
+            source.path(),
+            MediaLayout::Flat,
+            &mut batch,
+        )
+        .await
+        .unwrap();
+        transaction.rollback().await.unwrap();
+        batch.discard().await.unwrap();
+        drop(connection);
+
+        assert_eq!(setup.images_count().await, 0);
+        assert!(setup.files("").await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_publish_failure_recovered() {
+        let setup = Setup::new().await;
+        let source = NamedTempFile::new().unwrap();
+        let mut connection = setup.pool.acquire().await.unwrap();
+        let mut transaction = connection.begin().await.unwrap();
+        let mut batch = setup.staging.batch(&setup.store);
+
+        let mut image = new_test_image();
+        create_image(
+            &mut transaction,
+            &mut image,
+            source.path(),
+            MediaLayout::Flat,
+            &mut batch,
+        )
+        .await
+        .unwrap();
+        transaction.commit().await.unwrap();
+        setup.store.fail.store(true, Ordering::SeqCst);
+        assert!(batch.publish().await.is_err());
+        drop(connection);
+        let file_path = image.file_path.unwrap();
+        assert!(!setup.store.exists(&file_path).await.unwrap());
+
+        // Next startup moves file of committed image into storage
+        setup.store.fail.store(false, Ordering::SeqCst);
+        let summary = setup
+            .staging
+            .recover(&setup.pool, &setup.store)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            RecoverSummary {
+                published: 1,
+                removed: 0
+            }
+        );
+        assert!(setup.store.exists(&file_path).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_crash_before_commit_recovered() {
+        let setup = Setup::new().await;
+        let source = NamedTempFile::new().unwrap();
+        {
+            let mut connection = setup.pool.acquire().await.unwrap();
+            let mut transaction = connection.begin().await.unwrap();
+            let mut batch = setup.staging.batch(&setup.store);
+            create_image(
+                &mut transaction,
+                &mut new_test_image(),
+                source.path(),
+                MediaLayout::Flat,
+                &mut batch,
+            )
+            .await
+            .unwrap();
+            // Transaction and batch are dropped without commit and cleanup
+        }
+
+        let summary = setup
+            .staging
+            .recover(&setup.pool, &setup.store)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            RecoverSummary {
+                published: 0,
+                removed: 1
+            }
+        );
+        assert_eq!(setup.images_count().await, 0);
+        assert!(setup.files("").await.is_empty());
+    }
+}
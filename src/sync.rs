@@ -0,0 +1,691 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Context;
+use futures_util::TryStreamExt;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Pool, Sqlite, Transaction};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use utoipa::ToSchema;
+
+use crate::{
+    albums::{add_album_images, remove_album_images},
+    config::MediaLayout,
+    dump::{
+        fetch_records_by_ids, get_or_create_album, normalized_tags, set_ranking_and_dates,
+        ImageRecord,
+    },
+    models::IMAGE_COLUMNS,
+    models::{create_image, set_image_favorite, set_image_note, set_image_rating, Image},
+    revisions::{update_image_params, ImageParams},
+    storage::{MediaStore, StagedBatch, Staging},
+    tags::{add_tags, fetch_image_tags, remove_tags},
+};
+
+/// Changes are pulled from peers by this many
+const PAGE_SIZE: u32 = 200;
+
+/// Image with its fields changed since the cursor of [`ChangeFeed`]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ImageChange {
+    #[serde(flatten)]
+    pub image: ImageRecord,
+    /// Changed fields (`image`, `rating`, `favorite`, `note`, `score`, `inbox`, `trash`,
+    /// `tags` or `albums`) with times of their latest changes in milliseconds since epoch
+    pub changed: BTreeMap<String, i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChangeFeed {
+    pub changes: Vec<ImageChange>,
+    /// `since` of the request for the following changes
+    pub cursor: i64,
+    /// Whether there are more changes after `cursor`
+    pub more: bool,
+}
+
+/// Images changed after `since`, up to `limit` changed fields. Images without file hash
+/// can't be matched by other instances and are left out
+pub async fn fetch_changes(
+    pool: &Pool<Sqlite>,
+    since: i64,
+    limit: u32,
+) -> sqlx::Result<ChangeFeed> {
+    let fetch_limit = i64::from(limit) + 1;
+    let mut rows = sqlx::query!(
+        r#"SELECT image_change.id as "id!", image_change.image_id, image_change.field,
+        image_change.changed_at
+        FROM image_change JOIN image ON image.id = image_change.image_id
+        WHERE image_change.id > ? AND image.file_hash IS NOT NULL
+        ORDER BY image_change.id LIMIT ?"#,
+        since,
+        fetch_limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let more = rows.len() > limit as usize;
+    rows.truncate(limit as usize);
+    let cursor = rows.last().map_or(since, |row| row.id);
+
+    let mut changed: HashMap<i64, BTreeMap<String, i64>> = HashMap::new();
+    for row in rows {
+        changed
+            .entry(row.image_id)
+            .or_default()
+            .insert(row.field, row.changed_at);
+    }
+    let ids: Vec<i64> = changed.keys().copied().collect();
+    let changes = fetch_records_by_ids(pool, &ids)
+        .await?
+        .into_iter()
+        .map(|image| ImageChange {
+            changed: changed.remove(&image.id).unwrap_or_default(),
+            image,
+        })
+        .collect();
+    Ok(ChangeFeed {
+        changes,
+        cursor,
+        more,
+    })
+}
+
+/// Stored file with SHA-256 `file_hash`
+pub async fn fetch_file_path_by_hash(
+    executor: impl Executor<'_, Database = Sqlite>,
+    file_hash: &str,
+) -> sqlx::Result<Option<String>> {
+    sqlx::query_scalar!(
+        r#"SELECT file_path as "file_path!" FROM image
+        WHERE file_hash = ? AND file_path IS NOT NULL AND NOT file_missing
+        ORDER BY deleted_at IS NOT NULL, id LIMIT 1"#,
+        file_hash
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncReport {
+    /// Images copied from the peer
+    pub added: usize,
+    /// Images which got newer values of some fields
+    pub updated: usize,
+    /// File hashes of images which are not copied, with reasons
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Pull changes from sdgenbox at `peer` URL since the previous sync with it. Images missing
+/// here are copied with their files, which are fetched by hash. Fields of present images
+/// are resolved per field: the value changed last wins, pulled values keep the time they
+/// were changed at on the peer. Every page of changes is applied in its own transaction
+/// with the cursor, so interrupted syncs continue where they stopped. Images whose files
+/// couldn't be fetched are kept aside and retried first on the next sync
+pub async fn sync(
+    pool: &Pool<Sqlite>,
+    staging: &Staging,
+    store: &dyn MediaStore,
+    layout: MediaLayout,
+    peer: &str,
+) -> anyhow::Result<SyncReport> {
+    let peer = peer.trim_end_matches('/');
+    let client = reqwest::Client::new();
+    let mut cursor = sqlx::query_scalar!("SELECT cursor FROM sync_peer WHERE url = ?", peer)
+        .fetch_optional(pool)
+        .await?
+        .unwrap_or_default();
+    let sync = PeerSync {
+        pool,
+        staging,
+        store,
+        layout,
+        client,
+        peer,
+    };
+
+    let mut report = SyncReport::default();
+    let skipped = fetch_skipped_changes(pool, peer).await?;
+    if !skipped.is_empty() {
+        sync.apply_changes(&skipped, None, &mut report).await?;
+    }
+    loop {
+        let feed: ChangeFeed = sync
+            .client
+            .get(format!("{}/api/v1/changes", peer))
+            .query(&[("since", cursor), ("limit", i64::from(PAGE_SIZE))])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("Invalid change feed of {}", peer))?;
+        sync.apply_changes(&feed.changes, Some(feed.cursor), &mut report)
+            .await?;
+
+        cursor = feed.cursor;
+        if !feed.more {
+            break;
+        }
+    }
+    Ok(report)
+}
+
+/// Sync with one peer
+struct PeerSync<'a> {
+    pool: &'a Pool<Sqlite>,
+    staging: &'a Staging,
+    store: &'a dyn MediaStore,
+    layout: MediaLayout,
+    client: reqwest::Client,
+    peer: &'a str,
+}
+
+impl PeerSync<'_> {
+    /// Apply `changes` in one transaction and move the cursor to `cursor` if it's given.
+    /// Skipped changes are kept in `sync_skipped` until they are applied
+    async fn apply_changes(
+        &self,
+        changes: &[ImageChange],
+        cursor: Option<i64>,
+        report: &mut SyncReport,
+    ) -> anyhow::Result<()> {
+        // Files are downloaded before the transaction, it's not held during requests
+        let mut files = HashMap::new();
+        for change in changes {
+            let Some(file_hash) = &change.image.file_hash else {
+                continue;
+            };
+            if !files.contains_key(file_hash)
+                && fetch_local_image(self.pool, file_hash).await?.is_none()
+            {
+                let file = download_file(&self.client, self.peer, file_hash).await?;
+                files.insert(file_hash.clone(), file);
+            }
+        }
+
+        let mut transaction = self.pool.begin().await?;
+        let mut batch = self.staging.batch(self.store);
+        let result = async {
+            for change in changes {
+                let Some(file_hash) = &change.image.file_hash else {
+                    continue;
+                };
+                let skipped = apply_change(
+                    &mut transaction,
+                    &mut batch,
+                    self.layout,
+                    change,
+                    &files,
+                    report,
+                )
+                .await
+                .with_context(|| format!("Failed to apply change of image {}", change.image.id))?;
+                match skipped {
+                    Some(reason) => {
+                        let change = serde_json::to_string(change)?;
+                        sqlx::query!(
+                            "INSERT INTO sync_skipped (url, file_hash, change, reason)
+                            VALUES (?, ?, ?, ?)
+                            ON CONFLICT (url, file_hash) DO UPDATE SET change = excluded.change,
+                            reason = excluded.reason, skipped_at = unixepoch()",
+                            self.peer,
+                            file_hash,
+                            change,
+                            reason
+                        )
+                        .execute(&mut transaction)
+                        .await?;
+                        report.skipped.push((file_hash.clone(), reason));
+                    }
+                    None => {
+                        sqlx::query!(
+                            "DELETE FROM sync_skipped WHERE url = ? AND file_hash = ?",
+                            self.peer,
+                            file_hash
+                        )
+                        .execute(&mut transaction)
+                        .await?;
+                    }
+                }
+            }
+            if let Some(cursor) = cursor {
+                sqlx::query!(
+                    "INSERT INTO sync_peer (url, cursor) VALUES (?, ?)
+                    ON CONFLICT (url) DO UPDATE SET cursor = excluded.cursor,
+                    synced_at = unixepoch()",
+                    self.peer,
+                    cursor
+                )
+                .execute(&mut transaction)
+                .await?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(error) = result {
+            batch.discard().await?;
+            return Err(error);
+        }
+        if let Err(error) = transaction.commit().await {
+            batch.discard().await?;
+            return Err(error.into());
+        }
+        // Images are already saved, files failed to publish are retried on next server start
+        if let Err(error) = batch.publish().await {
+            log::error!("Failed to publish synced files: {}", error);
+        }
+        Ok(())
+    }
+}
+
+/// Changes skipped by previous syncs with `peer`
+async fn fetch_skipped_changes(
+    pool: &Pool<Sqlite>,
+    peer: &str,
+) -> anyhow::Result<Vec<ImageChange>> {
+    let rows = sqlx::query_scalar!(
+        "SELECT change FROM sync_skipped WHERE url = ? ORDER BY skipped_at",
+        peer
+    )
+    .fetch_all(pool)
+    .await?;
+    let changes = rows
+        .iter()
+        .map(|change| serde_json::from_str(change))
+        .collect::<Result<_, _>>()?;
+    Ok(changes)
+}
+
+/// File with SHA-256 `file_hash` from `peer`, or the reason why it's not downloaded
+async fn download_file(
+    client: &reqwest::Client,
+    peer: &str,
+    file_hash: &str,
+) -> anyhow::Result<Result<NamedTempFile, String>> {
+    let response = client
+        .get(format!("{}/api/v1/media/{}", peer, file_hash))
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(Err("File is missing on peer".to_owned()));
+    }
+    let mut stream = response.error_for_status()?.bytes_stream();
+
+    let file = NamedTempFile::new()?;
+    let mut output = tokio::fs::File::from_std(file.reopen()?);
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.try_next().await? {
+        hasher.update(&chunk);
+        output.write_all(&chunk).await?;
+    }
+    output.flush().await?;
+    if hex::encode(hasher.finalize()) != file_hash {
+        return Ok(Err("Downloaded file doesn't match its hash".to_owned()));
+    }
+    Ok(Ok(file))
+}
+
+/// Image with the file, images not in trash are preferred
+async fn fetch_local_image(
+    executor: impl Executor<'_, Database = Sqlite>,
+    file_hash: &str,
+) -> sqlx::Result<Option<Image>> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM image WHERE file_hash = ? ORDER BY deleted_at IS NOT NULL, id LIMIT 1",
+        IMAGE_COLUMNS
+    ))
+    .bind(file_hash)
+    .fetch_optional(executor)
+    .await
+}
+
+/// Apply `change` of image, returns the reason if the image is skipped
+async fn apply_change(
+    transaction: &mut Transaction<'_, Sqlite>,
+    batch: &mut StagedBatch<'_>,
+    layout: MediaLayout,
+    change: &ImageChange,
+    files: &HashMap<String, Result<NamedTempFile, String>>,
+    report: &mut SyncReport,
+) -> anyhow::Result<Option<String>> {
+    let record = &change.image;
+    let Some(file_hash) = &record.file_hash else {
+        return Ok(None);
+    };
+
+    let Some(image) = fetch_local_image(&mut *transaction, file_hash).await? else {
+        let file = match files.get(file_hash) {
+            Some(Ok(file)) => file,
+            Some(Err(reason)) => return Ok(Some(reason.clone())),
+            None => return Ok(Some("File is not downloaded".to_owned())),
+        };
+        let mut image = record.to_image();
+        create_image(transaction, &mut image, file.path(), layout, batch).await?;
+        set_ranking_and_dates(&mut *transaction, image.id, record).await?;
+        add_tags(transaction, &[image.id], &normalized_tags(record)?).await?;
+        set_albums(transaction, image.id, &record.albums).await?;
+        // Values are the peer's, fields it never changed lose to any local change
+        sqlx::query!(
+            "UPDATE image_change SET changed_at = 0 WHERE image_id = ?",
+            image.id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        for (field, changed_at) in &change.changed {
+            set_changed_at(&mut *transaction, image.id, field, *changed_at).await?;
+        }
+        report.added += 1;
+        return Ok(None);
+    };
+
+    let mut updated = false;
+    for (field, changed_at) in &change.changed {
+        let local_changed_at = sqlx::query_scalar!(
+            "SELECT changed_at FROM image_change WHERE image_id = ? AND field = ?",
+            image.id,
+            field
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .unwrap_or_default();
+        if local_changed_at >= *changed_at {
+            continue;
+        }
+        if apply_field(transaction, &image, record, field).await? {
+            updated = true;
+        }
+        set_changed_at(&mut *transaction, image.id, field, *changed_at).await?;
+    }
+    if updated {
+        report.updated += 1;
+    }
+    Ok(None)
+}
+
+/// Set `field` of local `image` to its value in `record`. Returns whether the value changed
+async fn apply_field(
+    transaction: &mut Transaction<'_, Sqlite>,
+    image: &Image,
+    record: &ImageRecord,
+    field: &str,
+) -> anyhow::Result<bool> {
+    let changed = match field {
+        "rating" if record.rating != image.rating => {
+            set_image_rating(&mut *transaction, image.id, record.rating).await?
+        }
+        "favorite" if record.favorite != image.favorite => {
+            set_image_favorite(&mut *transaction, image.id, record.favorite).await?
+        }
+        "note" if record.note != image.note => {
+            set_image_note(&mut *transaction, image.id, &record.note).await?
+        }
+        "score" if (record.score, record.comparisons) != (image.score, image.comparisons) => {
+            sqlx::query!(
+                "UPDATE image SET score = ?, comparisons = ? WHERE id = ?",
+                record.score,
+                record.comparisons,
+                image.id
+            )
+            .execute(&mut *transaction)
+            .await?;
+            true
+        }
+        "inbox" if record.inbox != image.inbox => {
+            sqlx::query!(
+                "UPDATE image SET inbox = ? WHERE id = ?",
+                record.inbox,
+                image.id
+            )
+            .execute(&mut *transaction)
+            .await?;
+            true
+        }
+        "trash" if record.deleted_at != image.deleted_at => {
+            let deleted_at = record.deleted_at.map(|deleted_at| deleted_at.timestamp());
+            sqlx::query!(
+                "UPDATE image SET deleted_at = ? WHERE id = ?",
+                deleted_at,
+                image.id
+            )
+            .execute(&mut *transaction)
+            .await?;
+            true
+        }
+        "tags" => {
+            let tags = normalized_tags(record)?;
+            let removed: Vec<String> = fetch_image_tags(&mut *transaction, image.id)
+                .await?
+                .into_iter()
+                .filter(|local| !tags.iter().any(|tag| tag.eq_ignore_ascii_case(local)))
+                .collect();
+            let removed = remove_tags(transaction, &[image.id], &removed).await?;
+            removed + add_tags(transaction, &[image.id], &tags).await? > 0
+        }
+        "albums" => set_albums(transaction, image.id, &record.albums).await?,
+        // Edited generation parameters, recorded as a revision here too
+        "image" => {
+            let params = ImageParams::from(&record.to_image());
+            update_image_params(transaction, image.id, &params, None)
+                .await?
+                .is_some()
+        }
+        _ => false,
+    };
+    Ok(changed)
+}
+
+/// Make image a member of albums named `names` only, albums are created if needed.
+/// Returns whether albums of the image changed
+async fn set_albums(
+    transaction: &mut Transaction<'_, Sqlite>,
+    image_id: i64,
+    names: &[String],
+) -> sqlx::Result<bool> {
+    let local = sqlx::query!(
+        "SELECT album.id, album.name FROM album
+        JOIN album_image ON album_image.album_id = album.id WHERE album_image.image_id = ?",
+        image_id
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let mut changed = 0;
+    for album in local {
+        if !names.contains(&album.name) {
+            changed += remove_album_images(transaction, album.id, &[image_id]).await?;
+        }
+    }
+    for name in names {
+        let album_id = get_or_create_album(&mut *transaction, name, "").await?;
+        changed += add_album_images(transaction, album_id, &[image_id]).await?;
+    }
+    Ok(changed > 0)
+}
+
+async fn set_changed_at(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+    field: &str,
+    changed_at: i64,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "INSERT INTO image_change (image_id, field, changed_at) VALUES (?, ?, ?)
+        ON CONFLICT (image_id, field) DO UPDATE SET changed_at = excluded.changed_at",
+        image_id,
+        field,
+        changed_at
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use actix_web::{
+        web::{scope, Data},
+        App, HttpServer,
+    };
+    use sha2::{Digest, Sha256};
+    use sqlx::{Pool, Sqlite};
+    use tempfile::TempDir;
+
+    use super::{sync, SyncReport};
+    use crate::{
+        config::MediaLayout,
+        dump::fetch_records,
+        handlers,
+        models::{
+            fetch_image_by_id, insert_test_image, new_test_image, new_test_pool, set_image_note,
+            set_image_rating, Image,
+        },
+        revisions::{fetch_image_revisions, update_image_params, ImageParams},
+        storage::{LocalStore, MediaStore, Staging},
+    };
+
+    async fn set_changed_at(pool: &Pool<Sqlite>, image_id: i64, field: &str, changed_at: i64) {
+        sqlx::query("UPDATE image_change SET changed_at = ? WHERE image_id = ? AND field = ?")
+            .bind(changed_at)
+            .bind(image_id)
+            .bind(field)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_sync() {
+        let root = TempDir::new().unwrap();
+        // Peer serves the JSON API of its library
+        let peer_media = root.path().join("peer_media");
+        let peer_store: Arc<dyn MediaStore> = Arc::new(LocalStore::new(&peer_media));
+        std::fs::create_dir_all(peer_media.join("images")).unwrap();
+        std::fs::write(peer_media.join("images/cat.png"), "cat").unwrap();
+        let file_hash = hex::encode(Sha256::digest("cat"));
+        // The file of the dog is missing on the peer for a while
+        let dog_hash = hex::encode(Sha256::digest("dog"));
+        let peer_pool = new_test_pool().await;
+        for (prompt, file_path, file_hash) in [
+            ("cat", "images/cat.png", file_hash.as_str()),
+            ("dog", "images/dog.png", dog_hash.as_str()),
+        ] {
+            insert_test_image(
+                &peer_pool,
+                Image {
+                    prompt: prompt.to_string(),
+                    file_path: Some(file_path.to_string()),
+                    file_hash: Some(file_hash.to_string()),
+                    rating: 3,
+                    ..new_test_image()
+                },
+            )
+            .await;
+        }
+        sqlx::query("INSERT INTO tag (name) VALUES ('animal')")
+            .execute(&peer_pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO image_tag (image_id, tag_id) VALUES (1, 1)")
+            .execute(&peer_pool)
+            .await
+            .unwrap();
+
+        let server_pool = peer_pool.clone();
+        let server_store = Data::from(peer_store.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .service(scope("/api/v1").configure(handlers::api::configure))
+                .app_data(Data::new(server_pool.clone()))
+                .app_data(server_store.clone())
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let peer = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let media_root = root.path().join("media");
+        let pool = new_test_pool().await;
+        let store = LocalStore::new(&media_root);
+        let staging = Staging::new(&media_root);
+
+        let report = sync(&pool, &staging, &store, MediaLayout::Flat, &peer)
+            .await
+            .unwrap();
+        assert_eq!(report.added, 1);
+        let skipped = vec![(dog_hash.clone(), "File is missing on peer".to_string())];
+        assert_eq!(report.skipped, skipped);
+        let records = fetch_records(&pool).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].file_hash.as_deref(), Some(file_hash.as_str()));
+        assert_eq!(records[0].rating, 3);
+        assert_eq!(records[0].tags, vec!["animal"]);
+        let image_id = records[0].id;
+        assert!(store
+            .exists(records[0].file_path.as_deref().unwrap())
+            .await
+            .unwrap());
+
+        // Nothing is pulled twice, skipped images are retried
+        let report = sync(&pool, &staging, &store, MediaLayout::Flat, &peer)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            SyncReport {
+                skipped,
+                ..Default::default()
+            }
+        );
+        std::fs::write(peer_media.join("images/dog.png"), "dog").unwrap();
+        let report = sync(&pool, &staging, &store, MediaLayout::Flat, &peer)
+            .await
+            .unwrap();
+        assert_eq!((report.added, report.skipped), (1, vec![]));
+        let report = sync(&pool, &staging, &store, MediaLayout::Flat, &peer)
+            .await
+            .unwrap();
+        assert_eq!(report, Default::default());
+
+        // Rating changed here after the peer's change wins, the peer's later note wins
+        set_image_rating(&peer_pool, 1, 5).await.unwrap();
+        set_changed_at(&peer_pool, 1, "rating", 1000).await;
+        set_image_rating(&pool, image_id, 1).await.unwrap();
+        set_changed_at(&pool, image_id, "rating", 2000).await;
+        set_image_note(&pool, image_id, "here").await.unwrap();
+        set_changed_at(&pool, image_id, "note", 1000).await;
+        set_image_note(&peer_pool, 1, "peer").await.unwrap();
+        set_changed_at(&peer_pool, 1, "note", 2000).await;
+
+        let report = sync(&pool, &staging, &store, MediaLayout::Flat, &peer)
+            .await
+            .unwrap();
+        assert_eq!(report.updated, 1);
+        let records = fetch_records(&pool).await.unwrap();
+        assert_eq!(records[0].rating, 1);
+        assert_eq!(records[0].note, "peer");
+
+        // Edited parameters are pulled with their revision
+        let mut transaction = peer_pool.begin().await.unwrap();
+        let params = ImageParams {
+            prompt: "a cat".to_string(),
+            ..ImageParams::from(&new_test_image())
+        };
+        update_image_params(&mut transaction, 1, &params, None)
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        let report = sync(&pool, &staging, &store, MediaLayout::Flat, &peer)
+            .await
+            .unwrap();
+        assert_eq!(report.updated, 1);
+        let image = fetch_image_by_id(&pool, image_id).await.unwrap().unwrap();
+        assert_eq!(image.prompt, "a cat");
+        let revisions = fetch_image_revisions(&pool, image_id).await.unwrap();
+        assert_eq!(revisions.len(), 1);
+    }
+}
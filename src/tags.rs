@@ -0,0 +1,327 @@
+use sqlx::{Executor, Sqlite, Transaction};
+
+/// Longest allowed tag name
+const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(Debug, PartialEq, serde::Serialize, sqlx::FromRow)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
+    /// Number of images (not in trash) with the tag
+    pub image_count: i64,
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum TagError {
+    #[error("Tag name is empty")]
+    EmptyName,
+    #[error("Tag name is longer than {MAX_NAME_LENGTH} characters")]
+    LongName,
+    #[error("Tag name can't contain commas")]
+    Comma,
+    #[error("Tag `{0}` already exists, merge tags instead")]
+    Exists(String),
+    #[error("Tags can't be merged into themselves")]
+    SelfMerge,
+}
+
+/// Trim tag name and collapse whitespace inside it
+pub fn normalize_name(name: &str) -> Result<String, TagError> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if name.is_empty() {
+        return Err(TagError::EmptyName);
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(TagError::LongName);
+    }
+    if name.contains(',') {
+        return Err(TagError::Comma);
+    }
+    Ok(name)
+}
+
+/// Parse comma separated tag names, empty names are skipped
+pub fn parse_names(names: &str) -> Result<Vec<String>, TagError> {
+    names
+        .split(',')
+        .filter(|name| !name.trim().is_empty())
+        .map(normalize_name)
+        .collect()
+}
+
+/// All tags ordered by name with counts of images
+pub async fn fetch_tags(executor: impl Executor<'_, Database = Sqlite>) -> sqlx::Result<Vec<Tag>> {
+    sqlx::query_as!(
+        Tag,
+        r#"SELECT tag.id as "id!", tag.name, count(image.id) as "image_count!: i64"
+        FROM tag
+        LEFT JOIN image_tag ON image_tag.tag_id = tag.id
+        LEFT JOIN image ON image.id = image_tag.image_id AND image.deleted_at IS NULL
+        GROUP BY tag.id ORDER BY tag.name COLLATE NOCASE"#
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Names of tags of image ordered by name
+pub async fn fetch_image_tags(
+    executor: impl Executor<'_, Database = Sqlite>,
+    image_id: i64,
+) -> sqlx::Result<Vec<String>> {
+    sqlx::query_scalar!(
+        "SELECT tag.name FROM tag JOIN image_tag ON image_tag.tag_id = tag.id
+        WHERE image_tag.image_id = ? ORDER BY tag.name COLLATE NOCASE",
+        image_id
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Names of tags starting with `prefix` for autocomplete, most used first
+pub async fn fetch_tag_names_by_prefix(
+    executor: impl Executor<'_, Database = Sqlite>,
+    prefix: &str,
+    limit: i64,
+) -> sqlx::Result<Vec<String>> {
+    let pattern = format!(
+        "{}%",
+        prefix
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+    sqlx::query_scalar!(
+        r#"SELECT tag.name as "name!" FROM tag LEFT JOIN image_tag ON image_tag.tag_id = tag.id
+        WHERE tag.name LIKE ? ESCAPE '\'
+        GROUP BY tag.id ORDER BY count(image_tag.image_id) DESC, tag.name LIMIT ?"#,
+        pattern,
+        limit,
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Id of tag with the name, the tag is created if it doesn't exist
+async fn get_or_create_tag(
+    transaction: &mut Transaction<'_, Sqlite>,
+    name: &str,
+) -> sqlx::Result<i64> {
+    sqlx::query!(
+        "INSERT INTO tag (name) VALUES (?) ON CONFLICT (name) DO NOTHING",
+        name
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query_scalar!(r#"SELECT id as "id!" FROM tag WHERE name = ?"#, name)
+        .fetch_one(&mut *transaction)
+        .await
+}
+
+/// Add tags to images, tags are created if needed. Returns number of new image tags
+pub async fn add_tags(
+    transaction: &mut Transaction<'_, Sqlite>,
+    image_ids: &[i64],
+    names: &[String],
+) -> sqlx::Result<u64> {
+    let mut added = 0;
+    for name in names {
+        let tag_id = get_or_create_tag(transaction, name).await?;
+        for image_id in image_ids {
+            let result = sqlx::query!(
+                "INSERT INTO image_tag (image_id, tag_id) SELECT id, ? FROM image WHERE id = ?
+                ON CONFLICT DO NOTHING",
+                tag_id,
+                image_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+            added += result.rows_affected();
+        }
+    }
+    Ok(added)
+}
+
+/// Remove tags from images. Tags themselves are kept even if no image has them
+pub async fn remove_tags(
+    transaction: &mut Transaction<'_, Sqlite>,
+    image_ids: &[i64],
+    names: &[String],
+) -> sqlx::Result<u64> {
+    let mut removed = 0;
+    for name in names {
+        for image_id in image_ids {
+            let result = sqlx::query!(
+                "DELETE FROM image_tag
+                WHERE image_id = ? AND tag_id IN (SELECT id FROM tag WHERE name = ?)",
+                image_id,
+                name
+            )
+            .execute(&mut *transaction)
+            .await?;
+            removed += result.rows_affected();
+        }
+    }
+    Ok(removed)
+}
+
+/// Rename tag, fails if another tag already has the name
+pub async fn rename_tag(
+    transaction: &mut Transaction<'_, Sqlite>,
+    tag_id: i64,
+    name: &str,
+) -> anyhow::Result<()> {
+    let name = normalize_name(name)?;
+    let existing = sqlx::query_scalar!(
+        "SELECT id FROM tag WHERE name = ? AND id != ?",
+        name,
+        tag_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if existing.is_some() {
+        return Err(TagError::Exists(name).into());
+    }
+    sqlx::query!("UPDATE tag SET name = ? WHERE id = ?", name, tag_id)
+        .execute(&mut *transaction)
+        .await?;
+    Ok(())
+}
+
+/// Move images of tag `source_id` to tag with `target` name (created if needed) and delete
+/// the source tag. Returns id of the target tag
+pub async fn merge_tag(
+    transaction: &mut Transaction<'_, Sqlite>,
+    source_id: i64,
+    target: &str,
+) -> anyhow::Result<i64> {
+    let target = normalize_name(target)?;
+    let target_id = get_or_create_tag(transaction, &target).await?;
+    if target_id == source_id {
+        return Err(TagError::SelfMerge.into());
+    }
+    sqlx::query!(
+        "INSERT INTO image_tag (image_id, tag_id) SELECT image_id, ? FROM image_tag
+        WHERE tag_id = ? ON CONFLICT DO NOTHING",
+        target_id,
+        source_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!("DELETE FROM tag WHERE id = ?", source_id)
+        .execute(&mut *transaction)
+        .await?;
+    Ok(target_id)
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::{Pool, Sqlite};
+
+    use super::{
+        add_tags, fetch_image_tags, fetch_tag_names_by_prefix, fetch_tags, merge_tag,
+        normalize_name, parse_names, remove_tags, rename_tag, TagError,
+    };
+    use crate::{
+        models::{
+            fetch_images_count, insert_test_image, new_test_image, new_test_pool, trash_image,
+        },
+        utils::search::SearchQuery,
+    };
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    async fn count(pool: &Pool<Sqlite>, search: &str) -> u32 {
+        let search: SearchQuery = search.parse().unwrap();
+        fetch_images_count(pool, &search).await.unwrap()
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(normalize_name("  client   A "), Ok("client A".to_string()));
+        assert_eq!(normalize_name(" "), Err(TagError::EmptyName));
+        assert_eq!(
+            parse_names("keeper, client A,,"),
+            Ok(names(&["keeper", "client A"]))
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_tag_images() {
+        let pool = new_test_pool().await;
+        let first = insert_test_image(&pool, new_test_image()).await;
+        let second = insert_test_image(&pool, new_test_image()).await;
+
+        let mut transaction = pool.begin().await.unwrap();
+        let added = add_tags(&mut transaction, &[first, second], &names(&["keeper"]))
+            .await
+            .unwrap();
+        assert_eq!(added, 2);
+        // Tag names are case-insensitive
+        add_tags(&mut transaction, &[first], &names(&["Keeper", "client A"]))
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        assert_eq!(
+            fetch_image_tags(&pool, first).await.unwrap(),
+            names(&["client A", "keeper"])
+        );
+        assert_eq!(count(&pool, "tag:keeper").await, 2);
+        assert_eq!(count(&pool, "tag:\"CLIENT A\"").await, 1);
+        assert_eq!(count(&pool, "tag:keeper -tag:\"client a\"").await, 1);
+        assert_eq!(
+            fetch_tag_names_by_prefix(&pool, "k", 10).await.unwrap(),
+            names(&["keeper"])
+        );
+
+        let mut transaction = pool.begin().await.unwrap();
+        remove_tags(&mut transaction, &[second], &names(&["keeper"]))
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+        assert_eq!(count(&pool, "tag:keeper").await, 1);
+
+        // Trashed images are not counted
+        trash_image(&pool, first).await.unwrap();
+        let tags = fetch_tags(&pool).await.unwrap();
+        assert_eq!(tags.len(), 2);
+        assert!(tags.iter().all(|tag| tag.image_count == 0));
+    }
+
+    #[actix_web::test]
+    async fn test_rename_and_merge() {
+        let pool = new_test_pool().await;
+        let first = insert_test_image(&pool, new_test_image()).await;
+        let second = insert_test_image(&pool, new_test_image()).await;
+
+        let mut transaction = pool.begin().await.unwrap();
+        add_tags(&mut transaction, &[first], &names(&["keepr"]))
+            .await
+            .unwrap();
+        add_tags(&mut transaction, &[first, second], &names(&["keeper"]))
+            .await
+            .unwrap();
+        let tags = fetch_tags(&mut transaction).await.unwrap();
+        let keeper_id = tags[0].id;
+        let keepr_id = tags[1].id;
+
+        assert!(rename_tag(&mut transaction, keepr_id, "keeper")
+            .await
+            .is_err());
+        let target_id = merge_tag(&mut transaction, keepr_id, "keeper")
+            .await
+            .unwrap();
+        assert_eq!(target_id, keeper_id);
+        rename_tag(&mut transaction, keeper_id, "best")
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        let tags = fetch_tags(&pool).await.unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].name, "best");
+        assert_eq!(tags[0].image_count, 2);
+    }
+}
@@ -0,0 +1,110 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    models::{fetch_trashed_image_ids, purge_images},
+    storage::MediaStore,
+};
+
+/// How often trash is checked for expired images
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Permanently remove images which are in trash for longer than `retention_days`
+pub async fn purge_expired(
+    pool: &Pool<Sqlite>,
+    store: &dyn MediaStore,
+    retention_days: u32,
+) -> anyhow::Result<usize> {
+    let deleted_before = Utc::now().naive_utc() - chrono::Duration::days(retention_days.into());
+    let image_ids = fetch_trashed_image_ids(pool, Some(deleted_before)).await?;
+    if image_ids.is_empty() {
+        return Ok(0);
+    }
+    purge_images(pool, store, &image_ids).await?;
+    Ok(image_ids.len())
+}
+
+/// Periodically purge expired images in background
+pub fn spawn_purge_task(pool: Pool<Sqlite>, store: Arc<dyn MediaStore>, retention_days: u32) {
+    if retention_days == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match purge_expired(&pool, store.as_ref(), retention_days).await {
+                Ok(0) => {}
+                Ok(purged) => log::info!("Purged {} images from trash", purged),
+                Err(error) => log::error!("Failed to purge trash: {:#}", error),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::purge_expired;
+    use crate::{
+        models::{
+            fetch_image_by_id, insert_test_image, new_test_image, new_test_pool, trash_image, Image,
+        },
+        storage::{LocalStore, MediaStore},
+    };
+
+    #[actix_web::test]
+    async fn test_purge_expired() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let store = LocalStore::new(media_root.path());
+        let source = NamedTempFile::new().unwrap();
+        for key in ["images/old.png", "images/new.png", "images/kept.png"] {
+            store.put(key, source.path()).await.unwrap();
+        }
+        let old_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/old.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let new_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/new.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        let kept_id = insert_test_image(
+            &pool,
+            Image {
+                file_path: Some("images/kept.png".to_string()),
+                ..new_test_image()
+            },
+        )
+        .await;
+        trash_image(&pool, old_id).await.unwrap();
+        trash_image(&pool, new_id).await.unwrap();
+        sqlx::query!(
+            "UPDATE image SET deleted_at = unixepoch() - 31 * 24 * 60 * 60 WHERE id = ?",
+            old_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(purge_expired(&pool, &store, 30).await.unwrap(), 1);
+        assert!(fetch_image_by_id(&pool, old_id).await.unwrap().is_none());
+        assert!(!store.exists("images/old.png").await.unwrap());
+        for image_id in [new_id, kept_id] {
+            assert!(fetch_image_by_id(&pool, image_id).await.unwrap().is_some());
+        }
+        assert!(store.exists("images/new.png").await.unwrap());
+    }
+}
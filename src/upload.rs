@@ -0,0 +1,469 @@
+use std::{fmt, path::Path};
+
+use anyhow::{anyhow, Context};
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use sqlx::{Connection, Pool, Sqlite};
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+
+use crate::{
+    archive::{read_archive, ArchiveEntry, ArchiveFormat},
+    config::MediaLayout,
+    models::{
+        attach_image_file, create_image, fetch_image_id_by_file_hash, stage_image_file, Image,
+    },
+    storage::{MediaStore, Staging},
+    utils::{
+        hash::sha256_file_blocking,
+        image::{extract_metadata_from_image, parse_raw, ExtractError},
+    },
+};
+
+/// What happened to one uploaded file
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UploadOutcome {
+    /// New image was created from the file, or the file was attached to the image missing it
+    /// (e.g. loaded from a metadata dump)
+    Imported { image_id: i64 },
+    /// The same file is already imported, no image was created
+    Duplicate { image_id: i64 },
+    /// The file is not a PNG, JPEG or WebP image
+    UnsupportedFormat,
+    /// The image has no generation parameters
+    MissingMetadata,
+    /// Generation parameters are present but can't be parsed
+    ParseError { reason: String },
+    /// Archive or its entry isn't read, e.g. because of unsafe path or size
+    Rejected { reason: String },
+    /// Unexpected error, details are in the server log
+    InternalError,
+}
+
+impl UploadOutcome {
+    /// The created or already existing image of the file
+    pub fn image_id(&self) -> Option<i64> {
+        match self {
+            UploadOutcome::Imported { image_id } | UploadOutcome::Duplicate { image_id } => {
+                Some(*image_id)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_imported(&self) -> bool {
+        matches!(self, UploadOutcome::Imported { .. })
+    }
+}
+
+impl fmt::Display for UploadOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadOutcome::Imported { .. } => write!(f, "Imported"),
+            UploadOutcome::Duplicate { .. } => write!(f, "Already imported"),
+            UploadOutcome::UnsupportedFormat => {
+                write!(f, "Unsupported format, expected PNG, JPEG or WebP image")
+            }
+            UploadOutcome::MissingMetadata => write!(f, "No generation parameters in metadata"),
+            UploadOutcome::ParseError { reason } => {
+                write!(f, "Failed to parse generation parameters: {}", reason)
+            }
+            UploadOutcome::Rejected { reason } => write!(f, "Rejected: {}", reason),
+            UploadOutcome::InternalError => write!(f, "Internal error"),
+        }
+    }
+}
+
+/// Outcome of uploaded file named `file_name`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UploadResult {
+    pub file_name: String,
+    #[serde(flatten)]
+    pub outcome: UploadOutcome,
+}
+
+/// File to import
+pub struct UploadFile<'a> {
+    /// Name shown in results, path inside archive for archive entries
+    pub name: String,
+    pub path: &'a Path,
+    /// Generation parameters from a sidecar `.txt` file, used if the image has none
+    pub sidecar: Option<String>,
+}
+
+/// Imports uploaded files into the library
+pub struct Importer<'a> {
+    pub pool: &'a Pool<Sqlite>,
+    pub staging: &'a Staging,
+    pub store: &'a dyn MediaStore,
+    pub layout: MediaLayout,
+    /// Number of files whose metadata is extracted and hashed at the same time
+    pub workers: usize,
+}
+
+impl Importer<'_> {
+    /// Import uploaded `files` given as their names and temporary paths, ZIP and tar
+    /// archives are replaced by their entries. Results are in the same order as files
+    pub async fn import_uploads(&self, files: Vec<(Option<String>, &Path)>) -> Vec<UploadResult> {
+        let mut results = Vec::with_capacity(files.len());
+        let mut plain_files = Vec::new();
+        for (file_name, path) in files {
+            let name = file_name.unwrap_or_else(|| "unnamed".to_owned());
+            match ArchiveFormat::detect(path) {
+                Ok(Some(format)) => {
+                    results.extend(self.import_files(std::mem::take(&mut plain_files)).await);
+                    results.extend(self.import_archive(&name, path, format).await);
+                }
+                Ok(None) => plain_files.push(UploadFile {
+                    name,
+                    path,
+                    sidecar: None,
+                }),
+                Err(error) => {
+                    log::error!("Failed to read uploaded file {:?}: {}", name, error);
+                    results.extend(self.import_files(std::mem::take(&mut plain_files)).await);
+                    results.push(UploadResult {
+                        file_name: name,
+                        outcome: UploadOutcome::InternalError,
+                    });
+                }
+            }
+        }
+        results.extend(self.import_files(plain_files).await);
+        results
+    }
+
+    /// Import entries of archive named `name` while they are read, only a few entries
+    /// are unpacked to disk at any time. Entries are named `<name>/<path in archive>`
+    async fn import_archive(
+        &self,
+        name: &str,
+        path: &Path,
+        format: ArchiveFormat,
+    ) -> Vec<UploadResult> {
+        let (sender, mut receiver) = mpsc::channel(self.workers.max(1));
+        let archive_path = path.to_owned();
+        let reader =
+            tokio::task::spawn_blocking(move || read_archive(&archive_path, format, sender));
+
+        let mut results = Vec::new();
+        let mut entries = Vec::new();
+        while let Some(item) = receiver.recv().await {
+            match item {
+                Ok(entry) => entries.push(entry),
+                Err(rejected) => {
+                    results.extend(
+                        self.import_entries(name, std::mem::take(&mut entries))
+                            .await,
+                    );
+                    results.push(UploadResult {
+                        file_name: format!("{}/{}", name, rejected.path),
+                        outcome: UploadOutcome::Rejected {
+                            reason: rejected.reason,
+                        },
+                    });
+                }
+            }
+            if entries.len() >= self.workers.max(1) {
+                results.extend(
+                    self.import_entries(name, std::mem::take(&mut entries))
+                        .await,
+                );
+            }
+        }
+        results.extend(self.import_entries(name, entries).await);
+
+        let error = match reader.await {
+            Ok(Ok(())) => return results,
+            Ok(Err(error)) => error,
+            Err(error) => error.into(),
+        };
+        log::warn!("Failed to read uploaded archive {:?}: {:#}", name, error);
+        results.push(UploadResult {
+            file_name: name.to_owned(),
+            outcome: UploadOutcome::Rejected {
+                reason: format!("Invalid archive: {}", error),
+            },
+        });
+        results
+    }
+
+    async fn import_entries(
+        &self,
+        archive_name: &str,
+        entries: Vec<ArchiveEntry>,
+    ) -> Vec<UploadResult> {
+        let files = entries
+            .iter()
+            .map(|entry| UploadFile {
+                name: format!("{}/{}", archive_name, entry.path),
+                path: entry.file.path(),
+                sidecar: entry.sidecar.clone(),
+            })
+            .collect();
+        self.import_files(files).await
+    }
+
+    /// Import image `files`. Metadata of up to `workers` files is extracted and hashed
+    /// at the same time on blocking threads, then every image is inserted in its own short
+    /// transaction, so a failed file doesn't affect others and the database isn't locked
+    /// for the whole upload
+    async fn import_files(&self, files: Vec<UploadFile<'_>>) -> Vec<UploadResult> {
+        let mut prepared = stream::iter(&files)
+            .map(|file| {
+                let path = file.path.to_owned();
+                let sidecar = file.sidecar.clone();
+                tokio::task::spawn_blocking(move || prepare_file(&path, sidecar.as_deref()))
+            })
+            .buffered(self.workers.max(1));
+
+        let mut results = Vec::with_capacity(files.len());
+        for file in &files {
+            let outcome = match prepared.next().await {
+                Some(Ok(Ok(Ok(image)))) => self.save_image(image, file.path).await,
+                Some(Ok(Ok(Err(outcome)))) => Ok(outcome),
+                Some(Ok(Err(error))) => Err(error),
+                Some(Err(error)) => Err(error.into()),
+                None => Err(anyhow!("File wasn't prepared")),
+            };
+            let outcome = outcome.unwrap_or_else(|error| {
+                log::error!(
+                    "Failed to import uploaded file {:?}: {:#}",
+                    file.name,
+                    error
+                );
+                UploadOutcome::InternalError
+            });
+            results.push(UploadResult {
+                file_name: file.name.clone(),
+                outcome,
+            });
+        }
+        results
+    }
+
+    /// Insert prepared `image` of `file` unless the file is already imported. Image of the file
+    /// which is missing it gets the file instead
+    async fn save_image(&self, mut image: Image, file: &Path) -> anyhow::Result<UploadOutcome> {
+        let file_hash = image.file_hash.clone().context("Image has no file hash")?;
+        let mut connection = self.pool.acquire().await?;
+        let mut transaction = connection.begin().await?;
+        let mut batch = self.staging.batch(self.store);
+        match fetch_image_id_by_file_hash(&mut transaction, &file_hash).await? {
+            Some((image_id, false)) => return Ok(UploadOutcome::Duplicate { image_id }),
+            Some((image_id, true)) => {
+                let file_path = stage_image_file(&mut batch, self.layout, file).await?;
+                if let Err(error) = attach_image_file(&mut transaction, image_id, &file_path).await
+                {
+                    batch.unstage(&file_path).await?;
+                    return Err(error.into());
+                }
+                image.id = image_id;
+            }
+            None => {
+                create_image(&mut transaction, &mut image, file, self.layout, &mut batch).await?
+            }
+        }
+        if let Err(error) = transaction.commit().await {
+            batch.discard().await?;
+            return Err(error.into());
+        }
+        // Image is already saved, file failed to publish is retried on next server start
+        if let Err(error) = batch.publish().await {
+            log::error!("Failed to publish uploaded file: {}", error);
+        }
+        Ok(UploadOutcome::Imported { image_id: image.id })
+    }
+}
+
+/// Extract metadata and hash of the file, blocking. Parameters of `sidecar` are used
+/// if the image has none. Files without valid metadata get their final outcome instead
+fn prepare_file(
+    path: &Path,
+    sidecar: Option<&str>,
+) -> anyhow::Result<Result<Image, UploadOutcome>> {
+    let path_str = path.to_str().context("Invalid temporary path")?;
+    let result = match extract_metadata_from_image(path_str) {
+        Err(ExtractError::MissingMetadata) if sidecar.is_some() => {
+            parse_raw(sidecar.unwrap_or_default())
+        }
+        result => result,
+    };
+    let mut image = match result {
+        Ok(image) => image,
+        Err(ExtractError::UnsupportedFormat) => return Ok(Err(UploadOutcome::UnsupportedFormat)),
+        Err(ExtractError::MissingMetadata) => return Ok(Err(UploadOutcome::MissingMetadata)),
+        Err(ExtractError::ParseError(reason)) => {
+            return Ok(Err(UploadOutcome::ParseError { reason }))
+        }
+        Err(ExtractError::InternalError(error)) => return Err(error),
+    };
+    image.file_hash = Some(sha256_file_blocking(path)?);
+    Ok(Ok(image))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::{Importer, UploadOutcome, UploadResult};
+    use crate::{
+        config::MediaLayout,
+        dump::{fetch_records, load_records},
+        models::{fetch_image_by_id, insert_test_image, new_test_image, new_test_pool, Image},
+        storage::{LocalStore, MediaStore, Staging},
+        utils::hash::sha256_file,
+    };
+
+    #[test]
+    fn test_upload_result_json() {
+        let result = UploadResult {
+            file_name: "a.png".to_string(),
+            outcome: UploadOutcome::Duplicate { image_id: 3 },
+        };
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"file_name":"a.png","status":"duplicate","image_id":3}"#
+        );
+        let result = UploadResult {
+            file_name: "b.gif".to_string(),
+            outcome: UploadOutcome::UnsupportedFormat,
+        };
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"file_name":"b.gif","status":"unsupported_format"}"#
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_import_keeps_order() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let staging = Staging::new(media_root.path());
+        let store = LocalStore::new(media_root.path());
+
+        let files: Vec<_> = (0..5)
+            .map(|i| {
+                let mut file = NamedTempFile::new().unwrap();
+                write!(file, "not an image {}", i).unwrap();
+                file
+            })
+            .collect();
+        let importer = Importer {
+            pool: &pool,
+            staging: &staging,
+            store: &store,
+            layout: MediaLayout::Flat,
+            workers: 2,
+        };
+        let results = importer
+            .import_uploads(
+                files
+                    .iter()
+                    .enumerate()
+                    .map(|(i, file)| (Some(format!("{}.txt", i)), file.path()))
+                    .collect(),
+            )
+            .await;
+        let names: Vec<_> = results.iter().map(|r| r.file_name.as_str()).collect();
+        assert_eq!(names, vec!["0.txt", "1.txt", "2.txt", "3.txt", "4.txt"]);
+        assert!(results
+            .iter()
+            .all(|r| r.outcome == UploadOutcome::UnsupportedFormat));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs exiftool"]
+    async fn test_import_valid_file() {
+        let pool = new_test_pool().await;
+        let media_root = TempDir::new().unwrap();
+        let staging = Staging::new(media_root.path());
+        let store = LocalStore::new(media_root.path());
+
+        let png = include_bytes!("tests/assets/image_with_params.png");
+        let mut valid = NamedTempFile::new().unwrap();
+        valid.write_all(png).unwrap();
+        let mut invalid = NamedTempFile::new().unwrap();
+        write!(invalid, "not an image").unwrap();
+        let mut duplicate = NamedTempFile::new().unwrap();
+        duplicate.write_all(png).unwrap();
+        let importer = Importer {
+            pool: &pool,
+            staging: &staging,
+            store: &store,
+            layout: MediaLayout::Flat,
+            workers: 2,
+        };
+        let results = importer
+            .import_uploads(vec![
+                (Some("a.png".to_string()), valid.path()),
+                (Some("b.txt".to_string()), invalid.path()),
+                (Some("c.png".to_string()), duplicate.path()),
+            ])
+            .await;
+
+        let UploadOutcome::Imported { image_id } = results[0].outcome else {
+            panic!("{:?} is not imported", results[0]);
+        };
+        assert_eq!(results[1].outcome, UploadOutcome::UnsupportedFormat);
+        assert_eq!(results[2].outcome, UploadOutcome::Duplicate { image_id });
+        let image = fetch_image_by_id(&pool, image_id).await.unwrap().unwrap();
+        assert!(store
+            .exists(image.file_path.as_deref().unwrap())
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_upload_restores_file_of_loaded_image() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "image").unwrap();
+        let file_hash = sha256_file(file.path()).await.unwrap();
+        let source = new_test_pool().await;
+        let image = Image {
+            file_path: Some("images/a.png".to_string()),
+            file_hash: Some(file_hash.clone()),
+            ..new_test_image()
+        };
+        insert_test_image(&source, image).await;
+        let pool = new_test_pool().await;
+        load_records(&pool, fetch_records(&source).await.unwrap())
+            .await
+            .unwrap();
+        let image_id = fetch_records(&pool).await.unwrap()[0].id;
+
+        let media_root = TempDir::new().unwrap();
+        let staging = Staging::new(media_root.path());
+        let store = LocalStore::new(media_root.path());
+        let importer = Importer {
+            pool: &pool,
+            staging: &staging,
+            store: &store,
+            layout: MediaLayout::Flat,
+            workers: 2,
+        };
+        let image = Image {
+            file_hash: Some(file_hash.clone()),
+            ..new_test_image()
+        };
+        let outcome = importer.save_image(image, file.path()).await.unwrap();
+        assert_eq!(outcome, UploadOutcome::Imported { image_id });
+        let image = fetch_image_by_id(&pool, image_id).await.unwrap().unwrap();
+        assert!(!image.file_missing);
+        assert!(store
+            .exists(image.file_path.as_deref().unwrap())
+            .await
+            .unwrap());
+
+        // The file is there now
+        let image = Image {
+            file_hash: Some(file_hash),
+            ..new_test_image()
+        };
+        let outcome = importer.save_image(image, file.path()).await.unwrap();
+        assert_eq!(outcome, UploadOutcome::Duplicate { image_id });
+    }
+}
@@ -0,0 +1,103 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::{Payload, ServiceResponse},
+    FromRequest, HttpMessage, HttpRequest,
+};
+
+/// Name of the cookie and of the hidden form field with CSRF token
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// Double-submit CSRF token: the same random value is stored in a cookie and sent with every
+/// form. Other sites can submit forms to sdgenbox, but can't read the cookie to fill the field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsrfToken(pub String);
+
+/// Token generated for request without the cookie, it is set by [`set_cookie`]
+#[derive(Clone)]
+struct NewCsrfToken(String);
+
+impl FromRequest for CsrfToken {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        if let Some(cookie) = req.cookie(CSRF_FIELD) {
+            return ready(Ok(CsrfToken(cookie.value().to_string())));
+        }
+
+        let mut extensions = req.extensions_mut();
+        let token = match extensions.get::<NewCsrfToken>() {
+            Some(NewCsrfToken(token)) => token.clone(),
+            None => {
+                let token = hex::encode(rand::random::<[u8; 32]>());
+                extensions.insert(NewCsrfToken(token.clone()));
+                token
+            }
+        };
+        ready(Ok(CsrfToken(token)))
+    }
+}
+
+impl std::fmt::Display for CsrfToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Check that form was submitted with the token from the cookie
+pub fn verify(req: &HttpRequest, token: &str) -> actix_web::Result<()> {
+    match req.cookie(CSRF_FIELD) {
+        Some(cookie) if !token.is_empty() && cookie.value() == token => Ok(()),
+        _ => Err(actix_web::error::ErrorForbidden(
+            "Invalid CSRF token, reload the page and try again",
+        )),
+    }
+}
+
+/// Store token generated while handling the request in the cookie, used as middleware
+pub fn set_cookie<B>(mut res: ServiceResponse<B>) -> ServiceResponse<B> {
+    let token = res.request().extensions().get::<NewCsrfToken>().cloned();
+    if let Some(NewCsrfToken(token)) = token {
+        let cookie = Cookie::build(CSRF_FIELD, token)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish();
+        if let Err(error) = res.response_mut().add_cookie(&cookie) {
+            log::error!("Failed to set CSRF cookie: {}", error);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{cookie::Cookie, test::TestRequest, FromRequest};
+
+    use super::{verify, CsrfToken, CSRF_FIELD};
+
+    #[actix_web::test]
+    async fn test_token_from_cookie() {
+        let req = TestRequest::default()
+            .cookie(Cookie::new(CSRF_FIELD, "token"))
+            .to_http_request();
+        let token = CsrfToken::extract(&req).await.unwrap();
+        assert_eq!(token, CsrfToken("token".to_string()));
+        assert!(verify(&req, "token").is_ok());
+        assert!(verify(&req, "other").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_new_token() {
+        let req = TestRequest::default().to_http_request();
+        let token = CsrfToken::extract(&req).await.unwrap();
+        assert_eq!(token.0.len(), 64);
+        // The same token is used for the whole request
+        assert_eq!(CsrfToken::extract(&req).await.unwrap(), token);
+        // Not accepted until the cookie is set
+        assert!(verify(&req, &token.0).is_err());
+        assert!(verify(&req, "").is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::{fs::File, io, path::Path};
+
+use futures_util::TryStreamExt;
+use sha2::{Digest, Sha256};
+use tokio_util::io::ReaderStream;
+
+use crate::storage::ByteStream;
+
+/// Hex-encoded SHA-256 of the stream contents
+pub async fn sha256_stream(mut stream: ByteStream) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.try_next().await? {
+        hasher.update(&chunk);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hex-encoded SHA-256 of the local file contents
+pub async fn sha256_file(path: &Path) -> io::Result<String> {
+    let file = tokio::fs::File::open(path).await?;
+    sha256_stream(Box::pin(ReaderStream::new(file))).await
+}
+
+/// [`sha256_file`] for blocking threads, e.g. inside [`tokio::task::spawn_blocking`]
+pub fn sha256_file_blocking(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::{sha256_file, sha256_file_blocking};
+
+    #[actix_web::test]
+    async fn test_sha256_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"abc").unwrap();
+
+        assert_eq!(
+            sha256_file(file.path()).await.unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_file_blocking(file.path()).unwrap(),
+            sha256_file(file.path()).await.unwrap()
+        );
+    }
+}
@@ -1,19 +1,48 @@
-use std::process::Command;
+use std::{fs::File, io::Read, process::Command, str::FromStr};
 
-use crate::models::Image;
-use anyhow::{anyhow, bail, Context};
+use crate::models::{ExtraParams, Image};
+use anyhow::{anyhow, Context};
 use lazy_static::lazy_static;
-use regex::Regex;
+use regex::{Captures, Regex};
 use serde::Deserialize;
+use sqlx::types::Json;
 
 #[derive(Debug, Deserialize)]
 struct ExiftoolOutput {
     #[serde(alias = "Parameters")]
-    pub parameters: String,
+    pub parameters: Option<String>,
+}
+
+/// Why generation parameters can't be extracted from a file
+#[derive(Debug, thiserror::Error)]
+pub enum ExtractError {
+    #[error("Unsupported file format, expected PNG, JPEG or WebP image")]
+    UnsupportedFormat,
+    #[error("Image has no generation parameters")]
+    MissingMetadata,
+    #[error("Invalid generation parameters: {0}")]
+    ParseError(String),
+    #[error(transparent)]
+    InternalError(#[from] anyhow::Error),
+}
+
+/// Whether file starting with `header` is an image format which may hold parameters
+fn is_supported_format(header: &[u8]) -> bool {
+    header.starts_with(b"\x89PNG\r\n\x1a\n")
+        || header.starts_with(&[0xff, 0xd8, 0xff])
+        || (header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WEBP"))
 }
 
 /// Extract parameters which were used to generate image from image metadata
-pub fn extract_metadata_from_image(path: &str) -> anyhow::Result<Option<Image>> {
+pub fn extract_metadata_from_image(path: &str) -> Result<Image, ExtractError> {
+    let mut header = Vec::with_capacity(12);
+    File::open(path)
+        .and_then(|file| file.take(12).read_to_end(&mut header))
+        .context("Failed to read file header")?;
+    if !is_supported_format(&header) {
+        return Err(ExtractError::UnsupportedFormat);
+    }
+
     let output = Command::new("exiftool")
         .args([path, "-h", "-Parameters", "-j"])
         .output()
@@ -21,10 +50,10 @@ pub fn extract_metadata_from_image(path: &str) -> anyhow::Result<Option<Image>>
     if !output.status.success() {
         // File is empty
         if output.status.code() == Some(1) {
-            return Ok(None);
+            return Err(ExtractError::MissingMetadata);
         }
 
-        bail!("Process exited with non-zero status: {}", output.status);
+        return Err(anyhow!("Process exited with non-zero status: {}", output.status).into());
     }
 
     let raw_json =
@@ -32,23 +61,92 @@ pub fn extract_metadata_from_image(path: &str) -> anyhow::Result<Option<Image>>
     let response = serde_json::from_str::<Vec<ExiftoolOutput>>(&raw_json)
         .context("Failed to parse stdout to json")?;
     let response = response
-        .get(0)
+        .into_iter()
+        .next()
         .ok_or(anyhow!("Failed to parse stdout to json"))?;
-    Ok(parse_raw(&response.parameters))
+    match response.parameters {
+        Some(parameters) if !parameters.trim().is_empty() => parse_raw(&parameters),
+        _ => Err(ExtractError::MissingMetadata),
+    }
 }
 
 lazy_static! {
     static ref PARAMETERS_REGEX: Regex = Regex::new(
         r"^(?P<prompt>[\S\s]+)\nNegative prompt: (?P<negative_prompt>[\S\s]+)\nSteps: (?P<steps>\d+), Sampler: (?P<sampler>[^,]+), CFG scale: (?P<cfg_scale>[\d\.]+), Seed: (?P<seed>-?\d+), Size: (?P<size>\d+x\d+), Model hash: (?P<model_hash>[^,]+), Model: (?P<model>[^,]+)(?:, Conditional mask weight: (?P<conditional_mask_weight>[^,]+))?(?:, Clip skip: (?P<clip_skip>\d+))?",
     ).unwrap();
+    /// `Key: value` pair of parameters line, value may be quoted JSON string with commas
+    static ref PARAMETER_REGEX: Regex =
+        Regex::new(r#"\s*([\w ]+):\s*("(?:\\.|[^\\"])+"|[^,]*)(?:,|$)"#).unwrap();
+}
+
+/// Parameters which are stored in own fields of [`Image`]
+const KNOWN_PARAMETERS: [&str; 8] = [
+    "Steps",
+    "Sampler",
+    "CFG scale",
+    "Seed",
+    "Size",
+    "Model hash",
+    "Model",
+    "Clip skip",
+];
+
+/// Parse parameters of `line` which are not stored in own fields
+fn parse_extra_params(line: &str) -> ExtraParams {
+    PARAMETER_REGEX
+        .captures_iter(line)
+        .filter_map(|captures| {
+            let key = captures.get(1)?.as_str().trim();
+            let value = captures.get(2)?.as_str().trim();
+            if KNOWN_PARAMETERS.contains(&key) {
+                return None;
+            }
+            let value = match value.starts_with('"') {
+                true => serde_json::from_str(value).unwrap_or_else(|_| value.to_owned()),
+                false => value.to_owned(),
+            };
+            Some((key.to_owned(), value))
+        })
+        .collect()
+}
+
+/// Why `raw` parameters don't match the A1111 infotext format
+fn mismatch_reason(raw: &str) -> String {
+    if !raw.contains("\nNegative prompt: ") {
+        "missing `Negative prompt:` line".to_owned()
+    } else if !raw.contains("\nSteps: ") {
+        "missing `Steps:` line".to_owned()
+    } else {
+        "expected `Steps`, `Sampler`, `CFG scale`, `Seed`, `Size`, `Model hash` and `Model` \
+        in this order"
+            .to_owned()
+    }
+}
+
+/// Parse captured number named `name`
+fn parse_number<T: FromStr>(captures: &Captures, name: &str) -> Result<T, ExtractError> {
+    let value = captures.name(name).map_or("", |value| value.as_str());
+    value
+        .parse()
+        .map_err(|_| ExtractError::ParseError(format!("invalid {} `{}`", name, value)))
 }
 
 /// Parses image parameters to the structure (see [`ImageParameters`])
-fn parse_raw(raw: &str) -> Option<Image> {
-    let captures = PARAMETERS_REGEX.captures(raw)?;
+pub fn parse_raw(raw: &str) -> Result<Image, ExtractError> {
+    let captures = PARAMETERS_REGEX
+        .captures(raw)
+        .ok_or_else(|| ExtractError::ParseError(mismatch_reason(raw)))?;
 
-    let (width, height) = parse_size(captures.name("size").unwrap().as_str()).ok()?;
-    Some(Image {
+    let size = captures.name("size").unwrap().as_str();
+    let (width, height) = parse_size(size)
+        .map_err(|_| ExtractError::ParseError(format!("invalid size `{}`", size)))?;
+    let parameters_start = captures.name("steps").unwrap().start() - "Steps: ".len();
+    let parameters_line = raw[parameters_start..].lines().next().unwrap_or_default();
+    let clip_skip = match captures.name("clip_skip") {
+        Some(_) => Some(parse_number(&captures, "clip_skip")?),
+        None => None,
+    };
+    Ok(Image {
         id: -1,
         prompt: captures.name("prompt").unwrap().as_str().to_owned(),
         negative_prompt: captures
@@ -56,34 +154,27 @@ fn parse_raw(raw: &str) -> Option<Image> {
             .unwrap()
             .as_str()
             .to_owned(),
-        steps: captures
-            .name("steps")
-            .unwrap()
-            .as_str()
-            .parse::<i64>()
-            .unwrap(),
+        steps: parse_number(&captures, "steps")?,
         sampler: captures.name("sampler").unwrap().as_str().to_owned(),
-        cfg_scale: captures
-            .name("cfg_scale")
-            .unwrap()
-            .as_str()
-            .parse::<f64>()
-            .unwrap(),
-        seed: captures
-            .name("seed")
-            .unwrap()
-            .as_str()
-            .parse::<i64>()
-            .unwrap(),
+        cfg_scale: parse_number(&captures, "cfg_scale")?,
+        seed: parse_number(&captures, "seed")?,
         width,
         height,
         model_hash: captures.name("model_hash").unwrap().as_str().to_owned(),
         model: captures.name("model").unwrap().as_str().to_owned(),
-        clip_skip: captures
-            .name("clip_skip")
-            .map(|clip_skip| clip_skip.as_str().parse::<i64>().unwrap()),
+        clip_skip,
+        extra_params: Json(parse_extra_params(parameters_line)),
         file_path: None,
+        file_hash: None,
+        file_missing: false,
+        rating: 0,
+        favorite: false,
+        score: 1500.0,
+        comparisons: 0,
+        note: String::new(),
+        inbox: true,
         created_at: chrono::NaiveDateTime::default(),
+        deleted_at: None,
     })
 }
 
@@ -98,3 +189,56 @@ fn parse_size(raw: &str) -> Result<(i64, i64), ParseSizeError> {
         h.parse::<i64>().map_err(|_| ParseSizeError)?,
     ))
 }
+
+#[cfg(test)]
+mod test {
+    use super::{is_supported_format, parse_raw, ExtractError};
+
+    #[test]
+    fn test_parse_raw() {
+        let raw = "1girl, blonde hair\nNegative prompt: worst quality\nSteps: 20, Sampler: DPM++ 2M Karras, CFG scale: 7, Seed: 2179987202, Size: 768x512, Model hash: 93b79e09ed, Model: anything-v4.5, Conditional mask weight: 1.0, Clip skip: 2, Lora hashes: \"a: 1, b: 2\", Version: v1.2.0";
+        let image = parse_raw(raw).unwrap();
+        assert_eq!(image.prompt, "1girl, blonde hair");
+        assert_eq!(image.negative_prompt, "worst quality");
+        assert_eq!((image.width, image.height), (768, 512));
+        assert_eq!(image.model, "anything-v4.5");
+        assert_eq!(image.clip_skip, Some(2));
+        assert_eq!(
+            image.extra_params.0.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("Conditional mask weight".to_string(), "1.0".to_string()),
+                ("Lora hashes".to_string(), "a: 1, b: 2".to_string()),
+                ("Version".to_string(), "v1.2.0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_errors() {
+        let reason = |raw: &str| match parse_raw(raw) {
+            Err(ExtractError::ParseError(reason)) => reason,
+            result => panic!("Unexpected result {:?}", result),
+        };
+        assert_eq!(
+            reason("1girl\nSteps: 20, Sampler: Euler"),
+            "missing `Negative prompt:` line"
+        );
+        assert_eq!(
+            reason("1girl\nNegative prompt: bad\nSteps: 20, Sampler: Euler a, CFG scale: 7.0.1, Seed: 1, Size: 512x512, Model hash: 1, Model: m"),
+            "invalid cfg_scale `7.0.1`"
+        );
+        assert_eq!(
+            reason("1girl\nNegative prompt: bad\nSteps: 20, Sampler: Euler a, Seed: 1"),
+            "expected `Steps`, `Sampler`, `CFG scale`, `Seed`, `Size`, `Model hash` and `Model` in this order"
+        );
+    }
+
+    #[test]
+    fn test_is_supported_format() {
+        let png = include_bytes!("../tests/assets/image_with_params.png");
+        assert!(is_supported_format(&png[..12]));
+        assert!(is_supported_format(b"RIFF\x10\0\0\0WEBPVP8 "));
+        assert!(!is_supported_format(b"GIF89a"));
+        assert!(!is_supported_format(b""));
+    }
+}
@@ -0,0 +1,34 @@
+use pulldown_cmark::{html, Options, Parser};
+
+/// Render markdown of image notes to HTML which is safe to insert into page.
+///
+/// Raw HTML in markdown is sanitized: scripts, event handlers and `javascript:` links are
+/// removed, links get `rel="noopener noreferrer"`
+pub fn render_markdown(markdown: &str) -> String {
+    let options =
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES | Options::ENABLE_TASKLISTS;
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+    ammonia::clean(&unsafe_html)
+}
+
+#[cfg(test)]
+mod test {
+    use super::render_markdown;
+
+    #[test]
+    fn test_render_markdown() {
+        assert_eq!(
+            render_markdown("Kept for **hands**, fix `cfg`"),
+            "<p>Kept for <strong>hands</strong>, fix <code>cfg</code></p>\n"
+        );
+        assert_eq!(
+            render_markdown("<script>alert(1)</script><b onclick=\"alert(1)\">bold</b>"),
+            "<b>bold</b>"
+        );
+        assert_eq!(
+            render_markdown("[link](javascript:alert(1)) <img src=x onerror=alert(1)>"),
+            "<p><a rel=\"noopener noreferrer\">link</a> <img src=\"x\"></p>\n"
+        );
+    }
+}
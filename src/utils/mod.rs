@@ -1,4 +1,8 @@
+pub mod csrf;
 pub mod errors;
+pub mod hash;
 pub mod image;
+pub mod markdown;
 pub mod pager;
 pub mod render;
+pub mod search;
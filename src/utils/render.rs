@@ -1,4 +1,11 @@
-use actix_web::{http::header::ContentType, HttpResponse, HttpResponseBuilder};
+use actix_web::{
+    http::{
+        header::{ContentType, REFERER},
+        Uri,
+    },
+    web::Redirect,
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+};
 use askama::Template;
 
 use super::errors::MapErrToInternal;
@@ -11,3 +18,89 @@ pub fn render_html(
 
     Ok(http_response.content_type(ContentType::html()).body(html))
 }
+
+/// Redirect to the page the form was sent from, or to `fallback` if it's unknown.
+/// Only path of the referring page is used, so the redirect never leaves the site
+pub fn redirect_back(req: &HttpRequest, fallback: &str) -> Redirect {
+    let path = req
+        .headers()
+        .get(REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .and_then(|referer| referer.parse::<Uri>().ok())
+        .and_then(|uri| uri.path_and_query().map(|path| path.to_string()))
+        .filter(|path| path.starts_with('/') && !path.starts_with("//"));
+    Redirect::to(path.unwrap_or_else(|| fallback.to_string())).see_other()
+}
+
+/// Redirect to `path` showing the error as `error_message` if it's a validation error `E`,
+/// other errors are internal. `path` may end with `#fragment`
+pub fn error_redirect<E>(path: &str, error: anyhow::Error) -> actix_web::Result<Redirect>
+where
+    E: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
+{
+    match error.downcast::<E>() {
+        Ok(error) => {
+            let query = serde_urlencoded::to_string([("error_message", error.to_string())])
+                .map_err_to_internal()?;
+            let location = match path.split_once('#') {
+                Some((path, fragment)) => format!("{}?{}#{}", path, query, fragment),
+                None => format!("{}?{}", path, query),
+            };
+            Ok(Redirect::to(location).see_other())
+        }
+        Err(error) => Err(actix_web::error::ErrorInternalServerError(error)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{http::header::REFERER, test::TestRequest, Responder};
+
+    use super::{error_redirect, redirect_back};
+
+    fn location(referer: Option<&str>) -> String {
+        let mut req = TestRequest::default();
+        if let Some(referer) = referer {
+            req = req.insert_header((REFERER, referer));
+        }
+        let req = req.to_http_request();
+        let response = redirect_back(&req, "/images/1").respond_to(&req);
+        response
+            .headers()
+            .get("location")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_redirect_back() {
+        assert_eq!(
+            location(Some("http://localhost:8080/images?search=a&page=2")),
+            "/images?search=a&page=2"
+        );
+        assert_eq!(
+            location(Some("https://evil.example//evil.example")),
+            "/images/1"
+        );
+        assert_eq!(location(None), "/images/1");
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Name is taken")]
+    struct NameTaken;
+
+    #[test]
+    fn test_error_redirect() {
+        let req = TestRequest::default().to_http_request();
+        let redirect = error_redirect::<NameTaken>("/albums#saved-searches", NameTaken.into());
+        let response = redirect.unwrap().respond_to(&req);
+        assert_eq!(
+            response.headers().get("location").unwrap(),
+            "/albums?error_message=Name+is+taken#saved-searches"
+        );
+        let other = anyhow::anyhow!("database is locked");
+        assert!(error_redirect::<NameTaken>("/albums", other).is_err());
+    }
+}
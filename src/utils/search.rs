@@ -0,0 +1,536 @@
+use std::str::FromStr;
+
+use sqlx::{QueryBuilder, Sqlite};
+
+/// Image field which can be searched with `field:value` qualifier
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Id,
+    Prompt,
+    NegativePrompt,
+    Sampler,
+    Model,
+    ModelHash,
+    Seed,
+    Steps,
+    CfgScale,
+    Width,
+    Height,
+    ClipSkip,
+    Rating,
+    Score,
+    Note,
+}
+
+/// How values of field are compared
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldKind {
+    /// Case-insensitive substring
+    Contains,
+    /// Case-insensitive equality, `*` matches any characters
+    Exact,
+    Integer,
+    Real,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        let field = match name {
+            "id" => Field::Id,
+            "prompt" => Field::Prompt,
+            "negative" | "negative_prompt" => Field::NegativePrompt,
+            "sampler" => Field::Sampler,
+            "model" => Field::Model,
+            "model_hash" | "hash" => Field::ModelHash,
+            "seed" => Field::Seed,
+            "steps" => Field::Steps,
+            "cfg" | "cfg_scale" => Field::CfgScale,
+            "width" => Field::Width,
+            "height" => Field::Height,
+            "clip_skip" => Field::ClipSkip,
+            "rating" => Field::Rating,
+            "score" => Field::Score,
+            "note" => Field::Note,
+            _ => return None,
+        };
+        Some(field)
+    }
+
+    pub fn column(self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::Prompt => "prompt",
+            Field::NegativePrompt => "negative_prompt",
+            Field::Sampler => "sampler",
+            Field::Model => "model",
+            Field::ModelHash => "model_hash",
+            Field::Seed => "seed",
+            Field::Steps => "steps",
+            Field::CfgScale => "cfg_scale",
+            Field::Width => "width",
+            Field::Height => "height",
+            Field::ClipSkip => "clip_skip",
+            Field::Rating => "rating",
+            Field::Score => "score",
+            Field::Note => "note",
+        }
+    }
+
+    fn kind(self) -> FieldKind {
+        match self {
+            Field::Prompt | Field::NegativePrompt | Field::Note => FieldKind::Contains,
+            Field::Sampler | Field::Model | Field::ModelHash => FieldKind::Exact,
+            Field::CfgScale | Field::Score => FieldKind::Real,
+            _ => FieldKind::Integer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Comparison {
+    /// Split comparison operator from the beginning of qualifier value
+    fn parse(value: &str) -> (Comparison, &str) {
+        for (prefix, comparison) in [
+            (">=", Comparison::Ge),
+            ("<=", Comparison::Le),
+            (">", Comparison::Gt),
+            ("<", Comparison::Lt),
+            ("=", Comparison::Eq),
+        ] {
+            if let Some(value) = value.strip_prefix(prefix) {
+                return (comparison, value);
+            }
+        }
+        (Comparison::Eq, value)
+    }
+
+    fn operator(self) -> &'static str {
+        match self {
+            Comparison::Eq => " = ",
+            Comparison::Gt => " > ",
+            Comparison::Ge => " >= ",
+            Comparison::Lt => " < ",
+            Comparison::Le => " <= ",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Integer(i64),
+    Real(f64),
+}
+
+/// Flag of image checked with `is:flag`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flag {
+    Favorite,
+    /// The best scored image of its group of duplicates (images with the same parameters)
+    Best,
+    /// Newly imported image waiting for triage
+    Inbox,
+}
+
+impl Flag {
+    fn from_name(name: &str) -> Option<Flag> {
+        match name {
+            "favorite" | "fav" => Some(Flag::Favorite),
+            "best" => Some(Flag::Best),
+            "inbox" => Some(Flag::Inbox),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    /// Free text, searched in all text fields
+    Text(String),
+    Field(Field, Comparison, Value),
+    /// Images with the tag, `tag:name`
+    Tag(String),
+    Is(Flag),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    /// Term prefixed with `-` excludes matching images
+    pub negated: bool,
+    pub term: Term,
+}
+
+/// Parsed search box query, all conditions must match.
+///
+/// Syntax: words and `"quoted phrases"` are searched in prompts, sampler, model, seed and note,
+/// `field:value` compares one field (`steps:>=20`, `model:"any v4*"`, `rating:>=4`,
+/// `note:hands`), `tag:name` finds tagged images, `is:favorite` finds favorites, `is:best` finds
+/// the best scored image of every group of duplicates, `is:inbox` finds images waiting for triage,
+/// `-` negates a term.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum SearchError {
+    #[error("Invalid value of `{0}`: {1}")]
+    InvalidValue(String, String),
+    #[error("`{0}` can only be compared for equality")]
+    InvalidComparison(String),
+    #[error("Unclosed quote")]
+    UnclosedQuote,
+}
+
+impl FromStr for SearchQuery {
+    type Err = SearchError;
+
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        let mut conditions = Vec::new();
+        for token in tokenize(query)? {
+            let (negated, token) = match token.strip_prefix('-') {
+                Some(rest) if !rest.is_empty() => (true, rest.to_string()),
+                _ => (false, token),
+            };
+            conditions.push(Condition {
+                negated,
+                term: parse_term(token)?,
+            });
+        }
+        Ok(SearchQuery { conditions })
+    }
+}
+
+/// Split query by whitespace keeping quoted parts together, quotes are removed
+fn tokenize(query: &str) -> Result<Vec<String>, SearchError> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    let mut has_token = false;
+    for char in query.chars() {
+        match char {
+            '"' => {
+                quoted = !quoted;
+                has_token = true;
+            }
+            char if char.is_whitespace() && !quoted => {
+                if has_token {
+                    tokens.push(std::mem::take(&mut token));
+                    has_token = false;
+                }
+            }
+            char => {
+                token.push(char);
+                has_token = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(SearchError::UnclosedQuote);
+    }
+    if has_token {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn parse_term(token: String) -> Result<Term, SearchError> {
+    // Unknown qualifiers are just text, e.g. prompt weights like `(masterpiece:1.2)`
+    let Some((name, value)) = token.split_once(':') else {
+        return Ok(Term::Text(token));
+    };
+    if name.eq_ignore_ascii_case("tag") {
+        return Ok(Term::Tag(value.to_string()));
+    }
+    if name.eq_ignore_ascii_case("is") {
+        return Flag::from_name(&value.to_lowercase())
+            .map(Term::Is)
+            .ok_or_else(|| SearchError::InvalidValue(name.to_string(), value.to_string()));
+    }
+    let Some(field) = Field::from_name(&name.to_lowercase()) else {
+        return Ok(Term::Text(token));
+    };
+    let (comparison, value) = Comparison::parse(value);
+
+    let invalid_value = || SearchError::InvalidValue(name.to_string(), value.to_string());
+    let value = match field.kind() {
+        FieldKind::Contains | FieldKind::Exact => {
+            if comparison != Comparison::Eq {
+                return Err(SearchError::InvalidComparison(name.to_string()));
+            }
+            Value::Text(value.to_string())
+        }
+        FieldKind::Integer => Value::Integer(value.parse().map_err(|_| invalid_value())?),
+        FieldKind::Real => Value::Real(value.parse().map_err(|_| invalid_value())?),
+    };
+    Ok(Term::Field(field, comparison, value))
+}
+
+/// Columns searched by free text
+const TEXT_COLUMNS: [&str; 8] = [
+    "cast(id as text)",
+    "upper(prompt)",
+    "upper(negative_prompt)",
+    "upper(sampler)",
+    "upper(model_hash)",
+    "upper(model)",
+    "cast(seed as text)",
+    "upper(note)",
+];
+
+impl SearchQuery {
+    /// Whether the query has no conditions besides empty text, which matches every image
+    pub fn is_empty(&self) -> bool {
+        self.conditions.iter().all(|condition| {
+            !condition.negated && matches!(&condition.term, Term::Text(text) if text.is_empty())
+        })
+    }
+
+    /// Add conditions to query which already has `WHERE` clause
+    pub fn push_conditions(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        for condition in &self.conditions {
+            query.push(if condition.negated {
+                " AND NOT ("
+            } else {
+                " AND ("
+            });
+            push_term(query, &condition.term);
+            query.push(")");
+        }
+    }
+}
+
+fn push_term(query: &mut QueryBuilder<'_, Sqlite>, term: &Term) {
+    match term {
+        Term::Text(text) => {
+            let pattern = format!("%{}%", text.to_uppercase());
+            for (i, column) in TEXT_COLUMNS.iter().enumerate() {
+                if i > 0 {
+                    query.push(" OR ");
+                }
+                query.push(column).push(" LIKE ").push_bind(pattern.clone());
+            }
+        }
+        Term::Tag(name) => {
+            query
+                .push(
+                    "id IN (SELECT image_id FROM image_tag JOIN tag ON tag.id = image_tag.tag_id
+                    WHERE tag.name = ",
+                )
+                .push_bind(name.clone())
+                .push(")");
+        }
+        Term::Is(Flag::Favorite) => {
+            query.push("favorite");
+        }
+        Term::Is(Flag::Inbox) => {
+            query.push("inbox");
+        }
+        Term::Is(Flag::Best) => {
+            query.push(
+                "id IN (SELECT id FROM (
+                    SELECT id, row_number() OVER (
+                        PARTITION BY prompt, negative_prompt, steps, sampler, cfg_scale, seed,
+                            width, height, model_hash, model, clip_skip
+                        ORDER BY score DESC, comparisons DESC, id DESC
+                    ) AS position
+                    FROM image WHERE deleted_at IS NULL
+                ) WHERE position = 1)",
+            );
+        }
+        Term::Field(field, comparison, value) => match (field.kind(), value) {
+            (FieldKind::Contains, Value::Text(text)) => {
+                query
+                    .push(format!("upper({}) LIKE ", field.column()))
+                    .push_bind(format!("%{}%", text.to_uppercase()));
+            }
+            (_, Value::Text(text)) => {
+                query
+                    .push(format!("upper({}) LIKE ", field.column()))
+                    .push_bind(text.to_uppercase().replace('*', "%"));
+            }
+            (_, Value::Integer(number)) => {
+                query
+                    .push(field.column())
+                    .push(comparison.operator())
+                    .push_bind(*number);
+            }
+            (_, Value::Real(number)) => {
+                query
+                    .push(field.column())
+                    .push(comparison.operator())
+                    .push_bind(*number);
+            }
+        },
+    }
+}
+
+/// Order of found images
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Newest,
+    Oldest,
+    /// Best rated first, favorites first among equally rated
+    Rating,
+    /// Highest Elo score first
+    Score,
+}
+
+impl SortOrder {
+    pub const ALL: &'static [SortOrder] = &[
+        SortOrder::Newest,
+        SortOrder::Oldest,
+        SortOrder::Rating,
+        SortOrder::Score,
+    ];
+
+    pub fn from_name(name: &str) -> Option<SortOrder> {
+        SortOrder::ALL
+            .iter()
+            .copied()
+            .find(|order| order.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortOrder::Newest => "newest",
+            SortOrder::Oldest => "oldest",
+            SortOrder::Rating => "rating",
+            SortOrder::Score => "score",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SortOrder::Newest => "Newest first",
+            SortOrder::Oldest => "Oldest first",
+            SortOrder::Rating => "Best rated first",
+            SortOrder::Score => "Highest score first",
+        }
+    }
+
+    /// `ORDER BY` clause
+    pub fn order_by(self) -> &'static str {
+        match self {
+            SortOrder::Newest => " ORDER BY created_at DESC, id DESC",
+            SortOrder::Oldest => " ORDER BY created_at, id",
+            SortOrder::Rating => " ORDER BY rating DESC, favorite DESC, created_at DESC, id DESC",
+            SortOrder::Score => " ORDER BY score DESC, comparisons DESC, id DESC",
+        }
+    }
+}
+
+impl std::fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Comparison, Condition, Field, Flag, SearchError, SearchQuery, Term, Value};
+
+    fn condition(negated: bool, term: Term) -> Condition {
+        Condition { negated, term }
+    }
+
+    #[test]
+    fn test_parse() {
+        let query: SearchQuery =
+            r#"blonde "red eyes" -(worst:1.4) model:"any v4*" steps:>=20 -cfg:7.5 -tag:"client A" is:favorite note:hands -is:inbox"#
+                .parse()
+                .unwrap();
+        assert_eq!(
+            query.conditions,
+            vec![
+                condition(false, Term::Text("blonde".to_string())),
+                condition(false, Term::Text("red eyes".to_string())),
+                condition(true, Term::Text("(worst:1.4)".to_string())),
+                condition(
+                    false,
+                    Term::Field(
+                        Field::Model,
+                        Comparison::Eq,
+                        Value::Text("any v4*".to_string())
+                    )
+                ),
+                condition(
+                    false,
+                    Term::Field(Field::Steps, Comparison::Ge, Value::Integer(20))
+                ),
+                condition(
+                    true,
+                    Term::Field(Field::CfgScale, Comparison::Eq, Value::Real(7.5))
+                ),
+                condition(true, Term::Tag("client A".to_string())),
+                condition(false, Term::Is(Flag::Favorite)),
+                condition(
+                    false,
+                    Term::Field(
+                        Field::Note,
+                        Comparison::Eq,
+                        Value::Text("hands".to_string())
+                    )
+                ),
+                condition(true, Term::Is(Flag::Inbox)),
+            ]
+        );
+        assert_eq!("".parse::<SearchQuery>().unwrap(), SearchQuery::default());
+    }
+
+    #[test]
+    fn test_is_empty() {
+        for query in ["", "  ", r#""""#, r#""" """#] {
+            assert!(
+                query.parse::<SearchQuery>().unwrap().is_empty(),
+                "{}",
+                query
+            );
+        }
+        for query in ["cat", r#"-"""#, "steps:>0"] {
+            assert!(
+                !query.parse::<SearchQuery>().unwrap().is_empty(),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "steps:many".parse::<SearchQuery>(),
+            Err(SearchError::InvalidValue(
+                "steps".to_string(),
+                "many".to_string()
+            ))
+        );
+        assert_eq!(
+            "model:>x".parse::<SearchQuery>(),
+            Err(SearchError::InvalidComparison("model".to_string()))
+        );
+        assert_eq!(
+            "is:big".parse::<SearchQuery>(),
+            Err(SearchError::InvalidValue(
+                "is".to_string(),
+                "big".to_string()
+            ))
+        );
+        assert_eq!(
+            "\"open".parse::<SearchQuery>(),
+            Err(SearchError::UnclosedQuote)
+        );
+    }
+}